@@ -0,0 +1,86 @@
+//! Compares one syscall per datagram with the batched path of `shared::batch`.
+//!
+//! `cargo bench --bench batch` - without the `mmsg` feature or off Linux both
+//! columns measure the per-datagram fallback.
+
+use network::shared::batch::{self, MAX_BATCH};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const PACKET_BYTES: usize = 200;
+const ROUNDS: usize = 2000;
+
+fn sockets() -> (UdpSocket, UdpSocket, SocketAddr) {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dest = receiver.local_addr().unwrap();
+    (sender, receiver, dest)
+}
+
+fn per_packet(elapsed: Duration) -> f64 {
+    elapsed.as_nanos() as f64 / (ROUNDS * MAX_BATCH) as f64
+}
+
+fn send(batched: bool) -> f64 {
+    let (sender, _receiver, dest) = sockets();
+    let packets = vec![(vec![0u8; PACKET_BYTES], dest); MAX_BATCH];
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        if batched {
+            batch::send_batch(&sender, &packets).unwrap();
+        } else {
+            for (packet, dest) in &packets {
+                sender.send_to(packet, *dest).unwrap();
+            }
+        }
+    }
+    per_packet(start.elapsed())
+}
+
+fn recv(batched: bool) -> f64 {
+    let (sender, receiver, dest) = sockets();
+    let packets = vec![(vec![0u8; PACKET_BYTES], dest); MAX_BATCH];
+    let mut buffers = vec![vec![0u8; 1500]; MAX_BATCH];
+    let mut received = Vec::with_capacity(MAX_BATCH);
+
+    let mut elapsed = Duration::default();
+    for _ in 0..ROUNDS {
+        batch::send_batch(&sender, &packets).unwrap();
+
+        let start = Instant::now();
+        let mut count = 0;
+        while count < MAX_BATCH {
+            if batched {
+                count +=
+                    batch::recv_batch(&receiver, &mut buffers[count..], &mut received).unwrap();
+            } else {
+                receiver.recv_from(&mut buffers[count]).unwrap();
+                count += 1;
+            }
+        }
+        elapsed += start.elapsed();
+    }
+    per_packet(elapsed)
+}
+
+fn main() {
+    println!(
+        "{} rounds of {} packets, {} bytes each",
+        ROUNDS, MAX_BATCH, PACKET_BYTES
+    );
+    let (single, batched) = (send(false), send(true));
+    println!(
+        "send: {:8.0} ns/packet per datagram, {:8.0} ns/packet batched ({:.1}x)",
+        single,
+        batched,
+        single / batched
+    );
+    let (single, batched) = (recv(false), recv(true));
+    println!(
+        "recv: {:8.0} ns/packet per datagram, {:8.0} ns/packet batched ({:.1}x)",
+        single,
+        batched,
+        single / batched
+    );
+}
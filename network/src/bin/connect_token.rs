@@ -0,0 +1,149 @@
+//! Issues connect tokens for a backend that has no Rust code of its own.
+//!
+//!     connect_token keygen
+//!     connect_token generate <key> <client id> <lifetime secs> <server address>... [--user-data <hex>]
+//!
+//! Keys, user data and tokens are hex encoded.
+
+use network::shared::token::{self, ConnectToken, Key, KEY_BYTES};
+use std::{env, net::SocketAddr, process, time};
+
+const USAGE: &str = "usage:
+    connect_token keygen
+    connect_token generate <key> <client id> <lifetime secs> <server address>... [--user-data <hex>]";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("odd length hex '{}'", text));
+    }
+    let digit = |byte: u8| (byte as char).to_digit(16);
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match (digit(pair[0]), digit(pair[1])) {
+            (Some(high), Some(low)) => Ok((high << 4 | low) as u8),
+            _ => Err(format!("invalid hex '{}'", text)),
+        })
+        .collect()
+}
+
+fn parse<T: std::str::FromStr>(text: &str, what: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("invalid {} '{}'", what, text))
+}
+
+fn generate(args: &[String]) -> Result<String, String> {
+    let mut args = args.to_vec();
+    let mut user_data = Vec::new();
+    if let Some(flag) = args.iter().position(|arg| arg == "--user-data") {
+        let hex = args.get(flag + 1).ok_or("missing user data")?;
+        user_data = from_hex(hex)?;
+        args.drain(flag..flag + 2);
+    }
+    if args.len() < 4 {
+        return Err(USAGE.to_string());
+    }
+
+    let key_bytes = from_hex(&args[0])?;
+    if key_bytes.len() != KEY_BYTES {
+        return Err(format!("keys are {} bytes", KEY_BYTES));
+    }
+    let mut key: Key = [0; KEY_BYTES];
+    key.copy_from_slice(&key_bytes);
+    let client_id: u64 = parse(&args[1], "client id")?;
+    let lifetime = time::Duration::from_secs(parse(&args[2], "lifetime")?);
+    let servers = args[3..]
+        .iter()
+        .map(|arg| parse::<SocketAddr>(arg, "server address"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let token = ConnectToken::generate(&key, client_id, lifetime, &servers, &user_data)
+        .map_err(|e| format!("cannot generate token: {}", e))?;
+    Ok(to_hex(&token.to_bytes()))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("keygen") => Ok(to_hex(&token::generate_key())),
+        Some("generate") => generate(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(output) => println!("{}", output),
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_hex, generate, to_hex};
+    use network::shared::token::ConnectToken;
+    use std::net::SocketAddr;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn hex() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(from_hex(&to_hex(&bytes)).unwrap(), bytes);
+        assert_eq!(from_hex("00FfaB").unwrap(), [0x00, 0xff, 0xab]);
+        assert_eq!(from_hex("").unwrap(), []);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("0g").is_err());
+        assert!(from_hex("+1").is_err());
+        // multi byte characters are not split.
+        assert!(from_hex("aé").is_err());
+        assert!(from_hex("éa0").is_err());
+    }
+
+    #[test]
+    fn generate_token() {
+        let key = [7u8; 32];
+        let line = format!(
+            "{} 42 30 127.0.0.1:40000 --user-data 0102 [::1]:40001",
+            to_hex(&key)
+        );
+        let token = from_hex(&generate(&args(&line)).unwrap()).unwrap();
+        let token = ConnectToken::from_bytes(&token).unwrap();
+        let servers: Vec<SocketAddr> = vec![
+            "127.0.0.1:40000".parse().unwrap(),
+            "[::1]:40001".parse().unwrap(),
+        ];
+        assert_eq!(token.server_addresses, servers);
+        let private = token.sealed.open(&key).unwrap();
+        assert_eq!(private.client_id, 42);
+        assert_eq!(private.server_addresses, servers);
+        assert_eq!(private.user_data[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn generate_errors() {
+        let key = to_hex(&[7u8; 32]);
+        let fails = |line: String| generate(&args(&line)).is_err();
+        assert!(fails(format!("{} 42 30", key)));
+        assert!(fails(format!("{}00 42 30 127.0.0.1:40000", key)));
+        assert!(fails(format!("{} -1 30 127.0.0.1:40000", key)));
+        assert!(fails(format!("{} 42 soon 127.0.0.1:40000", key)));
+        assert!(fails(format!("{} 42 {} 127.0.0.1:40000", key, u64::MAX)));
+        assert!(fails(format!("{} 42 30 localhost", key)));
+        assert!(fails(format!("{} 42 30 127.0.0.1:40000 --user-data", key)));
+        assert!(fails(format!(
+            "{} 42 30 127.0.0.1:40000 --user-data ü",
+            key
+        )));
+        let too_much = "00".repeat(257);
+        assert!(fails(format!(
+            "{} 42 30 127.0.0.1:40000 --user-data {}",
+            key, too_much
+        )));
+    }
+}
@@ -1,59 +1,517 @@
-use crate::shared::socketio;
+use crate::shared::{
+    address::{NetcodeAddress, NetcodeSocketConfig},
+    channel::{ChannelId, Message, SendError},
+    checksum,
+    conditioner::{Conditioner, ConditionerConfig},
+    connection::{ConnectionStats, Connections},
+    crypto::PacketCipher,
+    error::{set_last_error, NETCODE_ERROR, NETCODE_OK},
+    handshake::{ClientHandshake, ClientState, DenyReason, Handshake},
+    socketio,
+    token::{ConnectToken, SealedToken},
+    transport::Transport,
+};
+use std::io;
 use std::mem::transmute;
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::ptr;
+use std::slice;
+use std::time;
 
 pub struct NetcodeClient {
     test: i32,
-    io: socketio::Context,
+    io: Conditioner,
+    connections: Connections,
+    handshake: Option<ClientHandshake>,
+    // the token being connected with and the index of the server tried.
+    token: Option<(ConnectToken, usize)>,
+    // datagrams dropped after the transport let them through.
+    rejected: u64,
+    last_sender: Option<SocketAddr>,
 }
 
+impl NetcodeClient {
+    pub fn new(io: Box<dyn Transport>) -> NetcodeClient {
+        NetcodeClient {
+            test: 2,
+            io: Conditioner::new(io),
+            connections: Connections::default(),
+            handshake: None,
+            token: None,
+            rejected: 0,
+            last_sender: None,
+        }
+    }
+
+    /// Starts connecting to `server`, dropping the connection to any previous
+    /// one. Progress is reported by `state`.
+    pub fn connect(&mut self, server: SocketAddr) {
+        let now = time::Instant::now();
+        self.connections = Connections::default();
+        self.token = None;
+        self.handshake = Some(ClientHandshake::new(server, SealedToken::default(), now));
+    }
+
+    /// Connects to the servers listed in `token` one after the other, until
+    /// one of them does not time out. Stays disconnected if it lists none.
+    pub fn connect_with_token(&mut self, token: ConnectToken) {
+        let now = time::Instant::now();
+        self.connections = Connections::default();
+        self.handshake = token
+            .server_addresses
+            .first()
+            .map(|server| ClientHandshake::from_token(*server, &token, now));
+        self.token = self.handshake.as_ref().map(|_| (token, 0));
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.handshake
+            .as_ref()
+            .map_or(ClientState::Disconnected, ClientHandshake::state)
+    }
+
+    /// Why the server refused the client, if it did.
+    pub fn deny_reason(&self) -> DenyReason {
+        self.handshake
+            .as_ref()
+            .map_or(DenyReason::None, ClientHandshake::deny_reason)
+    }
+
+    /// Ends the connection to the server and tells the server, or stops
+    /// connecting.
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        let mut datagrams = Vec::new();
+        if let Some(handshake) = &self.handshake {
+            let server = handshake.server();
+            if let Some(connection) = self.connections.get_mut(server) {
+                connection.write_disconnect(&mut datagrams);
+            }
+            for datagram in &datagrams {
+                self.io.send(datagram, server)?;
+            }
+        }
+        self.handshake = None;
+        self.token = None;
+        self.connections = Connections::default();
+        Ok(())
+    }
+
+    fn is_connected_to(&self, addr: SocketAddr) -> bool {
+        self.state() == ClientState::Connected && self.connections.get(addr).is_some()
+    }
+
+    /// Reads everything received since the last update. Fails if the
+    /// transport stopped receiving because of a socket error.
+    pub fn update(&mut self) -> io::Result<()> {
+        self.test += 1;
+
+        let now = time::Instant::now();
+        while let Ok(data) = self.io.try_recv() {
+            let packet = &data.buffer[..data.nbytes];
+            let from_server = self
+                .handshake
+                .as_ref()
+                .is_some_and(|handshake| handshake.server() == data.addr);
+            match Handshake::read(packet) {
+                Ok(Some(packet)) if from_server => {
+                    let handshake = self.handshake.as_mut().unwrap();
+                    handshake.receive(packet);
+                    if handshake.state() == ClientState::Connected
+                        && self.connections.get(data.addr).is_none()
+                    {
+                        let connection = self.connections.insert(data.addr, now);
+                        match (&self.token, handshake.cookie()) {
+                            (Some((token, _)), Some(cookie)) if handshake.is_encrypted() => {
+                                connection.encrypt(PacketCipher::new(
+                                    &token.client_to_server_key,
+                                    &token.server_to_client_key,
+                                    cookie,
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Ok(None) if self.is_connected_to(data.addr) => {
+                    match self.connections.read_datagram(packet, data.addr, now) {
+                        Ok(None) => {}
+                        Ok(Some(_)) => self.last_sender = Some(data.addr),
+                        Err(_) => self.rejected += 1,
+                    }
+                }
+                Ok(_) | Err(_) => self.rejected += 1,
+            }
+            self.io.recycle(data);
+        }
+        if let Some(handshake) = &mut self.handshake {
+            if let Some(connection) = self.connections.get(handshake.server()) {
+                let closed = match connection.is_disconnected() {
+                    true => Some(ClientState::Disconnected),
+                    false if connection.is_timed_out(now) => Some(ClientState::TimedOut),
+                    false => None,
+                };
+                if let Some(state) = closed {
+                    handshake.close(state);
+                    self.connections = Connections::default();
+                    // the servers of the token are only tried while connecting.
+                    self.token = None;
+                }
+            }
+        }
+        // try the next server of the token.
+        if self.state() == ClientState::TimedOut {
+            if let Some((token, index)) = &mut self.token {
+                if let Some(server) = token.server_addresses.get(*index + 1) {
+                    *index += 1;
+                    let handshake = ClientHandshake::from_token(*server, token, now);
+                    self.handshake = Some(handshake);
+                }
+            }
+        }
+        if let Some(handshake) = &mut self.handshake {
+            if let Some(packet) = handshake.poll(now) {
+                let mut datagram = Vec::new();
+                packet.write(&mut datagram);
+                self.io.send(&datagram, handshake.server())?;
+            }
+        }
+        self.connections.update(now);
+        let io = &mut self.io;
+        self.connections
+            .flush(now, |packet, addr| io.send(packet, addr))?;
+        match self.io.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends `payload` in a packet with a header that acknowledges what was
+    /// received from `dest`. Payloads larger than a datagram are sent in
+    /// fragments.
+    pub fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if !self.is_connected_to(dest) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let datagrams = self
+            .connections
+            .write_datagrams(payload, dest, time::Instant::now())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let packets: Vec<_> = datagrams
+            .iter()
+            .map(|datagram| (&datagram[..], dest))
+            .collect();
+        self.io.send_batch(&packets)?;
+        Ok(payload.len())
+    }
+
+    /// Queues `message` on `channel` for `dest`. It is sent with the next
+    /// packet to `dest`, or from `update` if there is none.
+    pub fn send_message(
+        &mut self,
+        dest: SocketAddr,
+        channel: ChannelId,
+        message: Message,
+    ) -> Result<(), SendError> {
+        if !self.is_connected_to(dest) {
+            return Err(SendError::NotConnected);
+        }
+        self.connections
+            .send_message(dest, channel, message, time::Instant::now())
+    }
+
+    /// Next message received from `from` on `channel`.
+    pub fn receive_message(&mut self, from: SocketAddr, channel: ChannelId) -> Option<Message> {
+        self.connections.receive_message(from, channel)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.io.local_addr()
+    }
+
+    /// Received datagrams that were dropped: failed checksums, packets from
+    /// anyone but the server and ones that could not be read.
+    pub fn rejected_packets(&self) -> u64 {
+        self.io.rejected_packets() + self.rejected
+    }
+
+    /// Simulates bad network conditions on received packets, `None` turns it off.
+    pub fn set_conditioner(&mut self, config: Option<ConditionerConfig>) {
+        self.io.set_config(config);
+    }
+
+    /// Source address of the most recent packet.
+    pub fn last_sender(&self) -> Option<SocketAddr> {
+        self.last_sender
+    }
+
+    /// Link statistics of the connection with `addr`, if packets were exchanged.
+    pub fn connection_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        self.connections
+            .get(addr)
+            .map(|connection| *connection.stats())
+    }
+
+    /// Starts probing the path MTU to `addr`, the result shows up in
+    /// `ConnectionStats::max_packet_bytes`. Returns false if no packets were
+    /// exchanged with `addr`.
+    pub fn probe_mtu(&mut self, addr: SocketAddr) -> bool {
+        self.connections.probe_mtu(addr)
+    }
+}
+
+// returns null and sets the last error if the socket could not be opened.
+fn create(io: io::Result<(socketio::Context, u16)>) -> *mut NetcodeClient {
+    match io {
+        Ok((socket_io, _port)) => {
+            let context = Box::new(NetcodeClient::new(Box::new(socket_io)));
+            unsafe { transmute::<Box<NetcodeClient>, *mut NetcodeClient>(context) }
+        }
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Opens a socket as described by `config`, null uses the defaults.
+/// Returns null if that failed, see `netcode_last_error`.
 #[no_mangle]
-pub extern "C" fn client_create() -> *mut NetcodeClient {
-    let local_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
-    let (socket_io, _port) = socketio::Context::new(local_addr);
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_create_with(
+    config: *const NetcodeSocketConfig,
+) -> *mut NetcodeClient {
+    let config = config.as_ref().copied().unwrap_or_default();
+    let io = config
+        .local_addr()
+        .and_then(|local_addr| match config.polled {
+            true => socketio::Context::polled(local_addr, checksum::PROTOCOL),
+            false => socketio::Context::new(local_addr, checksum::PROTOCOL),
+        });
+    create(io)
+}
 
-    let context = Box::new(NetcodeClient {
-        test: 2,
-        io: socket_io,
-    });
+/// Binds 127.0.0.1 on any free port and starts a receive thread.
+/// Returns null if the socket could not be opened, see `netcode_last_error`.
+#[no_mangle]
+pub extern "C" fn client_create() -> *mut NetcodeClient {
+    unsafe { client_create_with(ptr::null()) }
+}
 
-    unsafe { transmute(context) }
+/// Like `client_create`, but without a receive thread: the socket is
+/// non-blocking and read from `client_update` on the calling thread.
+#[no_mangle]
+pub extern "C" fn client_create_polled() -> *mut NetcodeClient {
+    let config = NetcodeSocketConfig {
+        polled: true,
+        ..Default::default()
+    };
+    unsafe { client_create_with(&config) }
 }
 
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn client_destroy(context: *mut NetcodeClient) {
+    if context.is_null() {
+        return;
+    }
     let _dropped: Box<NetcodeClient> = transmute(context);
 }
 
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn client_update(context: *mut NetcodeClient) {
+pub unsafe extern "C" fn client_update(context: *mut NetcodeClient) -> i32 {
     let client = &mut *context;
+    match client.update() {
+        Ok(()) => NETCODE_OK,
+        Err(e) => {
+            set_last_error(e);
+            NETCODE_ERROR
+        }
+    }
+}
 
-    client.test += 1;
+/// Number of received datagrams that were dropped, see `NetcodeClient::rejected_packets`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_rejected_packets(context: *const NetcodeClient) -> u64 {
+    let client = &*context;
+    client.rejected_packets()
+}
 
-    while let Ok(data) = client.io.try_recv() {
-        println!(
-            "client read {}({}) on main. time since recv: {}ms",
-            data.nbytes,
-            data.buffer.len(),
-            data.recv_time.elapsed().as_millis()
-        );
+/// Number of times the packet buffer pool ran empty and a buffer had to be allocated.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_pool_exhausted(context: *const NetcodeClient) -> u64 {
+    let client = &*context;
+    client.io.pool_exhausted()
+}
+
+/// Starts connecting to the server at `address`, see `client_state`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_connect(
+    context: *mut NetcodeClient,
+    address: *const NetcodeAddress,
+) {
+    let client = &mut *context;
+    client.connect(SocketAddr::from(*address));
+}
+
+/// Starts connecting with the `nbytes` long connect token issued by the
+/// backend. Fails if it is not a valid token, see `netcode_last_error`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_connect_with_token(
+    context: *mut NetcodeClient,
+    token: *const u8,
+    nbytes: usize,
+) -> i32 {
+    let client = &mut *context;
+    match ConnectToken::from_bytes(slice::from_raw_parts(token, nbytes)) {
+        Ok(token) => {
+            client.connect_with_token(token);
+            NETCODE_OK
+        }
+        Err(e) => {
+            set_last_error(format!("invalid connect token: {}", e));
+            NETCODE_ERROR
+        }
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_state(context: *const NetcodeClient) -> ClientState {
+    let client = &*context;
+    client.state()
+}
+
+/// Why the server refused the client once `client_state` is denied.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_deny_reason(context: *const NetcodeClient) -> DenyReason {
+    let client = &*context;
+    client.deny_reason()
+}
+
+/// Ends the connection to the server and tells the server. Returns
+/// `NETCODE_ERROR` if that could not be sent, see `netcode_last_error`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_disconnect(context: *mut NetcodeClient) -> i32 {
+    let client = &mut *context;
+    match client.disconnect() {
+        Ok(()) => NETCODE_OK,
+        Err(e) => {
+            set_last_error(e);
+            NETCODE_ERROR
+        }
     }
 }
 
+/// Applies `config` to all packets received from now on. A null `config`
+/// turns the conditioner off.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_set_conditioner(
+    context: *mut NetcodeClient,
+    config: *const ConditionerConfig,
+) {
+    let client = &mut *context;
+    client.set_conditioner(config.as_ref().copied());
+}
+
+/// Writes the source address of the most recent packet to `address`.
+/// Returns false if nothing was received yet.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_last_sender(
+    context: *const NetcodeClient,
+    address: *mut NetcodeAddress,
+) -> bool {
+    let client = &*context;
+    match client.last_sender() {
+        Some(addr) => {
+            *address = NetcodeAddress::from(addr);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Writes the link statistics of the connection with `address` to `stats`.
+/// Returns false if no packets were exchanged with `address`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_connection_stats(
+    context: *const NetcodeClient,
+    address: *const NetcodeAddress,
+    stats: *mut ConnectionStats,
+) -> bool {
+    let client = &*context;
+    match client.connection_stats(SocketAddr::from(*address)) {
+        Some(connection_stats) => {
+            *stats = connection_stats;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Starts probing the path MTU to `address`, see `client_connection_stats`.
+/// Returns false if no packets were exchanged with `address`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn client_probe_mtu(
+    context: *mut NetcodeClient,
+    address: *const NetcodeAddress,
+) -> bool {
+    let client = &mut *context;
+    client.probe_mtu(SocketAddr::from(*address))
+}
+
 #[cfg(test)]
 mod tests {
     use super::client_create;
     use super::client_destroy;
+    use super::client_rejected_packets;
     use super::client_update;
+    use super::NetcodeClient;
+    use crate::shared::{
+        error::NETCODE_OK,
+        handshake::ClientState,
+        token::{self, ConnectToken},
+        transport::MemoryTransport,
+    };
+    use std::{net::SocketAddr, time};
+
     #[test]
     fn instatiation() {
         let instance = client_create();
         assert!(!instance.is_null());
-        unsafe { client_update(instance) };
+        assert_eq!(unsafe { client_update(instance) }, NETCODE_OK);
+        assert_eq!(unsafe { client_rejected_packets(instance) }, 0);
         unsafe { client_destroy(instance) };
+        unsafe { client_destroy(std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn token_without_servers() {
+        let (client_io, _) = MemoryTransport::pair();
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let lifetime = time::Duration::from_secs(30);
+        let server = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let key = token::generate_key();
+        let token = ConnectToken::generate(&key, 1, lifetime, &[server], b"").unwrap();
+        client.connect_with_token(ConnectToken {
+            server_addresses: Vec::new(),
+            ..token.clone()
+        });
+        assert_eq!(client.state(), ClientState::Disconnected);
+        client.update().unwrap();
+        assert_eq!(client.state(), ClientState::Disconnected);
+
+        client.connect_with_token(token);
+        assert_eq!(client.state(), ClientState::Connecting);
     }
 }
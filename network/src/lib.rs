@@ -1,3 +1,6 @@
+// lets code generated by network_derive refer to this crate as `::network`.
+extern crate self as network;
+
 pub mod client;
 pub mod server;
 pub mod shared;
@@ -118,7 +118,7 @@ mod tests {
         for (i, cmd) in commands.iter().enumerate() {
             let expected_input = SimInput {
                 previous: prev_cmd,
-                current: cmd.clone(),
+                current: *cmd,
             };
 
             println!("before: {:?}", buffer.data);
@@ -170,7 +170,7 @@ mod tests {
         for (i, cmd) in initial_cmds.iter().enumerate() {
             let expected_input = SimInput {
                 previous: prev_cmd,
-                current: cmd.clone(),
+                current: *cmd,
             };
 
             println!("before: {:?}", buffer.data);
@@ -197,7 +197,7 @@ mod tests {
         for (i, cmd) in followup_cmds.iter().enumerate() {
             let expected_input = SimInput {
                 previous: prev_cmd,
-                current: cmd.clone(),
+                current: *cmd,
             };
 
             println!("before: {:?}", buffer.data);
@@ -275,10 +275,10 @@ mod tests {
         println!(" after: {:?}", buffer.data);
 
         let mut prev_cmd = SimCommand::default();
-        for i in 0..expected_cmds.len() {
+        for cmd in expected_cmds.iter() {
             let expected = SimInput {
                 previous: prev_cmd,
-                current: expected_cmds[i],
+                current: *cmd,
             };
             println!("before: {:?}", buffer.data);
             let input = buffer.consume_command();
@@ -4,54 +4,548 @@ mod control;
 mod simulation;
 mod world;
 
-use crate::shared::socketio;
-use std::{mem::transmute, net::SocketAddr, slice, str::FromStr, time};
+use crate::shared::{
+    address::{NetcodeAddress, NetcodeSocketConfig},
+    channel::{ChannelId, Message, SendError},
+    checksum,
+    conditioner::{Conditioner, ConditionerConfig},
+    connection::{ConnectionStats, Connections},
+    crypto::PacketCipher,
+    error::{set_last_error, NETCODE_ERROR, NETCODE_OK},
+    handshake::{Cookie, Cookies, DenyReason, Handshake, PROTOCOL_VERSION},
+    socketio,
+    token::{self, Key, PrivateToken, SealedToken, KEY_BYTES},
+    transport::Transport,
+};
+use actor_ids::ActorId;
+use std::{collections::HashMap, io, mem::transmute, net::SocketAddr, ptr, slice, time};
+
+// a client that completed the handshake.
+struct Client {
+    actor: ActorId,
+    // the cookie and nonce the connection was set up with. Cookies issued
+    // to one address within the same millisecond are the same, the nonce
+    // tells sessions apart.
+    cookie: Cookie,
+    nonce: u64,
+    token: Option<PrivateToken>,
+}
 
 pub struct NetcodeServer {
-    io: socketio::Context,
+    io: Conditioner,
     simulation: simulation::Simulation,
+    connections: Connections,
+    cookies: Cookies,
+    clients: HashMap<SocketAddr, Client>,
+    // key and public address connect tokens are checked against.
+    token_key: Option<(Key, SocketAddr)>,
+    // connections set up with a connect token are encrypted.
+    encryption: bool,
+    // datagrams dropped after the transport let them through.
+    rejected: u64,
+    last_sender: Option<SocketAddr>,
 }
 
+impl NetcodeServer {
+    pub fn new(io: Box<dyn Transport>) -> NetcodeServer {
+        let simulation = simulation::Simulation::start(0, time::Duration::from_millis(16), 8, 8);
+        NetcodeServer {
+            io: Conditioner::new(io),
+            simulation,
+            connections: Connections::default(),
+            cookies: Cookies::new(time::Instant::now()),
+            clients: HashMap::new(),
+            token_key: None,
+            encryption: true,
+            rejected: 0,
+            last_sender: None,
+        }
+    }
+
+    /// Only accepts clients with a connect token sealed with `key` that lists
+    /// `address`, the address clients reach the server at.
+    pub fn require_connect_tokens(&mut self, key: Key, address: SocketAddr) {
+        self.token_key = Some((key, address));
+    }
+
+    /// Turns encryption of connections accepted from now on on or off, the
+    /// clients follow what the server tells them when it accepts them. Only
+    /// debug builds can turn it off, for packet inspection. Returns false if
+    /// it stays on.
+    pub fn set_encryption(&mut self, enabled: bool) -> bool {
+        if !enabled && !cfg!(debug_assertions) {
+            return false;
+        }
+        self.encryption = enabled;
+        true
+    }
+
+    // the private part of `token`, `None` if tokens are not required. Such
+    // servers deny tokens, they could not set up the keys the client has.
+    fn check_token(&self, token: &SealedToken) -> Result<Option<PrivateToken>, DenyReason> {
+        let (key, address) = match &self.token_key {
+            Some(token_key) => token_key,
+            None if *token == SealedToken::default() => return Ok(None),
+            None => return Err(DenyReason::InvalidToken),
+        };
+        match token.open(key) {
+            Some(_) if token.is_expired(token::unix_time()) => Err(DenyReason::TokenExpired),
+            Some(private) if private.server_addresses.contains(address) => Ok(Some(private)),
+            _ => Err(DenyReason::InvalidToken),
+        }
+    }
+
+    // answers a handshake packet from `addr`. Actors are only added for
+    // clients that returned a cookie, so spoofed requests cost nothing.
+    // Accepts and denies are signed if the token of the client could be
+    // opened.
+    fn handshake(
+        &mut self,
+        packet: Handshake,
+        addr: SocketAddr,
+        now: time::Instant,
+    ) -> Option<Handshake> {
+        let sign = |reply: Handshake, token: Option<&PrivateToken>| match token {
+            Some(token) => Some(reply.sign(&token.server_to_client_key)),
+            None => Some(reply),
+        };
+        match packet {
+            Handshake::Request { version, nonce, .. } if version != PROTOCOL_VERSION => {
+                Some(Handshake::denied(DenyReason::VersionMismatch, nonce))
+            }
+            Handshake::Request { nonce, token, .. } => match self.check_token(&token) {
+                Ok(_) => Some(Handshake::Challenge {
+                    cookie: self.cookies.issue(addr, now),
+                    nonce,
+                }),
+                Err(reason) => Some(Handshake::denied(reason, nonce)),
+            },
+            Handshake::Response { cookie, .. } if !self.cookies.verify(&cookie, addr, now) => {
+                self.rejected += 1;
+                None
+            }
+            // on the way since before the client connected again.
+            Handshake::Response { cookie, .. }
+                if self.clients.get(&addr).is_some_and(|client| {
+                    Cookies::issued(&cookie) < Cookies::issued(&client.cookie)
+                }) =>
+            {
+                None
+            }
+            // the accept got lost.
+            Handshake::Response { cookie, nonce, .. }
+                if self
+                    .clients
+                    .get(&addr)
+                    .is_some_and(|client| client.cookie == cookie && client.nonce == nonce) =>
+            {
+                let client = &self.clients[&addr];
+                let encrypted = self
+                    .connections
+                    .get(addr)
+                    .is_some_and(|connection| connection.is_encrypted());
+                sign(Handshake::accepted(nonce, encrypted), client.token.as_ref())
+            }
+            Handshake::Response {
+                cookie,
+                nonce,
+                token,
+            } => {
+                let token = match self.check_token(&token) {
+                    Ok(token) => token,
+                    Err(reason) => return Some(Handshake::denied(reason, nonce)),
+                };
+                // a fresh cookie from a connected address is a new session.
+                self.remove_client(addr);
+                if let Some(token) = &token {
+                    if self.clients.values().any(|other| {
+                        other
+                            .token
+                            .as_ref()
+                            .is_some_and(|other| other.client_id == token.client_id)
+                    }) {
+                        let denied = Handshake::denied(DenyReason::AlreadyConnected, nonce);
+                        return sign(denied, Some(token));
+                    }
+                }
+                let actor = match self.simulation.add_actor(0, "client") {
+                    Some(actor) => actor,
+                    None => {
+                        let denied = Handshake::denied(DenyReason::ServerFull, nonce);
+                        return sign(denied, token.as_ref());
+                    }
+                };
+                let connection = self.connections.insert(addr, now);
+                let encrypted = token.is_some() && self.encryption;
+                if let Some(token) = token.as_ref().filter(|_| encrypted) {
+                    connection.encrypt(PacketCipher::new(
+                        &token.server_to_client_key,
+                        &token.client_to_server_key,
+                        &cookie,
+                    ));
+                }
+                let accepted = sign(Handshake::accepted(nonce, encrypted), token.as_ref());
+                let client = Client {
+                    actor,
+                    cookie,
+                    nonce,
+                    token,
+                };
+                self.clients.insert(addr, client);
+                accepted
+            }
+            _ => None,
+        }
+    }
+
+    // frees everything the client at `addr` held, if there is one.
+    fn remove_client(&mut self, addr: SocketAddr) -> bool {
+        match self.clients.remove(&addr) {
+            Some(client) => {
+                self.simulation.remove_actor(client.actor);
+                self.connections.remove(addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends the connection with the client at `addr` and tells the client.
+    /// Returns false if there is no such client.
+    pub fn disconnect(&mut self, addr: SocketAddr) -> io::Result<bool> {
+        let mut datagrams = Vec::new();
+        match self.connections.get_mut(addr) {
+            Some(connection) if self.clients.contains_key(&addr) => {
+                connection.write_disconnect(&mut datagrams)
+            }
+            _ => return Ok(false),
+        }
+        self.remove_client(addr);
+        for datagram in &datagrams {
+            self.io.send(datagram, addr)?;
+        }
+        Ok(true)
+    }
+
+    /// Reads everything received since the last update. Fails if the
+    /// transport stopped receiving because of a socket error.
+    pub fn update(&mut self) -> io::Result<()> {
+        self.update_at(time::Instant::now())
+    }
+
+    fn update_at(&mut self, now: time::Instant) -> io::Result<()> {
+        // tick server loop
+        const UPDATE_DELTA: time::Duration = time::Duration::from_millis(16);
+        self.simulation.update(UPDATE_DELTA);
+
+        let mut replies = Vec::new();
+        while let Ok(data) = self.io.try_recv() {
+            let packet = &data.buffer[..data.nbytes];
+            match Handshake::read(packet) {
+                Ok(Some(handshake)) => {
+                    if let Some(reply) = self.handshake(handshake, data.addr, now) {
+                        replies.push((reply, data.addr));
+                    }
+                }
+                Ok(None) if !self.clients.contains_key(&data.addr) => self.rejected += 1,
+                Ok(None) => match self.connections.read_datagram(packet, data.addr, now) {
+                    Ok(None) => {}
+                    Ok(Some(_)) => self.last_sender = Some(data.addr),
+                    Err(_) => self.rejected += 1,
+                },
+                Err(_) => self.rejected += 1,
+            }
+            self.io.recycle(data);
+        }
+        let mut datagram = Vec::new();
+        for (reply, addr) in replies {
+            reply.write(&mut datagram);
+            self.io.send(&datagram, addr)?;
+        }
+        // clients that left or went silent.
+        for addr in self.connections.closed(now) {
+            self.remove_client(addr);
+        }
+        self.connections.update(now);
+        let io = &mut self.io;
+        self.connections
+            .flush(now, |packet, addr| io.send(packet, addr))?;
+        match self.io.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends `payload` in a packet with a header that acknowledges what was
+    /// received from `dest`. Payloads larger than a datagram are sent in
+    /// fragments.
+    pub fn send_to(&mut self, payload: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if !self.clients.contains_key(&dest) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let datagrams = self
+            .connections
+            .write_datagrams(payload, dest, time::Instant::now())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let packets: Vec<_> = datagrams
+            .iter()
+            .map(|datagram| (&datagram[..], dest))
+            .collect();
+        self.io.send_batch(&packets)?;
+        Ok(payload.len())
+    }
+
+    /// Queues `message` on `channel` for `dest`. It is sent with the next
+    /// packet to `dest`, or from `update` if there is none.
+    pub fn send_message(
+        &mut self,
+        dest: SocketAddr,
+        channel: ChannelId,
+        message: Message,
+    ) -> Result<(), SendError> {
+        if !self.clients.contains_key(&dest) {
+            return Err(SendError::NotConnected);
+        }
+        self.connections
+            .send_message(dest, channel, message, time::Instant::now())
+    }
+
+    /// Next message received from `from` on `channel`.
+    pub fn receive_message(&mut self, from: SocketAddr, channel: ChannelId) -> Option<Message> {
+        self.connections.receive_message(from, channel)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.io.local_addr()
+    }
+
+    /// Received datagrams that were dropped: failed checksums, packets from
+    /// addresses that did not complete the handshake and ones that could not
+    /// be read.
+    pub fn rejected_packets(&self) -> u64 {
+        self.io.rejected_packets() + self.rejected
+    }
+
+    /// Simulates bad network conditions on received packets, `None` turns it off.
+    pub fn set_conditioner(&mut self, config: Option<ConditionerConfig>) {
+        self.io.set_config(config);
+    }
+
+    /// Source address of the most recent packet.
+    pub fn last_sender(&self) -> Option<SocketAddr> {
+        self.last_sender
+    }
+
+    /// Link statistics of the connection with `addr`, if packets were exchanged.
+    pub fn connection_stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        self.connections
+            .get(addr)
+            .map(|connection| *connection.stats())
+    }
+
+    /// Starts probing the path MTU to `addr`, the result shows up in
+    /// `ConnectionStats::max_packet_bytes`. Returns false if no packets were
+    /// exchanged with `addr`.
+    pub fn probe_mtu(&mut self, addr: SocketAddr) -> bool {
+        self.connections.probe_mtu(addr)
+    }
+}
+
+// returns null and sets the last error if the socket could not be opened.
+fn create(io: io::Result<(socketio::Context, u16)>) -> *mut NetcodeServer {
+    match io {
+        Ok((socket_io, _port)) => {
+            let context = Box::new(NetcodeServer::new(Box::new(socket_io)));
+            unsafe { transmute::<Box<NetcodeServer>, *mut NetcodeServer>(context) }
+        }
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Opens a socket as described by `config`, null uses the defaults.
+/// Returns null if that failed, see `netcode_last_error`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_create_with(
+    config: *const NetcodeSocketConfig,
+) -> *mut NetcodeServer {
+    let config = config.as_ref().copied().unwrap_or_default();
+    let io = config
+        .local_addr()
+        .and_then(|local_addr| match config.polled {
+            true => socketio::Context::polled(local_addr, checksum::PROTOCOL),
+            false => socketio::Context::new(local_addr, checksum::PROTOCOL),
+        });
+    create(io)
+}
+
+/// Binds 127.0.0.1 on any free port and starts a receive thread.
+/// Returns null if the socket could not be opened, see `netcode_last_error`.
 #[no_mangle]
 pub extern "C" fn server_create() -> *mut NetcodeServer {
-    let local_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
-    let (socket_io, _port) = socketio::Context::new(local_addr);
-    let simulation = simulation::Simulation::start(0, time::Duration::from_millis(16), 8, 8);
+    unsafe { server_create_with(ptr::null()) }
+}
 
-    let context = Box::new(NetcodeServer {
-        io: socket_io,
-        simulation,
-    });
-    unsafe { transmute(context) }
+/// Like `server_create`, but without a receive thread: the socket is
+/// non-blocking and read from `server_update` on the calling thread.
+#[no_mangle]
+pub extern "C" fn server_create_polled() -> *mut NetcodeServer {
+    let config = NetcodeSocketConfig {
+        polled: true,
+        ..Default::default()
+    };
+    unsafe { server_create_with(&config) }
 }
 
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn server_destroy(context: *mut NetcodeServer) {
+    if context.is_null() {
+        return;
+    }
     let _dropped: Box<NetcodeServer> = transmute(context);
     _dropped.simulation.stop();
 }
 
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn server_update(context: *mut NetcodeServer) {
+pub unsafe extern "C" fn server_update(context: *mut NetcodeServer) -> i32 {
     let server = &mut *context;
+    match server.update() {
+        Ok(()) => NETCODE_OK,
+        Err(e) => {
+            set_last_error(e);
+            NETCODE_ERROR
+        }
+    }
+}
 
-    // tick server loop
-    const UPDATE_DELTA: time::Duration = time::Duration::from_millis(16);
-    server.simulation.update(UPDATE_DELTA);
+/// Number of received datagrams that were dropped, see `NetcodeServer::rejected_packets`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_rejected_packets(context: *const NetcodeServer) -> u64 {
+    let server = &*context;
+    server.rejected_packets()
+}
 
-    while let Ok(data) = server.io.try_recv() {
-        println!(
-            "server read {}({}) on main. time since recv: {}ms",
-            data.nbytes,
-            data.buffer.len(),
-            data.recv_time.elapsed().as_millis()
-        );
-        continue;
+/// Number of times the packet buffer pool ran empty and a buffer had to be allocated.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_pool_exhausted(context: *const NetcodeServer) -> u64 {
+    let server = &*context;
+    server.io.pool_exhausted()
+}
+
+/// Applies `config` to all packets received from now on. A null `config`
+/// turns the conditioner off.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_set_conditioner(
+    context: *mut NetcodeServer,
+    config: *const ConditionerConfig,
+) {
+    let server = &mut *context;
+    server.set_conditioner(config.as_ref().copied());
+}
+
+/// Writes the source address of the most recent packet to `address`.
+/// Returns false if nothing was received yet.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_last_sender(
+    context: *const NetcodeServer,
+    address: *mut NetcodeAddress,
+) -> bool {
+    let server = &*context;
+    match server.last_sender() {
+        Some(addr) => {
+            *address = NetcodeAddress::from(addr);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Writes the link statistics of the connection with `address` to `stats`.
+/// Returns false if no packets were exchanged with `address`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_connection_stats(
+    context: *const NetcodeServer,
+    address: *const NetcodeAddress,
+    stats: *mut ConnectionStats,
+) -> bool {
+    let server = &*context;
+    match server.connection_stats(SocketAddr::from(*address)) {
+        Some(connection_stats) => {
+            *stats = connection_stats;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Starts probing the path MTU to `address`, see `server_connection_stats`.
+/// Returns false if no packets were exchanged with `address`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_probe_mtu(
+    context: *mut NetcodeServer,
+    address: *const NetcodeAddress,
+) -> bool {
+    let server = &mut *context;
+    server.probe_mtu(SocketAddr::from(*address))
+}
+
+/// Only accepts clients with a connect token sealed with the `KEY_BYTES`
+/// long `key` that lists `address`, the address clients reach the server at.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_require_connect_tokens(
+    context: *mut NetcodeServer,
+    key: *const u8,
+    address: *const NetcodeAddress,
+) {
+    let server = &mut *context;
+    let mut token_key = [0u8; KEY_BYTES];
+    token_key.copy_from_slice(slice::from_raw_parts(key, KEY_BYTES));
+    server.require_connect_tokens(token_key, SocketAddr::from(*address));
+}
+
+/// Ends the connection with the client at `address` and tells the client.
+/// Returns `NETCODE_ERROR` if the disconnect could not be sent, see
+/// `netcode_last_error`, and `NETCODE_OK` otherwise, also if there was no
+/// such client.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_disconnect(
+    context: *mut NetcodeServer,
+    address: *const NetcodeAddress,
+) -> i32 {
+    let server = &mut *context;
+    match server.disconnect(SocketAddr::from(*address)) {
+        Ok(_) => NETCODE_OK,
+        Err(e) => {
+            set_last_error(e);
+            NETCODE_ERROR
+        }
     }
 }
 
+/// Turns encryption of connections accepted from now on on or off. Traffic
+/// of connections set up with a connect token is encrypted unless the server
+/// turns it off, which only debug builds can do to inspect packets. Clients
+/// are told when they are accepted. Returns false if it stays on.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn server_set_encryption(context: *mut NetcodeServer, enabled: bool) -> bool {
+    let server = &mut *context;
+    server.set_encryption(enabled)
+}
+
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn server_read(
@@ -67,14 +561,724 @@ pub unsafe extern "C" fn server_read(
 
 #[cfg(test)]
 mod tests {
+    use super::server_connection_stats;
     use super::server_create;
+    use super::server_create_polled;
+    use super::server_create_with;
     use super::server_destroy;
+    use super::server_last_sender;
+    use super::server_pool_exhausted;
+    use super::server_rejected_packets;
+    use super::server_require_connect_tokens;
+    use super::server_set_conditioner;
     use super::server_update;
+    use super::NetcodeServer;
+    use crate::client::{
+        client_connect, client_connect_with_token, client_connection_stats, client_create_polled,
+        client_create_with, client_deny_reason, client_destroy, client_probe_mtu, client_state,
+        client_update, NetcodeClient,
+    };
+    use crate::shared::{
+        address::{NetcodeAddress, NetcodeSocketConfig},
+        channel::{Message, RELIABLE_CHANNEL},
+        conditioner::ConditionerConfig,
+        connection::{ConnectionStats, Connections, CONNECTION_TIMEOUT},
+        error::netcode_last_error,
+        error::{NETCODE_ERROR, NETCODE_OK},
+        handshake::{ClientState, DenyReason, Handshake, PROTOCOL_VERSION},
+        mtu::PROBE_SIZES,
+        token::{self, ConnectToken},
+        transport::{MemoryTransport, Transport},
+    };
+    use std::{
+        ffi::{CStr, CString},
+        io,
+        net::{SocketAddr, UdpSocket},
+        thread, time,
+    };
+
+    // runs the handshake of `client` with `server` to its end.
+    fn connect(server: &mut NetcodeServer, client: &mut NetcodeClient) -> ClientState {
+        client.connect(server.local_addr());
+        while client.state() == ClientState::Connecting {
+            client.update().unwrap();
+            server.update().unwrap();
+            thread::yield_now();
+        }
+        client.state()
+    }
+
     #[test]
     fn instatiation() {
         let instance = server_create();
         assert!(!instance.is_null());
-        unsafe { server_update(instance) };
+        assert_eq!(unsafe { server_update(instance) }, NETCODE_OK);
+        assert_eq!(unsafe { server_rejected_packets(instance) }, 0);
+        assert_eq!(unsafe { server_pool_exhausted(instance) }, 0);
         unsafe { server_destroy(instance) };
+        unsafe { server_destroy(std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn sender_address() {
+        let instance = server_create();
+        let client = client_create_polled();
+        let mut address = NetcodeAddress::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+        assert!(!unsafe { server_last_sender(instance, &mut address) });
+
+        let server_addr = unsafe { (*instance).local_addr() };
+        unsafe { (*client).connect(server_addr) };
+        let start = time::Instant::now();
+        while unsafe { client_state(client) } == ClientState::Connecting {
+            unsafe { client_update(client) };
+            unsafe { server_update(instance) };
+            thread::yield_now();
+        }
+        assert_eq!(unsafe { client_state(client) }, ClientState::Connected);
+        unsafe { (*client).send_to(b"hello", server_addr).unwrap() };
+
+        while !unsafe { server_last_sender(instance, &mut address) } {
+            unsafe { server_update(instance) };
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert_eq!(SocketAddr::from(address), unsafe { (*client).local_addr() });
+        unsafe { client_destroy(client) };
+        unsafe { server_destroy(instance) };
+    }
+
+    #[test]
+    fn handshake() {
+        let (server_io, mut raw) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let (server_addr, raw_addr) = (server.local_addr(), raw.local_addr());
+        let mut datagram = Vec::new();
+        let receive = |raw: &mut MemoryTransport| {
+            let packet = raw.try_recv().unwrap();
+            Handshake::read(&packet.buffer).unwrap().unwrap()
+        };
+
+        // payloads from unconnected addresses go nowhere.
+        let mut connections = Connections::default();
+        let datagrams = connections
+            .write_datagrams(b"hello", server_addr, time::Instant::now())
+            .unwrap();
+        raw.send(&datagrams[0], server_addr).unwrap();
+        server.update().unwrap();
+        assert_eq!(server.last_sender(), None);
+        assert_eq!(server.rejected_packets(), 1);
+        assert_eq!(
+            server.send_to(b"hi", raw_addr).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+
+        // made up cookies do not connect.
+        let response = |cookie| Handshake::Response {
+            cookie,
+            nonce: 7,
+            token: Box::default(),
+        };
+        let request = |version| Handshake::Request {
+            version,
+            nonce: 7,
+            token: Box::default(),
+        };
+        response([7; 24]).write(&mut datagram);
+        raw.send(&datagram, server_addr).unwrap();
+        server.update().unwrap();
+        assert!(raw.try_recv().is_err());
+        assert!(server.connection_stats(raw_addr).is_none());
+        assert_eq!(server.rejected_packets(), 2);
+
+        request(0).write(&mut datagram);
+        raw.send(&datagram, server_addr).unwrap();
+        server.update().unwrap();
+        assert_eq!(
+            receive(&mut raw),
+            Handshake::denied(DenyReason::VersionMismatch, 7)
+        );
+
+        request(PROTOCOL_VERSION).write(&mut datagram);
+        raw.send(&datagram, server_addr).unwrap();
+        server.update().unwrap();
+        let cookie = match receive(&mut raw) {
+            Handshake::Challenge { cookie, nonce: 7 } => cookie,
+            packet => panic!("expected a challenge, got {:?}", packet),
+        };
+        assert!(server.connection_stats(raw_addr).is_none());
+
+        // a lost accept is sent again.
+        response(cookie).write(&mut datagram);
+        for _ in 0..2 {
+            raw.send(&datagram, server_addr).unwrap();
+            server.update().unwrap();
+            assert_eq!(receive(&mut raw), Handshake::accepted(7, false));
+        }
+        assert!(server.connection_stats(raw_addr).is_some());
+        raw.send(&datagrams[0], server_addr).unwrap();
+        server.update().unwrap();
+        assert_eq!(server.last_sender(), Some(raw_addr));
+
+        // a response from before the client connected again is ignored.
+        thread::sleep(time::Duration::from_millis(2));
+        request(PROTOCOL_VERSION).write(&mut datagram);
+        raw.send(&datagram, server_addr).unwrap();
+        server.update().unwrap();
+        let fresh = match receive(&mut raw) {
+            Handshake::Challenge { cookie, .. } => cookie,
+            packet => panic!("expected a challenge, got {:?}", packet),
+        };
+        response(fresh).write(&mut datagram);
+        raw.send(&datagram, server_addr).unwrap();
+        server.update().unwrap();
+        assert_eq!(receive(&mut raw), Handshake::accepted(7, false));
+        response(cookie).write(&mut datagram);
+        raw.send(&datagram, server_addr).unwrap();
+        server.update().unwrap();
+        assert!(raw.try_recv().is_err());
+        assert_eq!(server.clients[&raw_addr].cookie, fresh);
+    }
+
+    // updates `server` and `clients` until none of them is connecting.
+    fn run_handshakes(
+        server: *mut NetcodeServer,
+        clients: &[*mut NetcodeClient],
+    ) -> Vec<ClientState> {
+        let start = time::Instant::now();
+        let states = || -> Vec<_> {
+            clients
+                .iter()
+                .map(|client| unsafe { client_state(*client) })
+                .collect()
+        };
+        while states().contains(&ClientState::Connecting) {
+            for client in clients {
+                assert_eq!(unsafe { client_update(*client) }, NETCODE_OK);
+            }
+            assert_eq!(unsafe { server_update(server) }, NETCODE_OK);
+            assert!(start.elapsed() < time::Duration::from_secs(10));
+            thread::yield_now();
+        }
+        states()
+    }
+
+    #[test]
+    fn server_full() {
+        let server = server_create_polled();
+        let server_addr = NetcodeAddress::from(unsafe { (*server).local_addr() });
+        let clients: Vec<_> = (0..9).map(|_| client_create_polled()).collect();
+        for client in &clients {
+            unsafe { client_connect(*client, &server_addr) };
+        }
+
+        let states = run_handshakes(server, &clients);
+        let denied = states
+            .iter()
+            .position(|state| *state == ClientState::Denied)
+            .unwrap();
+        assert_eq!(
+            states
+                .iter()
+                .filter(|state| **state == ClientState::Connected)
+                .count(),
+            8
+        );
+        assert_eq!(
+            unsafe { client_deny_reason(clients[denied]) },
+            DenyReason::ServerFull
+        );
+        for client in clients {
+            unsafe { client_destroy(client) };
+        }
+        unsafe { server_destroy(server) };
+    }
+
+    #[test]
+    fn connect_tokens() {
+        let key = token::generate_key();
+        let server = server_create_polled();
+        let server_addr = unsafe { (*server).local_addr() };
+        let address = NetcodeAddress::from(server_addr);
+        unsafe { server_require_connect_tokens(server, key.as_ptr(), &address) };
+        let clients: Vec<_> = (0..4).map(|_| client_create_polled()).collect();
+        let lifetime = time::Duration::from_secs(30);
+        let elsewhere = SocketAddr::from(([127, 0, 0, 1], 9));
+        let valid = ConnectToken::generate(&key, 1, lifetime, &[server_addr], b"").unwrap();
+        let tokens = [
+            ConnectToken::generate(&token::generate_key(), 1, lifetime, &[server_addr], b""),
+            ConnectToken::generate(&key, 1, time::Duration::from_secs(0), &[server_addr], b""),
+            // the public list is changed, the sealed one still names another server.
+            ConnectToken::generate(&key, 1, lifetime, &[elsewhere], b"").map(|token| {
+                ConnectToken {
+                    server_addresses: vec![server_addr],
+                    ..token
+                }
+            }),
+        ];
+
+        // no token at all.
+        unsafe { client_connect(clients[0], &address) };
+        for (client, token) in clients[1..].iter().zip(tokens.iter()) {
+            let bytes = token.as_ref().unwrap().to_bytes();
+            let result = unsafe { client_connect_with_token(*client, bytes.as_ptr(), bytes.len()) };
+            assert_eq!(result, NETCODE_OK);
+        }
+        let states = run_handshakes(server, &clients);
+        assert!(states.iter().all(|state| *state == ClientState::Denied));
+        let reasons: Vec<_> = clients
+            .iter()
+            .map(|client| unsafe { client_deny_reason(*client) })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                DenyReason::InvalidToken,
+                DenyReason::InvalidToken,
+                DenyReason::TokenExpired,
+                DenyReason::InvalidToken
+            ]
+        );
+
+        let bytes = valid.to_bytes();
+        unsafe { client_connect_with_token(clients[0], bytes.as_ptr(), bytes.len()) };
+        assert_eq!(
+            run_handshakes(server, &clients[..1]),
+            [ClientState::Connected]
+        );
+        // the same token a second time.
+        unsafe { client_connect_with_token(clients[1], bytes.as_ptr(), bytes.len()) };
+        assert_eq!(
+            run_handshakes(server, &clients[1..2]),
+            [ClientState::Denied]
+        );
+        assert_eq!(
+            unsafe { client_deny_reason(clients[1]) },
+            DenyReason::AlreadyConnected
+        );
+        let garbage = [0u8; 10];
+        let result =
+            unsafe { client_connect_with_token(clients[1], garbage.as_ptr(), garbage.len()) };
+        assert_eq!(result, NETCODE_ERROR);
+
+        for client in clients {
+            unsafe { client_destroy(client) };
+        }
+        unsafe { server_destroy(server) };
+    }
+
+    #[test]
+    fn encryption() {
+        let key = token::generate_key();
+        let lifetime = time::Duration::from_secs(30);
+        for encrypted in [true, false] {
+            let (server_io, client_io) = MemoryTransport::pair();
+            let mut server = NetcodeServer::new(Box::new(server_io));
+            let mut client = NetcodeClient::new(Box::new(client_io));
+            let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+            server.require_connect_tokens(key, server_addr);
+            // release builds keep encrypting.
+            let can_switch = encrypted || cfg!(debug_assertions);
+            assert_eq!(server.set_encryption(encrypted), can_switch);
+            let token = ConnectToken::generate(&key, 1, lifetime, &[server_addr], b"").unwrap();
+            client.connect_with_token(token);
+            while client.state() == ClientState::Connecting {
+                client.update().unwrap();
+                server.update().unwrap();
+            }
+            assert_eq!(client.state(), ClientState::Connected);
+            // the client follows the server, it only reads what it can decrypt.
+            let connection = server.connections.get(client_addr).unwrap();
+            assert_eq!(connection.is_encrypted(), !can_switch || encrypted);
+
+            client.send_to(b"buttons", server_addr).unwrap();
+            server.update().unwrap();
+            assert_eq!(server.last_sender(), Some(client_addr));
+            server.send_to(b"snapshot", client_addr).unwrap();
+            client.update().unwrap();
+            assert_eq!(client.last_sender(), Some(server_addr));
+        }
+
+        // connections without a connect token have no keys.
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+        let connection = server.connections.get(client.local_addr()).unwrap();
+        assert!(!connection.is_encrypted());
+
+        // nor can a server that does not check tokens set up their keys.
+        let server_addr = server.local_addr();
+        let token = ConnectToken::generate(&key, 1, lifetime, &[server_addr], b"").unwrap();
+        client.connect_with_token(token);
+        while client.state() == ClientState::Connecting {
+            client.update().unwrap();
+            server.update().unwrap();
+        }
+        assert_eq!(client.state(), ClientState::Denied);
+        assert_eq!(client.deny_reason(), DenyReason::InvalidToken);
+    }
+
+    #[test]
+    fn slow_handshake() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+        let key = token::generate_key();
+        server.require_connect_tokens(key, server_addr);
+        // challenges arrive late, twice and out of order.
+        let slow = ConditionerConfig {
+            latency_ms: 150,
+            jitter_ms: 120,
+            duplicate_percent: 100.0,
+            seed: 2,
+            ..Default::default()
+        };
+        client.set_conditioner(Some(slow));
+        let lifetime = time::Duration::from_secs(30);
+        let token = ConnectToken::generate(&key, 1, lifetime, &[server_addr], b"").unwrap();
+        client.connect_with_token(token);
+        while client.state() == ClientState::Connecting {
+            client.update().unwrap();
+            server.update().unwrap();
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        assert_eq!(client.state(), ClientState::Connected);
+
+        // both sides derived their keys from the same cookie.
+        client.set_conditioner(None);
+        client.send_to(b"buttons", server_addr).unwrap();
+        server.update().unwrap();
+        assert_eq!(server.last_sender(), Some(client_addr));
+        server.send_to(b"snapshot", client_addr).unwrap();
+        client.update().unwrap();
+        assert_eq!(client.last_sender(), Some(server_addr));
+    }
+
+    #[test]
+    fn memory_transport() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        assert_eq!(
+            client
+                .send_to(b"hello", server.local_addr())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+
+        client.send_to(b"hello", server.local_addr()).unwrap();
+        server.update().unwrap();
+        assert_eq!(server.last_sender(), Some(client.local_addr()));
+
+        server.send_to(b"welcome", client.local_addr()).unwrap();
+        client.update().unwrap();
+        assert_eq!(client.last_sender(), Some(server.local_addr()));
+    }
+
+    #[test]
+    fn connection_stats() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = Box::new(NetcodeServer::new(Box::new(server_io)));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let client_addr = NetcodeAddress::from(client.local_addr());
+        let mut stats = ConnectionStats::default();
+        assert!(!unsafe { server_connection_stats(&*server, &client_addr, &mut stats) });
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+
+        for _ in 0..10 {
+            client.send_to(b"ping", server.local_addr()).unwrap();
+            server.update().unwrap();
+            server.send_to(b"pong", client.local_addr()).unwrap();
+            client.update().unwrap();
+        }
+        assert!(unsafe { server_connection_stats(&*server, &client_addr, &mut stats) });
+        assert_eq!(stats.sent_packets, 10);
+        assert_eq!(stats.received_packets, 10);
+        // the last pong is not acknowledged yet.
+        assert_eq!(stats.acked_packets, 9);
+        assert_eq!(stats.packet_loss_percent, 0.0);
+
+        // every ping got a pong.
+        let stats = client.connection_stats(server.local_addr()).unwrap();
+        assert_eq!(stats.acked_packets, 10);
+    }
+
+    #[test]
+    fn messages() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+        let lossy = ConditionerConfig {
+            loss_percent: 30.0,
+            seed: 7,
+            ..Default::default()
+        };
+        server.set_conditioner(Some(lossy));
+        client.set_conditioner(Some(lossy));
+
+        for i in 0..20 {
+            let message = Message::new(i, b"round started");
+            client
+                .send_message(server_addr, RELIABLE_CHANNEL, message)
+                .unwrap();
+        }
+        let mut received = Vec::new();
+        let start = time::Instant::now();
+        while received.len() < 20 {
+            client.update().unwrap();
+            server.update().unwrap();
+            while let Some(message) = server.receive_message(client_addr, RELIABLE_CHANNEL) {
+                received.push(message.kind);
+            }
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reconnect() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+        let exchange = |server: &mut NetcodeServer, client: &mut NetcodeClient, kind| {
+            client
+                .send_message(server_addr, RELIABLE_CHANNEL, Message::new(kind, b"ready"))
+                .unwrap();
+            client.update().unwrap();
+            server.update().unwrap();
+            server
+                .receive_message(client_addr, RELIABLE_CHANNEL)
+                .map(|message| message.kind)
+        };
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+        assert_eq!(exchange(&mut server, &mut client, 1), Some(1));
+
+        // the same address starts over, the old connection must not linger.
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+        assert_eq!(server.clients.len(), 1);
+        assert_eq!(exchange(&mut server, &mut client, 2), Some(2));
+    }
+
+    #[test]
+    fn reconnect_within_a_millisecond() {
+        let (server_io, _) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let (addr, now) = (
+            SocketAddr::from(([127, 0, 0, 1], 4000)),
+            time::Instant::now(),
+        );
+        // both handshakes get the same cookie.
+        let cookie = server.cookies.issue(addr, now);
+        let response = |nonce| Handshake::Response {
+            cookie,
+            nonce,
+            token: Box::default(),
+        };
+        let sent = |server: &NetcodeServer| server.connection_stats(addr).unwrap().sent_packets;
+
+        let accepted = Some(Handshake::accepted(1, false));
+        assert_eq!(server.handshake(response(1), addr, now), accepted);
+        server
+            .connections
+            .write_datagrams(b"hi", addr, now)
+            .unwrap();
+        assert_eq!(server.handshake(response(1), addr, now), accepted);
+        assert_eq!(sent(&server), 1);
+        let accepted = Some(Handshake::accepted(2, false));
+        assert_eq!(server.handshake(response(2), addr, now), accepted);
+        assert_eq!(sent(&server), 0);
+        assert_eq!(server.clients.len(), 1);
+    }
+
+    #[test]
+    fn timeout() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let client_addr = client.local_addr();
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+
+        let later = time::Instant::now() + CONNECTION_TIMEOUT;
+        server.update_at(later).unwrap();
+        assert!(server.clients.is_empty());
+        assert!(server.connection_stats(client_addr).is_none());
+        assert_eq!(
+            server.send_to(b"anyone?", client_addr).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+
+    #[test]
+    fn disconnect() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = NetcodeServer::new(Box::new(server_io));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+
+        assert!(server.disconnect(client_addr).unwrap());
+        assert!(!server.disconnect(client_addr).unwrap());
+        assert!(server.clients.is_empty());
+        client.update().unwrap();
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert!(client.connection_stats(server_addr).is_none());
+
+        // and the other way around.
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+        assert_eq!(server.clients.len(), 1);
+        client.disconnect().unwrap();
+        assert_eq!(client.state(), ClientState::Disconnected);
+        server.update().unwrap();
+        assert!(server.clients.is_empty());
+    }
+
+    #[test]
+    fn conditioner() {
+        let (server_io, client_io) = MemoryTransport::pair();
+        let mut server = Box::new(NetcodeServer::new(Box::new(server_io)));
+        let mut client = NetcodeClient::new(Box::new(client_io));
+        assert_eq!(connect(&mut server, &mut client), ClientState::Connected);
+
+        let config = ConditionerConfig {
+            loss_percent: 100.0,
+            ..Default::default()
+        };
+        unsafe { server_set_conditioner(&mut *server, &config) };
+        client.send_to(b"lost", server.local_addr()).unwrap();
+        server.update().unwrap();
+        assert_eq!(server.last_sender(), None);
+
+        unsafe { server_set_conditioner(&mut *server, std::ptr::null()) };
+        client.send_to(b"hello", server.local_addr()).unwrap();
+        server.update().unwrap();
+        assert_eq!(server.last_sender(), Some(client.local_addr()));
+    }
+
+    #[test]
+    fn polled() {
+        let server = server_create_polled();
+        let client = client_create_polled();
+        let mut address = NetcodeAddress::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+
+        let server_addr = unsafe { (*server).local_addr() };
+        let state = unsafe { connect(&mut *server, &mut *client) };
+        assert_eq!(state, ClientState::Connected);
+        unsafe { (*client).send_to(b"hello", server_addr).unwrap() };
+
+        let start = time::Instant::now();
+        while !unsafe { server_last_sender(server, &mut address) } {
+            unsafe { server_update(server) };
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert_eq!(SocketAddr::from(address), unsafe { (*client).local_addr() });
+        unsafe { client_destroy(client) };
+        unsafe { server_destroy(server) };
+    }
+
+    #[test]
+    fn large_payload() {
+        let server = server_create_polled();
+        let client = client_create_polled();
+        let server_addr = unsafe { (*server).local_addr() };
+        let snapshot = vec![7u8; 20_000];
+        let state = unsafe { connect(&mut *server, &mut *client) };
+        assert_eq!(state, ClientState::Connected);
+        unsafe { (*client).send_to(&snapshot, server_addr).unwrap() };
+
+        // the sender is only known once all fragments arrived.
+        let mut address = NetcodeAddress::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+        let start = time::Instant::now();
+        while !unsafe { server_last_sender(server, &mut address) } {
+            assert_eq!(unsafe { server_update(server) }, NETCODE_OK);
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert_eq!(unsafe { server_rejected_packets(server) }, 0);
+        unsafe { client_destroy(client) };
+        unsafe { server_destroy(server) };
+    }
+
+    #[test]
+    fn mtu_probe() {
+        let server = server_create_polled();
+        let client = client_create_polled();
+        let server_addr = NetcodeAddress::from(unsafe { (*server).local_addr() });
+        let mut stats = ConnectionStats::default();
+        assert!(!unsafe { client_probe_mtu(client, &server_addr) });
+        let state = unsafe { connect(&mut *server, &mut *client) };
+        assert_eq!(state, ClientState::Connected);
+        assert!(unsafe { client_probe_mtu(client, &server_addr) });
+
+        // loopback takes the largest probe.
+        let start = time::Instant::now();
+        loop {
+            assert_eq!(unsafe { server_update(server) }, NETCODE_OK);
+            assert_eq!(unsafe { client_update(client) }, NETCODE_OK);
+            assert!(unsafe { client_connection_stats(client, &server_addr, &mut stats) });
+            if stats.max_packet_bytes as usize == PROBE_SIZES[0] {
+                break;
+            }
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        unsafe { client_destroy(client) };
+        unsafe { server_destroy(server) };
+    }
+
+    #[test]
+    fn ipv6() {
+        if UdpSocket::bind("[::1]:0").is_err() {
+            return; // no ipv6 on this host.
+        }
+        let bind_address = CString::new("[::1]:0").unwrap();
+        let config = NetcodeSocketConfig {
+            bind_address: bind_address.as_ptr(),
+            polled: true,
+        };
+        let server = unsafe { server_create_with(&config) };
+        let client = unsafe { client_create_with(&config) };
+        assert!(!server.is_null() && !client.is_null());
+
+        let server_addr = unsafe { (*server).local_addr() };
+        assert!(server_addr.is_ipv6());
+        let state = unsafe { connect(&mut *server, &mut *client) };
+        assert_eq!(state, ClientState::Connected);
+        unsafe { (*client).send_to(b"hello", server_addr).unwrap() };
+
+        let mut address = NetcodeAddress::from(SocketAddr::from(([0, 0, 0, 0], 0)));
+        let start = time::Instant::now();
+        while !unsafe { server_last_sender(server, &mut address) } {
+            unsafe { server_update(server) };
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert!(address.is_ipv6);
+        assert_eq!(SocketAddr::from(address), unsafe { (*client).local_addr() });
+        unsafe { client_destroy(client) };
+        unsafe { server_destroy(server) };
+    }
+
+    #[test]
+    fn invalid_bind_address() {
+        let bind_address = CString::new("127.0.0.1:port").unwrap();
+        let config = NetcodeSocketConfig {
+            bind_address: bind_address.as_ptr(),
+            polled: false,
+        };
+        let server = unsafe { server_create_with(&config) };
+        assert!(server.is_null());
+        let error = unsafe { CStr::from_ptr(netcode_last_error()) };
+        assert_eq!(
+            error.to_str().unwrap(),
+            "invalid bind address '127.0.0.1:port'"
+        );
     }
 }
@@ -43,13 +43,9 @@ impl Simulation {
     pub fn read(&mut self, buffer: &[u8]) {
         let current: FrameId = 0;
 
-        // parse buffer and push to ctrl and main_world.
-        if buffer[0] == 0 {
-            self.add_actor(current, "test");
-        } else if buffer[0] == 1 {
-            let actor: ActorId = NonZeroI16::new(1).unwrap();
-            self.remove_actor(actor);
-        } else if buffer[0] == 2 {
+        // parse buffer and push to ctrl. actors are only added and removed
+        // with the clients of the server, never by what a datagram says.
+        if buffer.first() == Some(&2) {
             let actor: ActorId = NonZeroI16::new(1).unwrap();
             if let Some(actor_index) = self.ids.find_index(actor) {
                 let commands = [SimCommand { buttons: 0 }];
@@ -78,9 +74,19 @@ impl Simulation {
 mod tests {
     use crate::{server::world::World, shared::FrameId};
 
-    use super::Control;
+    use super::{Control, Simulation};
     use std::time::Duration;
 
+    #[test]
+    fn read() {
+        let mut simulation = Simulation::start(0, Duration::from_millis(16), 2, 0);
+        let actor = simulation.add_actor(0, "first").unwrap();
+        simulation.read(&[]);
+        simulation.read(&[1]);
+        simulation.read(&[2]);
+        assert!(simulation.ids.find_index(actor).is_some());
+    }
+
     #[test]
     fn ctrl_world_integration() {
         const CAPACITY: i16 = 2;
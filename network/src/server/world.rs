@@ -37,6 +37,7 @@ impl World {
         self.actor_info.swap_remove(index);
     }
 
+    #[allow(dead_code)]
     pub fn add_object(&mut self) -> Option<ObjectId> {
         if self.objects.capacity() == self.objects.len() {
             return None;
@@ -48,6 +49,7 @@ impl World {
         Some(new_id)
     }
 
+    #[allow(dead_code)]
     pub fn remove_object(&mut self, id: ObjectId) -> bool {
         if let Some(index) = self.objects.iter().position(|&value| value == id) {
             self.objects.swap_remove(index);
@@ -80,7 +82,7 @@ mod tests {
         let mut ctx = World::new(0, 8, 1);
         let obj = ctx.add_object();
         assert!(obj.is_some());
-        assert_eq!(ctx.remove_object(obj.unwrap()), true);
+        assert!(ctx.remove_object(obj.unwrap()));
 
         let obj = ctx.add_object();
         assert!(obj.is_some());
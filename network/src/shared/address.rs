@@ -0,0 +1,179 @@
+use std::ffi::CStr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::raw::c_char;
+use std::ptr;
+
+/// Socket address as seen from C. IPv4 addresses use the first 4 bytes of `ip`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetcodeAddress {
+    pub ip: [u8; 16],
+    pub port: u16,
+    pub is_ipv6: bool,
+    /// IPv6 flow label, 0 for IPv4.
+    pub flowinfo: u32,
+    /// IPv6 interface of link-local addresses such as `fe80::1%2`, 0 for
+    /// IPv4. Addresses only match with the same scope.
+    pub scope_id: u32,
+}
+
+impl From<SocketAddr> for NetcodeAddress {
+    fn from(addr: SocketAddr) -> NetcodeAddress {
+        let mut ip = [0u8; 16];
+        let (is_ipv6, flowinfo, scope_id) = match addr {
+            SocketAddr::V4(v4) => {
+                ip[..4].copy_from_slice(&v4.ip().octets());
+                (false, 0, 0)
+            }
+            SocketAddr::V6(v6) => {
+                ip.copy_from_slice(&v6.ip().octets());
+                (true, v6.flowinfo(), v6.scope_id())
+            }
+        };
+        NetcodeAddress {
+            ip,
+            port: addr.port(),
+            is_ipv6,
+            flowinfo,
+            scope_id,
+        }
+    }
+}
+
+impl From<NetcodeAddress> for SocketAddr {
+    fn from(addr: NetcodeAddress) -> SocketAddr {
+        match addr.is_ipv6 {
+            true => SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.ip),
+                addr.port,
+                addr.flowinfo,
+                addr.scope_id,
+            )),
+            false => SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(addr.ip[0], addr.ip[1], addr.ip[2], addr.ip[3]),
+                addr.port,
+            )),
+        }
+    }
+}
+
+/// Socket setup for `server_create_with` and `client_create_with`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NetcodeSocketConfig {
+    /// IPv4 or IPv6 literal with an optional port, e.g. `0.0.0.0:4000`,
+    /// `[::1]:4000` or `::`. `[::]` accepts IPv4 and IPv6 where the OS allows
+    /// dual-stack sockets. Null binds `127.0.0.1` on any free port.
+    pub bind_address: *const c_char,
+    /// Read the socket from the update call instead of a receive thread.
+    pub polled: bool,
+}
+
+impl Default for NetcodeSocketConfig {
+    fn default() -> Self {
+        NetcodeSocketConfig {
+            bind_address: ptr::null(),
+            polled: false,
+        }
+    }
+}
+
+impl NetcodeSocketConfig {
+    /// # Safety
+    /// `bind_address` has to be null or a nul-terminated string.
+    pub unsafe fn local_addr(&self) -> io::Result<SocketAddr> {
+        if self.bind_address.is_null() {
+            return Ok(SocketAddr::from(([127, 0, 0, 1], 0)));
+        }
+        let text = CStr::from_ptr(self.bind_address).to_string_lossy();
+        parse_bind_address(&text).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid bind address '{}'", text),
+            )
+        })
+    }
+}
+
+/// Parses `ip:port`, `[ipv6]:port` or a bare ip, which binds any free port.
+pub fn parse_bind_address(text: &str) -> Option<SocketAddr> {
+    let text = text.trim();
+    if let Ok(addr) = text.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = text.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bind_address, NetcodeAddress, NetcodeSocketConfig};
+    use std::{
+        ffi::CString,
+        net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+        str::FromStr,
+    };
+
+    #[test]
+    fn conversion() {
+        for text in [
+            "127.0.0.1:8888",
+            "10.1.2.3:0",
+            "[::1]:5000",
+            "[fe80::1:2]:65535",
+        ]
+        .iter()
+        {
+            let addr = SocketAddr::from_str(text).unwrap();
+            let c_addr = NetcodeAddress::from(addr);
+            assert_eq!(c_addr.is_ipv6, addr.is_ipv6());
+            assert_eq!(c_addr.port, addr.port());
+            assert_eq!(SocketAddr::from(c_addr), addr);
+        }
+
+        let c_addr = NetcodeAddress::from(SocketAddr::from(([192, 168, 0, 1], 80)));
+        assert_eq!(c_addr.ip[..4], [192, 168, 0, 1]);
+        assert_eq!(c_addr.ip[4..], [0; 12]);
+
+        // link-local addresses keep their interface.
+        let addr = SocketAddr::from_str("[fe80::1%3]:4000").unwrap();
+        let c_addr = NetcodeAddress::from(addr);
+        assert_eq!(c_addr.scope_id, 3);
+        assert_eq!(SocketAddr::from(c_addr), addr);
+        let other_interface = NetcodeAddress {
+            scope_id: 4,
+            ..c_addr
+        };
+        assert_ne!(SocketAddr::from(other_interface), addr);
+        let labelled = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4000, 7, 0));
+        assert_eq!(NetcodeAddress::from(labelled).flowinfo, 7);
+        assert_eq!(SocketAddr::from(NetcodeAddress::from(labelled)), labelled);
+    }
+
+    #[test]
+    fn bind_address() {
+        let parsed = |text| parse_bind_address(text).map(|addr| addr.to_string());
+        assert_eq!(parsed("127.0.0.1:4000").unwrap(), "127.0.0.1:4000");
+        assert_eq!(parsed("0.0.0.0").unwrap(), "0.0.0.0:0");
+        assert_eq!(parsed("[::1]:4000").unwrap(), "[::1]:4000");
+        assert_eq!(parsed("::").unwrap(), "[::]:0");
+        assert_eq!(parsed("[::]").unwrap(), "[::]:0");
+        assert_eq!(parsed(" 10.0.0.1:1 ").unwrap(), "10.0.0.1:1");
+        assert_eq!(parsed("localhost:4000"), None);
+        assert_eq!(parsed("127.0.0.1:99999"), None);
+        assert_eq!(parsed(""), None);
+
+        let config = NetcodeSocketConfig::default();
+        let addr = unsafe { config.local_addr() }.unwrap();
+        assert_eq!(addr, SocketAddr::from(([127, 0, 0, 1], 0)));
+
+        let text = CString::new("not an address").unwrap();
+        let config = NetcodeSocketConfig {
+            bind_address: text.as_ptr(),
+            ..Default::default()
+        };
+        let error = unsafe { config.local_addr() }.unwrap_err();
+        assert_eq!(error.to_string(), "invalid bind address 'not an address'");
+    }
+}
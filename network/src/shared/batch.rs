@@ -0,0 +1,275 @@
+//! Sending and receiving several datagrams per syscall.
+//!
+//! With the `mmsg` feature on Linux this uses `sendmmsg`/`recvmmsg`, anywhere
+//! else it falls back to one `send_to`/`recv_from` per datagram.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Largest number of datagrams moved by one call.
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+pub const MAX_BATCH: usize = 32;
+#[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+pub const MAX_BATCH: usize = 1;
+
+/// Receives up to `buffers.len()` datagrams. Blocks like `recv_from` until the
+/// first one arrives, then takes whatever else is already queued. For every
+/// datagram read into `buffers[i]` its size and sender are in `received[i]`.
+/// Returns the number of datagrams received.
+pub fn recv_batch(
+    socket: &UdpSocket,
+    buffers: &mut [Vec<u8>],
+    received: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<usize> {
+    received.clear();
+    imp::recv_batch(socket, buffers, received)
+}
+
+/// Sends every buffer to its address. Returns the number of datagrams sent,
+/// which is less than `packets.len()` only if a later one failed.
+pub fn send_batch<B: AsRef<[u8]>>(
+    socket: &UdpSocket,
+    packets: &[(B, SocketAddr)],
+) -> io::Result<usize> {
+    let mut sent = 0;
+    for chunk in packets.chunks(MAX_BATCH) {
+        match imp::send_batch(socket, chunk) {
+            Ok(count) => {
+                sent += count;
+                if count < chunk.len() {
+                    break;
+                }
+            }
+            Err(e) if sent == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod imp {
+    use super::MAX_BATCH;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    fn to_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sockaddr = storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sockaddr).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*sockaddr).sin_port = addr.port().to_be();
+                    (*sockaddr).sin_addr = libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    };
+                }
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let sockaddr = storage as *mut _ as *mut libc::sockaddr_in6;
+                unsafe {
+                    (*sockaddr).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*sockaddr).sin6_port = addr.port().to_be();
+                    (*sockaddr).sin6_flowinfo = addr.flowinfo();
+                    (*sockaddr).sin6_addr = libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    };
+                    (*sockaddr).sin6_scope_id = addr.scope_id();
+                }
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sockaddr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(sockaddr.sin_addr.s_addr.to_ne_bytes());
+                let port = u16::from_be(sockaddr.sin_port);
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            }
+            libc::AF_INET6 => {
+                let sockaddr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sockaddr.sin6_addr.s6_addr);
+                let port = u16::from_be(sockaddr.sin6_port);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    port,
+                    sockaddr.sin6_flowinfo,
+                    sockaddr.sin6_scope_id,
+                )))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported address family",
+            )),
+        }
+    }
+
+    pub fn recv_batch(
+        socket: &UdpSocket,
+        buffers: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        let count = buffers.len().min(MAX_BATCH);
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        for ((buffer, iovec), (addr, msg)) in buffers
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(addrs.iter_mut().zip(msgs.iter_mut()))
+        {
+            iovec.iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = buffer.len();
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (msg, addr) in msgs.iter().zip(addrs.iter()).take(result as usize) {
+            received.push((msg.msg_len as usize, from_sockaddr(addr)?));
+        }
+        Ok(result as usize)
+    }
+
+    pub fn send_batch<B: AsRef<[u8]>>(
+        socket: &UdpSocket,
+        packets: &[(B, SocketAddr)],
+    ) -> io::Result<usize> {
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        for (((buffer, dest), iovec), (addr, msg)) in packets
+            .iter()
+            .map(|(buffer, dest)| (buffer.as_ref(), dest))
+            .zip(iovecs.iter_mut())
+            .zip(addrs.iter_mut().zip(msgs.iter_mut()))
+        {
+            // the kernel only reads from send buffers.
+            iovec.iov_base = buffer.as_ptr() as *mut libc::c_void;
+            iovec.iov_len = buffer.len();
+            msg.msg_hdr.msg_namelen = to_sockaddr(dest, addr);
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        let count = packets.len().min(MAX_BATCH);
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as usize)
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+mod imp {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub fn recv_batch(
+        socket: &UdpSocket,
+        buffers: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        match buffers.first_mut() {
+            Some(buffer) => {
+                received.push(socket.recv_from(buffer)?);
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    pub fn send_batch<B: AsRef<[u8]>>(
+        socket: &UdpSocket,
+        packets: &[(B, SocketAddr)],
+    ) -> io::Result<usize> {
+        for (sent, (buffer, dest)) in packets.iter().enumerate() {
+            if let Err(e) = socket.send_to(buffer.as_ref(), *dest) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+        Ok(packets.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recv_batch, send_batch, MAX_BATCH};
+    use std::net::{SocketAddr, UdpSocket};
+
+    #[test]
+    fn round_trip() {
+        let hostname = [127, 0, 0, 1];
+        let receiver = UdpSocket::bind(SocketAddr::from((hostname, 0))).unwrap();
+        let sender = UdpSocket::bind(SocketAddr::from((hostname, 0))).unwrap();
+        let dest = receiver.local_addr().unwrap();
+
+        // more than one batch worth of packets.
+        let count = MAX_BATCH * 2 + 1;
+        let packets: Vec<_> = (0..count).map(|i| (vec![i as u8; i + 1], dest)).collect();
+        assert_eq!(send_batch(&sender, &packets).unwrap(), count);
+
+        let mut buffers = vec![vec![0u8; 1500]; MAX_BATCH];
+        let mut received = Vec::with_capacity(MAX_BATCH);
+        let mut next = 0;
+        while next < count {
+            let batch = recv_batch(&receiver, &mut buffers, &mut received).unwrap();
+            assert_eq!(batch, received.len());
+            assert!(batch > 0 && batch <= MAX_BATCH);
+            for (buffer, (nbytes, addr)) in buffers.iter().zip(received.iter()) {
+                assert_eq!(*addr, sender.local_addr().unwrap());
+                assert_eq!(&buffer[..*nbytes], &packets[next].0[..]);
+                next += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn ipv6() {
+        let receiver = match UdpSocket::bind("[::1]:0") {
+            Ok(socket) => socket,
+            Err(_) => return, // no ipv6 on this host.
+        };
+        let sender = UdpSocket::bind("[::1]:0").unwrap();
+        let dest = receiver.local_addr().unwrap();
+        assert_eq!(send_batch(&sender, &[(b"six", dest)]).unwrap(), 1);
+
+        let mut buffers = vec![vec![0u8; 16]];
+        let mut received = Vec::new();
+        assert_eq!(
+            recv_batch(&receiver, &mut buffers, &mut received).unwrap(),
+            1
+        );
+        assert_eq!(received[0], (3, sender.local_addr().unwrap()));
+        assert_eq!(&buffers[0][..3], b"six");
+    }
+}
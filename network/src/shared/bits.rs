@@ -1,30 +1,532 @@
-use std::{
-    io::{Read, Write},
-    slice,
-};
+use super::crypto::CipherError;
+use std::{fmt, io::Read, slice};
 
-#[derive(Debug)]
+#[cfg(feature = "derive")]
+pub use network_derive::NetSerialize;
+
+/// Bit stream that can either write values out or read them in.
+///
+/// Serialization code is written once against this trait and used for encoding,
+/// decoding and size calculation. Values are passed by mutable reference: writing
+/// streams consume them, reading streams fill them in.
+pub trait Stream {
+    fn is_writing(&self) -> bool;
+
+    fn is_reading(&self) -> bool {
+        !self.is_writing()
+    }
+
+    /// Bits written or read so far.
+    fn position(&self) -> i64;
+
+    /// Attaches the stream position and the name of the value being processed
+    /// to `error`. Only reading streams annotate, writing ones return it as is.
+    fn annotate(&self, _offset: i64, _what: &'static str, error: Error) -> Error {
+        error
+    }
+
+    fn serialize_bits(&mut self, value: &mut u32, bits: i32) -> Result<(), Error>;
+
+    /// Pads to the next byte boundary.
+    fn serialize_align(&mut self) -> Result<(), Error>;
+
+    /// Byte aligned block of `bytes.len()` bytes.
+    fn serialize_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error>;
+
+    /// Length prefixed UTF-8 string of at most `max_length` bytes.
+    fn serialize_string(&mut self, value: &mut String, max_length: usize) -> Result<(), Error>;
+
+    fn serialize_byte(&mut self, value: &mut u8) -> Result<(), Error> {
+        annotated(self, "byte", |stream| {
+            let mut temp = *value as u32;
+            stream.serialize_bits(&mut temp, 8)?;
+            *value = temp as u8;
+            Ok(())
+        })
+    }
+
+    fn serialize_bool(&mut self, value: &mut bool) -> Result<(), Error> {
+        annotated(self, "bool", |stream| {
+            let mut temp = *value as u32;
+            stream.serialize_bits(&mut temp, 1)?;
+            *value = temp != 0;
+            Ok(())
+        })
+    }
+
+    fn serialize_int_range(&mut self, value: &mut i32, min: i32, max: i32) -> Result<(), Error> {
+        annotated(self, "int range", |stream| {
+            if min > max {
+                return Err(Error::InvalidArgument);
+            }
+            let bits = range_bits(min, max);
+            let mut temp = 0;
+            if stream.is_writing() {
+                if *value < min || *value > max {
+                    return Err(Error::ValueOutOfBounds);
+                }
+                temp = (*value as i64 - min as i64) as u32;
+            }
+            stream.serialize_bits(&mut temp, bits)?;
+            if stream.is_reading() {
+                let result = min as i64 + temp as i64;
+                if result > max as i64 {
+                    return Err(Error::ValueOutOfBounds);
+                }
+                *value = result as i32;
+            }
+            Ok(())
+        })
+    }
+
+    fn serialize_float(
+        &mut self,
+        value: &mut f32,
+        min: f32,
+        max: f32,
+        resolution: f32,
+    ) -> Result<(), Error> {
+        annotated(self, "float", |stream| {
+            let steps = quantize_steps(min, max, resolution)?;
+            let bits = bits_required(steps);
+            let mut temp = 0;
+            if stream.is_writing() {
+                temp = quantize(*value, min, max, resolution)?;
+            }
+            stream.serialize_bits(&mut temp, bits)?;
+            if stream.is_reading() {
+                *value = dequantize(temp, min, max, resolution)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn serialize_vector(
+        &mut self,
+        value: &mut [f32; 3],
+        min: f32,
+        max: f32,
+        resolution: f32,
+    ) -> Result<(), Error> {
+        annotated(self, "vector", |stream| {
+            for component in value.iter_mut() {
+                stream.serialize_float(component, min, max, resolution)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn serialize_quaternion(&mut self, value: &mut [f32; 4], bits: i32) -> Result<(), Error> {
+        annotated(self, "quaternion", |stream| {
+            let mut encoded = [0u32; 4];
+            if stream.is_writing() {
+                encoded = encode_quaternion(value, bits)?;
+            }
+            stream.serialize_bits(&mut encoded[0], 2)?;
+            for component in encoded[1..].iter_mut() {
+                stream.serialize_bits(component, bits)?;
+            }
+            if stream.is_reading() {
+                *value = decode_quaternion(&encoded, bits)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn serialize_varint(&mut self, value: &mut u64) -> Result<(), Error> {
+        annotated(self, "varint", |stream| {
+            if stream.is_writing() {
+                let mut remaining = *value;
+                loop {
+                    let mut group = (remaining & VARINT_GROUP_MASK) as u32;
+                    remaining >>= VARINT_GROUP_BITS;
+                    let mut more = remaining != 0;
+                    stream.serialize_bool(&mut more)?;
+                    stream.serialize_bits(&mut group, VARINT_GROUP_BITS)?;
+                    if !more {
+                        return Ok(());
+                    }
+                }
+            }
+
+            let mut result = 0u64;
+            let mut shift = 0;
+            loop {
+                let mut more = false;
+                let mut group = 0;
+                stream.serialize_bool(&mut more)?;
+                stream.serialize_bits(&mut group, VARINT_GROUP_BITS)?;
+                if shift >= 64 || (group as u64) << shift >> shift != group as u64 {
+                    return Err(Error::ValueOutOfBounds);
+                }
+                result |= (group as u64) << shift;
+                shift += VARINT_GROUP_BITS;
+                if !more {
+                    *value = result;
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    fn serialize_relative(&mut self, value: &mut i32, base: i32) -> Result<(), Error> {
+        annotated(self, "relative", |stream| {
+            let mut tier = 0;
+            let mut temp = 0;
+            if stream.is_writing() {
+                let delta = zigzag_encode(*value as i64 - base as i64);
+                tier = relative_tier(delta);
+                temp = match RELATIVE_TIERS.get(tier) {
+                    Some((offset, _)) => (delta - offset) as u32,
+                    None => *value as u32,
+                };
+            }
+
+            // tier prefix: 0, 10, 110, 111
+            let mut prefix = 0;
+            while prefix < RELATIVE_TIERS.len() {
+                let mut next = tier > prefix;
+                stream.serialize_bool(&mut next)?;
+                if !next {
+                    break;
+                }
+                prefix += 1;
+            }
+            tier = prefix;
+
+            let bits = RELATIVE_TIERS.get(tier).map_or(32, |(_, bits)| *bits);
+            stream.serialize_bits(&mut temp, bits)?;
+            if stream.is_reading() {
+                *value = match RELATIVE_TIERS.get(tier) {
+                    Some((offset, _)) => (base as i64 + zigzag_decode(temp as u64 + offset)) as i32,
+                    None => temp as i32,
+                };
+            }
+            Ok(())
+        })
+    }
+
+    fn serialize_u64(&mut self, value: &mut u64) -> Result<(), Error> {
+        annotated(self, "u64", |stream| {
+            let mut high = (*value >> 32) as u32;
+            let mut low = *value as u32;
+            stream.serialize_bits(&mut high, 32)?;
+            stream.serialize_bits(&mut low, 32)?;
+            *value = (high as u64) << 32 | low as u64;
+            Ok(())
+        })
+    }
+
+    fn serialize_i32(&mut self, value: &mut i32) -> Result<(), Error> {
+        annotated(self, "i32", |stream| {
+            let mut temp = zigzag_encode(*value as i64) as u32;
+            stream.serialize_bits(&mut temp, 32)?;
+            *value = zigzag_decode(temp as u64) as i32;
+            Ok(())
+        })
+    }
+
+    fn serialize_i64(&mut self, value: &mut i64) -> Result<(), Error> {
+        annotated(self, "i64", |stream| {
+            let mut temp = zigzag_encode(*value);
+            stream.serialize_u64(&mut temp)?;
+            *value = zigzag_decode(temp);
+            Ok(())
+        })
+    }
+}
+
+/// Runs `f` on `stream` and annotates its error with the position it started at.
+fn annotated<S, T, F>(stream: &mut S, what: &'static str, f: F) -> Result<T, Error>
+where
+    S: Stream + ?Sized,
+    F: FnOnce(&mut S) -> Result<T, Error>,
+{
+    let offset = stream.position();
+    f(stream).map_err(|error| stream.annotate(offset, what, error))
+}
+
+/// Type that can be written to, read from and measured with any `Stream`.
+pub trait NetSerialize {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error>;
+}
+
+/// Returns the number of bits `value` takes when serialized.
+pub fn measure<T: NetSerialize>(value: &mut T) -> Result<i64, Error> {
+    let mut counter = BitCounter::new();
+    value.serialize(&mut counter)?;
+    Ok(counter.written_bits())
+}
+
+/// Number of bits needed to store any value of the inclusive range `[min, max]`.
+pub fn range_bits(min: i32, max: i32) -> i32 {
+    bits_required((max as i64 - min as i64) as u32)
+}
+
+/// Number of bits needed to store any value of `[0, max]`.
+pub fn bits_required(max: u32) -> i32 {
+    32 - max.leading_zeros() as i32
+}
+
+fn bits_required_u64(max: u64) -> i32 {
+    64 - max.leading_zeros() as i32
+}
+
+const VARINT_GROUP_BITS: i32 = 7;
+const VARINT_GROUP_MASK: u64 = (1 << VARINT_GROUP_BITS) - 1;
+
+// (first zigzag delta, payload bits) of each relative encoding tier. Deltas
+// past the last tier are sent as the full 32 bit value.
+const RELATIVE_TIERS: [(u64, i32); 3] = [(0, 4), (16, 8), (16 + 256, 16)];
+
+fn relative_tier(delta: u64) -> usize {
+    RELATIVE_TIERS
+        .iter()
+        .position(|(offset, bits)| delta < offset + (1 << bits))
+        .unwrap_or(RELATIVE_TIERS.len())
+}
+
+/// Maps signed values to unsigned ones so that small magnitudes stay small:
+/// 0, -1, 1, -2, 2... become 0, 1, 2, 3, 4...
+pub fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn zigzag_decode(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Number of `resolution` sized steps in `[min, max]`.
+pub fn quantize_steps(min: f32, max: f32, resolution: f32) -> Result<u32, Error> {
+    if !min.is_finite()
+        || !max.is_finite()
+        || min >= max
+        || resolution.is_nan()
+        || resolution <= 0.0
+    {
+        return Err(Error::InvalidArgument);
+    }
+    let steps = ((max as f64 - min as f64) / resolution as f64).ceil();
+    if steps > u32::MAX as f64 {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(steps as u32)
+}
+
+pub fn quantize(value: f32, min: f32, max: f32, resolution: f32) -> Result<u32, Error> {
+    let steps = quantize_steps(min, max, resolution)?;
+    if !(value >= min && value <= max) {
+        return Err(Error::ValueOutOfBounds);
+    }
+    let step = ((value as f64 - min as f64) / resolution as f64).round();
+    Ok((step as u32).min(steps))
+}
+
+pub fn dequantize(value: u32, min: f32, max: f32, resolution: f32) -> Result<f32, Error> {
+    let steps = quantize_steps(min, max, resolution)?;
+    if value > steps {
+        return Err(Error::ValueOutOfBounds);
+    }
+    let result = min as f64 + value as f64 * resolution as f64;
+    Ok(result.min(max as f64) as f32)
+}
+
+// the three smallest components of a unit quaternion are within this range.
+const QUATERNION_COMPONENT_MAX: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// "Smallest three" quaternion compression: index of the largest component
+/// followed by the remaining three quantized to `bits` each. The largest one
+/// is reconstructed on read from the unit length constraint.
+pub fn encode_quaternion(value: &[f32; 4], bits: i32) -> Result<[u32; 4], Error> {
+    if !(2..=31).contains(&bits) {
+        return Err(Error::InvalidArgument);
+    }
+    if value.iter().any(|v| !v.is_finite()) {
+        return Err(Error::ValueOutOfBounds);
+    }
+
+    let mut largest = 0;
+    for i in 1..4 {
+        if value[i].abs() > value[largest].abs() {
+            largest = i;
+        }
+    }
+    // q and -q describe the same rotation, flip so that the dropped value is positive.
+    let sign = if value[largest] < 0.0 { -1.0 } else { 1.0 };
+
+    let max_value = ((1u64 << bits) - 1) as f64;
+    let mut result = [largest as u32, 0, 0, 0];
+    let mut out = 1;
+    for (i, component) in value.iter().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let normalized = (sign * *component as f64 / QUATERNION_COMPONENT_MAX).clamp(-1.0, 1.0);
+        result[out] = ((normalized + 1.0) * 0.5 * max_value).round() as u32;
+        out += 1;
+    }
+    Ok(result)
+}
+
+pub fn decode_quaternion(value: &[u32; 4], bits: i32) -> Result<[f32; 4], Error> {
+    if !(2..=31).contains(&bits) {
+        return Err(Error::InvalidArgument);
+    }
+    let max_value = ((1u64 << bits) - 1) as f64;
+    let largest = value[0] as usize;
+    if largest > 3 || value[1..].iter().any(|v| *v as f64 > max_value) {
+        return Err(Error::ValueOutOfBounds);
+    }
+
+    let mut result = [0f32; 4];
+    let mut sum = 0.0;
+    let mut read = 1;
+    for (i, component) in result.iter_mut().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let decoded = (value[read] as f64 / max_value * 2.0 - 1.0) * QUATERNION_COMPONENT_MAX;
+        sum += decoded * decoded;
+        *component = decoded as f32;
+        read += 1;
+    }
+    result[largest] = (1.0 - sum).max(0.0).sqrt() as f32;
+    Ok(result)
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Error {
     OutOfMemory,
     InvalidArgument,
     ValueOutOfBounds,
+    InvalidString,
+    /// A datagram of an encrypted connection could not be opened.
+    Cipher(CipherError),
+    /// `BitReader` failure: `cause` happened at bit `offset` while reading `what`.
+    Read {
+        offset: i64,
+        what: &'static str,
+        cause: Box<Error>,
+    },
+}
+
+impl Error {
+    /// The error without the read position.
+    pub fn cause(&self) -> &Error {
+        match self {
+            Error::Read { cause, .. } => cause.cause(),
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OutOfMemory => write!(f, "out of memory"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::ValueOutOfBounds => write!(f, "value out of bounds"),
+            Error::InvalidString => write!(f, "invalid utf-8 string"),
+            Error::Cipher(error) => write!(f, "{}", error),
+            Error::Read {
+                offset,
+                what,
+                cause,
+            } => write!(f, "{} reading {} at bit {}", cause, what, offset),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<CipherError> for Error {
+    fn from(error: CipherError) -> Error {
+        Error::Cipher(error)
+    }
+}
+
+enum Dest<'a> {
+    Borrowed {
+        bytes: &'a mut [u8],
+        position: usize,
+    },
+    Owned {
+        bytes: Vec<u8>,
+        max_bytes: Option<usize>,
+    },
+}
+
+impl<'a> Dest<'a> {
+    fn written(&self) -> usize {
+        match self {
+            Dest::Borrowed { position, .. } => *position,
+            Dest::Owned { bytes, .. } => bytes.len(),
+        }
+    }
+
+    fn available(&self) -> i64 {
+        // owned buffers without a cap report "plenty" and leave room for bit math.
+        const UNBOUNDED: i64 = i64::MAX / 16;
+        match self {
+            Dest::Borrowed { bytes, position } => (bytes.len() - position) as i64,
+            Dest::Owned {
+                bytes,
+                max_bytes: Some(max),
+            } => max.saturating_sub(bytes.len()) as i64,
+            Dest::Owned {
+                max_bytes: None, ..
+            } => UNBOUNDED,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        match self {
+            Dest::Borrowed { bytes, position } => {
+                bytes[*position..*position + data.len()].copy_from_slice(data);
+                *position += data.len();
+            }
+            Dest::Owned { bytes, .. } => bytes.extend_from_slice(data),
+        }
+    }
 }
 
 pub struct BitWriter<'a> {
-    dest: &'a mut [u8],
+    dest: Dest<'a>,
     scratch: u64,
     scratch_bits: i32,
-    capacity: i64,
 }
 
 impl<'a> BitWriter<'a> {
-    pub fn new(dest: &'a mut [u8]) -> BitWriter {
-        let capacity = dest.len() as i64;
+    pub fn new(dest: &'a mut [u8]) -> BitWriter<'a> {
+        BitWriter {
+            dest: Dest::Borrowed {
+                bytes: dest,
+                position: 0,
+            },
+            scratch: 0,
+            scratch_bits: 0,
+        }
+    }
+
+    /// Creates a writer that owns its buffer and grows it as needed, up to
+    /// `max_bytes` if given. Use `into_bytes` to take the result.
+    pub fn growable(max_bytes: Option<usize>) -> BitWriter<'static> {
         BitWriter {
-            dest,
+            dest: Dest::Owned {
+                bytes: Vec::new(),
+                max_bytes,
+            },
             scratch: 0,
             scratch_bits: 0,
-            capacity,
+        }
+    }
+
+    /// Flushes the writer and returns the written bytes.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.flush();
+        match self.dest {
+            Dest::Borrowed { bytes, position } => bytes[..position].to_vec(),
+            Dest::Owned { bytes, .. } => bytes,
         }
     }
 
@@ -32,6 +534,106 @@ impl<'a> BitWriter<'a> {
         self.write_bits(value as u32, 8)
     }
 
+    pub fn write_bool(&mut self, value: bool) -> Result<(), Error> {
+        self.write_bits(value as u32, 1)
+    }
+
+    pub fn write_int_range(&mut self, value: i32, min: i32, max: i32) -> Result<(), Error> {
+        if min > max {
+            return Err(Error::InvalidArgument);
+        }
+        if value < min || value > max {
+            return Err(Error::ValueOutOfBounds);
+        }
+        let bits = range_bits(min, max);
+        self.write_bits((value as i64 - min as i64) as u32, bits)
+    }
+
+    pub fn write_float(
+        &mut self,
+        value: f32,
+        min: f32,
+        max: f32,
+        resolution: f32,
+    ) -> Result<(), Error> {
+        let steps = quantize_steps(min, max, resolution)?;
+        let quantized = quantize(value, min, max, resolution)?;
+        self.write_bits(quantized, bits_required(steps))
+    }
+
+    pub fn write_vector(
+        &mut self,
+        value: &[f32; 3],
+        min: f32,
+        max: f32,
+        resolution: f32,
+    ) -> Result<(), Error> {
+        let steps = quantize_steps(min, max, resolution)?;
+        let bits = bits_required(steps);
+        let mut quantized = [0u32; 3];
+        for (result, component) in quantized.iter_mut().zip(value.iter()) {
+            *result = quantize(*component, min, max, resolution)?;
+        }
+        if self.available_bits() < 3 * bits as i64 {
+            return Err(Error::OutOfMemory);
+        }
+        for component in quantized.iter() {
+            self.write_bits(*component, bits)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_quaternion(&mut self, value: &[f32; 4], bits: i32) -> Result<(), Error> {
+        let encoded = encode_quaternion(value, bits)?;
+        if self.available_bits() < 2 + 3 * bits as i64 {
+            return Err(Error::OutOfMemory);
+        }
+        self.write_bits(encoded[0], 2)?;
+        for component in encoded[1..].iter() {
+            self.write_bits(*component, bits)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `value` in 7 bit groups, each preceded by a continuation bit.
+    pub fn write_varint(&mut self, value: u64) -> Result<(), Error> {
+        let groups = (bits_required_u64(value).max(1) + VARINT_GROUP_BITS - 1) / VARINT_GROUP_BITS;
+        if self.available_bits() < (groups * (VARINT_GROUP_BITS + 1)) as i64 {
+            return Err(Error::OutOfMemory);
+        }
+        let mut value = value;
+        self.serialize_varint(&mut value)
+    }
+
+    /// Writes `value` as a difference to `base`, a value known to the reader.
+    /// Values close to `base` take as little as 5 bits, far ones take 35.
+    pub fn write_relative(&mut self, value: i32, base: i32) -> Result<(), Error> {
+        let tier = relative_tier(zigzag_encode(value as i64 - base as i64));
+        let prefix_bits = (tier + 1).min(RELATIVE_TIERS.len()) as i64;
+        let bits = RELATIVE_TIERS.get(tier).map_or(32, |(_, bits)| *bits);
+        if self.available_bits() < prefix_bits + bits as i64 {
+            return Err(Error::OutOfMemory);
+        }
+        let mut value = value;
+        self.serialize_relative(&mut value, base)
+    }
+
+    pub fn write_i32(&mut self, value: i32) -> Result<(), Error> {
+        self.write_bits(zigzag_encode(value as i64) as u32, 32)
+    }
+
+    pub fn write_i64(&mut self, value: i64) -> Result<(), Error> {
+        self.write_u64(zigzag_encode(value))
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> Result<(), Error> {
+        if self.available_bits() < 64 {
+            return Err(Error::OutOfMemory);
+        }
+        self.write_bits((value >> 32) as u32, 32)?;
+        self.write_bits(value as u32, 32)
+    }
+
     pub fn write_bits(&mut self, value: u32, bits: i32) -> Result<(), Error> {
         if bits > 32 {
             return Err(Error::InvalidArgument);
@@ -47,101 +649,424 @@ impl<'a> BitWriter<'a> {
         self.scratch_bits += bits;
 
         if self.scratch_bits > 32 {
-            const BYTE_BITS: i32 = 8;
-            let byte_count = self.scratch_bits / BYTE_BITS;
+            self.write_scratch_bytes();
+        }
+
+        Ok(())
+    }
 
-            for _ in 0..byte_count {
-                let offset = self.scratch_bits - BYTE_BITS;
-                let byte = (self.scratch >> offset) as u8;
-                self.dest.write(slice::from_ref(&byte)).unwrap();
+    /// Pads the stream with zero bits up to the next byte boundary.
+    pub fn align(&mut self) -> Result<(), Error> {
+        let padding = self.align_bits();
+        self.write_bits(0, padding)
+    }
 
-                self.scratch &= (1 << offset) - 1;
-                self.scratch_bits -= BYTE_BITS;
-            }
+    /// Aligns the stream and writes `bytes` as they are. Once aligned the
+    /// bytes are copied directly instead of going through the scratch.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.available_bits() < self.align_bits() as i64 + bytes.len() as i64 * 8 {
+            return Err(Error::OutOfMemory);
         }
-
+        self.align()?;
+        self.write_scratch_bytes();
+        self.dest.write(bytes);
         Ok(())
     }
 
+    /// Writes the length of `value` in `[0, max_length]` followed by its UTF-8 bytes.
+    pub fn write_string(&mut self, value: &str, max_length: usize) -> Result<(), Error> {
+        let max_length = max_length.min(i32::MAX as usize) as i32;
+        if value.len() > max_length as usize {
+            return Err(Error::ValueOutOfBounds);
+        }
+        let length_bits = range_bits(0, max_length) as i64;
+        let padding = (8 - (self.written_bits() + length_bits) % 8) % 8;
+        if self.available_bits() < length_bits + padding + value.len() as i64 * 8 {
+            return Err(Error::OutOfMemory);
+        }
+        self.write_int_range(value.len() as i32, 0, max_length)?;
+        self.write_bytes(value.as_bytes())
+    }
+
     pub fn flush(&mut self) {
+        const BYTE_BITS: i32 = 8;
+        self.write_scratch_bytes();
+
+        // remaining bits
+        if self.scratch_bits > 0 {
+            let offset = BYTE_BITS - self.scratch_bits;
+            let byte = (self.scratch << offset) as u8;
+            self.dest.write(slice::from_ref(&byte));
+        }
+
+        self.scratch = 0;
+        self.scratch_bits = 0;
+    }
+
+    pub fn written_bytes(&self) -> i64 {
+        self.dest.written() as i64 + (self.scratch_bits / 8) as i64
+    }
+
+    pub fn written_bits(&self) -> i64 {
+        self.dest.written() as i64 * 8 + self.scratch_bits as i64
+    }
+
+    fn available_bits(&self) -> i64 {
+        (self.dest.available() * 8) - self.scratch_bits as i64
+    }
+
+    fn align_bits(&self) -> i32 {
+        (8 - self.scratch_bits % 8) % 8
+    }
+
+    fn write_scratch_bytes(&mut self) {
         const BYTE_BITS: i32 = 8;
         let byte_count = self.scratch_bits / BYTE_BITS;
 
         for _ in 0..byte_count {
             let offset = self.scratch_bits - BYTE_BITS;
             let byte = (self.scratch >> offset) as u8;
-            self.dest.write(slice::from_ref(&byte)).unwrap();
+            self.dest.write(slice::from_ref(&byte));
 
             self.scratch &= (1 << offset) - 1;
             self.scratch_bits -= BYTE_BITS;
         }
+    }
+}
 
-        // remaining bits
-        let offset = BYTE_BITS - self.scratch_bits;
-        let byte = (self.scratch << offset) as u8;
-        self.dest.write(slice::from_ref(&byte)).unwrap();
+impl<'a> Stream for BitWriter<'a> {
+    fn is_writing(&self) -> bool {
+        true
+    }
 
-        self.scratch &= (1 << offset) - 1;
-        self.scratch_bits = 0;
+    fn position(&self) -> i64 {
+        self.written_bits()
     }
 
-    pub fn written_bytes(&self) -> i64 {
-        (self.capacity - self.dest.len() as i64) + (self.scratch_bits / 8) as i64
+    fn serialize_bits(&mut self, value: &mut u32, bits: i32) -> Result<(), Error> {
+        self.write_bits(*value, bits)
     }
 
-    pub fn written_bits(&self) -> i64 {
-        (self.capacity - self.dest.len() as i64) * 8 + self.scratch_bits as i64
+    fn serialize_align(&mut self) -> Result<(), Error> {
+        self.align()
+    }
+
+    fn serialize_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        self.write_bytes(bytes)
+    }
+
+    fn serialize_string(&mut self, value: &mut String, max_length: usize) -> Result<(), Error> {
+        self.write_string(value, max_length)
+    }
+}
+
+#[derive(Clone)]
+pub struct BitReader<'a> {
+    source: &'a [u8],
+    scratch_bits: i32,
+    scratch: u64,
+    size_bits: i64,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(source: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            source,
+            scratch_bits: 0,
+            scratch: 0,
+            size_bits: source.len() as i64 * 8,
+        }
+    }
+
+    pub fn bits_read(&self) -> i64 {
+        self.size_bits - self.available_bits()
+    }
+
+    pub fn bits_remaining(&self) -> i64 {
+        self.available_bits()
+    }
+
+    /// Reads `bits` without consuming them.
+    pub fn peek_bits(&self, bits: i32) -> Result<u32, Error> {
+        self.clone().read_bits(bits)
+    }
+
+    pub fn skip_bits(&mut self, bits: i64) -> Result<(), Error> {
+        let offset = self.bits_read();
+        if bits < 0 {
+            return Err(self.annotate(offset, "skip", Error::InvalidArgument));
+        }
+        if self.available_bits() < bits {
+            return Err(self.annotate(offset, "skip", Error::OutOfMemory));
+        }
+
+        let mut remaining = bits;
+        let unaligned = (self.scratch_bits as i64 % 8).min(remaining);
+        self.read_bits(unaligned as i32)?;
+        remaining -= unaligned;
+
+        while self.scratch_bits > 0 && remaining >= 8 {
+            self.read_bits(8)?;
+            remaining -= 8;
+        }
+        let whole_bytes = (remaining / 8) as usize;
+        self.source = &self.source[whole_bytes..];
+        self.read_bits((remaining % 8) as i32)?;
+        Ok(())
+    }
+
+    /// Skips the padding up to the next byte boundary. Padding has to be zero.
+    pub fn align(&mut self) -> Result<(), Error> {
+        annotated(self, "align", |reader| {
+            let padding = reader.scratch_bits % 8;
+            if reader.read_bits(padding)? != 0 {
+                return Err(Error::ValueOutOfBounds);
+            }
+            Ok(())
+        })
+    }
+
+    /// Aligns the stream and fills `bytes` from it.
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        annotated(self, "bytes", |reader| {
+            let padding = reader.scratch_bits % 8;
+            if bytes.len() as i64 > (reader.available_bits() - padding as i64) / 8 {
+                return Err(Error::OutOfMemory);
+            }
+            reader.align()?;
+
+            let mut index = 0;
+            while reader.scratch_bits > 0 && index < bytes.len() {
+                bytes[index] = reader.read_bits(8)? as u8;
+                index += 1;
+            }
+            reader.source.read_exact(&mut bytes[index..]).unwrap();
+            Ok(())
+        })
+    }
+
+    /// Reads a string written with `BitWriter::write_string`. The declared
+    /// length is checked against the remaining data before allocating.
+    pub fn read_string(&mut self, max_length: usize) -> Result<String, Error> {
+        annotated(self, "string", |reader| {
+            let max_length = max_length.min(i32::MAX as usize) as i32;
+            let length = reader.read_int_range(0, max_length)? as usize;
+            reader.align()?;
+            if length as i64 > reader.available_bits() / 8 {
+                return Err(Error::OutOfMemory);
+            }
+
+            let mut bytes = vec![0u8; length];
+            reader.read_bytes(&mut bytes)?;
+            String::from_utf8(bytes).map_err(|_| Error::InvalidString)
+        })
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut value = 0;
+        self.serialize_byte(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        let mut value = false;
+        self.serialize_bool(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn read_int_range(&mut self, min: i32, max: i32) -> Result<i32, Error> {
+        let mut value = 0;
+        self.serialize_int_range(&mut value, min, max)?;
+        Ok(value)
+    }
+
+    pub fn read_float(&mut self, min: f32, max: f32, resolution: f32) -> Result<f32, Error> {
+        let mut value = 0.0;
+        self.serialize_float(&mut value, min, max, resolution)?;
+        Ok(value)
+    }
+
+    pub fn read_vector(&mut self, min: f32, max: f32, resolution: f32) -> Result<[f32; 3], Error> {
+        let mut value = [0f32; 3];
+        self.serialize_vector(&mut value, min, max, resolution)?;
+        Ok(value)
+    }
+
+    pub fn read_quaternion(&mut self, bits: i32) -> Result<[f32; 4], Error> {
+        let mut value = [0f32; 4];
+        self.serialize_quaternion(&mut value, bits)?;
+        Ok(value)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0;
+        self.serialize_varint(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn read_relative(&mut self, base: i32) -> Result<i32, Error> {
+        let mut value = 0;
+        self.serialize_relative(&mut value, base)?;
+        Ok(value)
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, Error> {
+        let mut value = 0;
+        self.serialize_i32(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, Error> {
+        let mut value = 0;
+        self.serialize_i64(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        if self.available_bits() < 64 {
+            return Err(self.annotate(self.bits_read(), "u64", Error::OutOfMemory));
+        }
+        let mut value = 0;
+        self.serialize_u64(&mut value)?;
+        Ok(value)
+    }
+
+    pub fn read_bits(&mut self, bits: i32) -> Result<u32, Error> {
+        annotated(self, "bits", |reader| {
+            if !(0..=32).contains(&bits) {
+                return Err(Error::InvalidArgument);
+            }
+
+            if reader.available_bits() < bits as i64 {
+                return Err(Error::OutOfMemory);
+            }
+
+            while reader.scratch_bits < bits {
+                let mut byte: u8 = 0;
+                reader
+                    .source
+                    .read_exact(slice::from_mut(&mut byte))
+                    .unwrap();
+                reader.scratch = reader.scratch << 8 | byte as u64;
+                reader.scratch_bits += 8;
+            }
+
+            let value = reader.scratch >> (reader.scratch_bits - bits);
+            reader.scratch &= (1 << (reader.scratch_bits - bits)) - 1;
+            reader.scratch_bits -= bits;
+            Ok(value as u32)
+        })
+    }
+
+    fn available_bits(&self) -> i64 {
+        self.source.len() as i64 * 8 + self.scratch_bits as i64
+    }
+}
+
+impl<'a> Stream for BitReader<'a> {
+    fn is_writing(&self) -> bool {
+        false
+    }
+
+    fn position(&self) -> i64 {
+        self.bits_read()
+    }
+
+    fn annotate(&self, offset: i64, what: &'static str, error: Error) -> Error {
+        match error {
+            // keep the innermost offset, it points at the exact failing read.
+            Error::Read { offset, cause, .. } => Error::Read {
+                offset,
+                what,
+                cause,
+            },
+            cause => Error::Read {
+                offset,
+                what,
+                cause: Box::new(cause),
+            },
+        }
+    }
+
+    fn serialize_bits(&mut self, value: &mut u32, bits: i32) -> Result<(), Error> {
+        *value = self.read_bits(bits)?;
+        Ok(())
+    }
+
+    fn serialize_align(&mut self) -> Result<(), Error> {
+        self.align()
+    }
+
+    fn serialize_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        self.read_bytes(bytes)
+    }
+
+    fn serialize_string(&mut self, value: &mut String, max_length: usize) -> Result<(), Error> {
+        *value = self.read_string(max_length)?;
+        Ok(())
+    }
+}
+
+/// Writing stream that only counts bits. Used to size buffers before writing.
+#[derive(Default)]
+pub struct BitCounter {
+    bits: i64,
+}
+
+impl BitCounter {
+    pub fn new() -> BitCounter {
+        BitCounter { bits: 0 }
+    }
+
+    pub fn written_bytes(&self) -> i64 {
+        (self.bits + 7) / 8
     }
 
-    fn available_bits(&self) -> i64 {
-        (self.dest.len() as i64 * 8) - self.scratch_bits as i64
+    pub fn written_bits(&self) -> i64 {
+        self.bits
     }
 }
 
-pub struct BitReader<'a> {
-    source: &'a [u8],
-    scratch_bits: i32,
-    scratch: u64,
-}
-
-impl<'a> BitReader<'a> {
-    pub fn new(source: &[u8]) -> BitReader {
-        BitReader {
-            source,
-            scratch_bits: 0,
-            scratch: 0,
-        }
+impl Stream for BitCounter {
+    fn is_writing(&self) -> bool {
+        true
     }
 
-    pub fn read_byte(&mut self) -> Result<u8, Error> {
-        let value = self.read_bits(8)?;
-        Ok(value as u8)
+    fn position(&self) -> i64 {
+        self.bits
     }
 
-    pub fn read_bits(&mut self, bits: i32) -> Result<u32, Error> {
+    fn serialize_bits(&mut self, value: &mut u32, bits: i32) -> Result<(), Error> {
         if bits > 32 {
             return Err(Error::InvalidArgument);
         }
-
-        if self.available_bits() < bits {
-            return Err(Error::OutOfMemory);
+        if *value > ((1u64 << bits) - 1) as u32 {
+            return Err(Error::ValueOutOfBounds);
         }
+        self.bits += bits as i64;
+        Ok(())
+    }
 
-        while self.scratch_bits < bits {
-            let mut byte: u8 = 0;
-            self.source.read(slice::from_mut(&mut byte)).unwrap();
-            self.scratch = self.scratch << 8 | byte as u64;
-            self.scratch_bits += 8;
-        }
+    fn serialize_align(&mut self) -> Result<(), Error> {
+        self.bits = self.written_bytes() * 8;
+        Ok(())
+    }
 
-        let value = self.scratch >> (self.scratch_bits - bits);
-        self.scratch &= (1 << (self.scratch_bits - bits)) - 1;
-        self.scratch_bits -= bits;
-        Ok(value as u32)
+    fn serialize_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        self.serialize_align()?;
+        self.bits += bytes.len() as i64 * 8;
+        Ok(())
     }
 
-    fn available_bits(&self) -> i32 {
-        self.source.len() as i32 * 8 + self.scratch_bits
+    fn serialize_string(&mut self, value: &mut String, max_length: usize) -> Result<(), Error> {
+        let max_length = max_length.min(i32::MAX as usize) as i32;
+        if value.len() > max_length as usize {
+            return Err(Error::ValueOutOfBounds);
+        }
+        self.bits += range_bits(0, max_length) as i64;
+        self.serialize_align()?;
+        self.bits += value.len() as i64 * 8;
+        Ok(())
     }
 }
 
@@ -150,7 +1075,53 @@ mod tests {
     use rand::{Rng, SeedableRng};
     use rand_chacha::{ChaCha8Rng, ChaChaRng};
 
-    use super::{BitReader, BitWriter};
+    use super::{measure, BitCounter, BitReader, BitWriter, Error, NetSerialize, Stream};
+
+    struct Sample {
+        values: Vec<(i32, u32)>,
+    }
+
+    impl Sample {
+        fn empty_like(other: &Sample) -> Sample {
+            Sample {
+                values: other.values.iter().map(|(bits, _)| (*bits, 0)).collect(),
+            }
+        }
+    }
+
+    impl NetSerialize for Sample {
+        fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+            for (bits, value) in self.values.iter_mut() {
+                stream.serialize_bits(value, *bits)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn read_error(offset: i64, what: &'static str, cause: Error) -> Error {
+        Error::Read {
+            offset,
+            what,
+            cause: Box::new(cause),
+        }
+    }
+
+    fn stream_round_trip(mut expected: Sample, bytes: &mut [u8]) {
+        let measured = measure(&mut expected).unwrap();
+        {
+            let mut writer = BitWriter::new(bytes);
+            assert!(writer.is_writing());
+            assert!(expected.serialize(&mut writer).is_ok());
+            assert_eq!(writer.written_bits(), measured);
+            writer.flush();
+        }
+
+        let mut reader = BitReader::new(bytes);
+        assert!(reader.is_reading());
+        let mut result = Sample::empty_like(&expected);
+        assert!(result.serialize(&mut reader).is_ok());
+        assert_eq!(result.values, expected.values);
+    }
 
     #[test]
     fn byte() {
@@ -198,6 +1169,701 @@ mod tests {
         let res = reader.read_bits(bits);
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), expected);
+
+        let sample = Sample {
+            values: vec![(bits, expected), (bits, expected)],
+        };
+        stream_round_trip(sample, &mut bytes);
+    }
+
+    #[test]
+    fn counter() {
+        let mut counter = BitCounter::new();
+        assert_eq!(counter.written_bits(), 0);
+        assert_eq!(counter.written_bytes(), 0);
+
+        assert!(counter.serialize_bits(&mut 1, 1).is_ok());
+        assert_eq!(counter.written_bits(), 1);
+        assert_eq!(counter.written_bytes(), 1);
+
+        assert!(counter.serialize_byte(&mut 0xff).is_ok());
+        assert_eq!(counter.written_bits(), 9);
+        assert_eq!(counter.written_bytes(), 2);
+
+        assert!(counter.serialize_bits(&mut 4, 2).is_err());
+        assert!(counter.serialize_bits(&mut 0, 33).is_err());
+        assert_eq!(counter.written_bits(), 9);
+    }
+
+    #[test]
+    fn primitives() {
+        let mut bytes = [0u8; 64];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_bool(true).is_ok());
+        assert!(writer.write_bool(false).is_ok());
+        assert!(writer.write_int_range(-100, -100, 100).is_ok());
+        assert!(writer.write_int_range(100, -100, 100).is_ok());
+        assert!(writer.write_int_range(i32::MIN, i32::MIN, i32::MAX).is_ok());
+        assert!(writer.write_int_range(7, 7, 7).is_ok());
+        assert!(writer.write_i32(-1).is_ok());
+        assert!(writer.write_i32(i32::MIN).is_ok());
+        assert!(writer.write_i64(i64::MIN).is_ok());
+        assert!(writer.write_i64(i64::MAX).is_ok());
+        assert!(writer.write_u64(u64::MAX).is_ok());
+        assert!(writer.write_u64(0x0123_4567_89ab_cdef).is_ok());
+        assert_eq!(writer.written_bits(), 2 + 8 + 8 + 32 + 32 + 32 + 4 * 64);
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        assert!(reader.read_bool().unwrap());
+        assert!(!reader.read_bool().unwrap());
+        assert_eq!(reader.read_int_range(-100, 100).unwrap(), -100);
+        assert_eq!(reader.read_int_range(-100, 100).unwrap(), 100);
+        assert_eq!(reader.read_int_range(i32::MIN, i32::MAX).unwrap(), i32::MIN);
+        assert_eq!(reader.read_int_range(7, 7).unwrap(), 7);
+        assert_eq!(reader.read_i32().unwrap(), -1);
+        assert_eq!(reader.read_i32().unwrap(), i32::MIN);
+        assert_eq!(reader.read_i64().unwrap(), i64::MIN);
+        assert_eq!(reader.read_i64().unwrap(), i64::MAX);
+        assert_eq!(reader.read_u64().unwrap(), u64::MAX);
+        assert_eq!(reader.read_u64().unwrap(), 0x0123_4567_89ab_cdef);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut bytes = [0u8; 16];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(matches!(
+            writer.write_int_range(101, -100, 100),
+            Err(Error::ValueOutOfBounds)
+        ));
+        assert!(matches!(
+            writer.write_int_range(-101, -100, 100),
+            Err(Error::ValueOutOfBounds)
+        ));
+        assert!(matches!(
+            writer.write_int_range(0, 1, -1),
+            Err(Error::InvalidArgument)
+        ));
+        assert_eq!(writer.written_bits(), 0);
+
+        // 200 fits into the 8 bits of [-100, 100] but is outside of it.
+        assert!(writer.write_bits(255, 8).is_ok());
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(
+            reader.read_int_range(-100, 100),
+            Err(read_error(0, "int range", Error::ValueOutOfBounds))
+        );
+
+        let mut small = [0u8; 7];
+        let mut writer = BitWriter::new(&mut small);
+        assert!(matches!(writer.write_u64(0), Err(Error::OutOfMemory)));
+        assert_eq!(writer.written_bits(), 0);
+    }
+
+    #[test]
+    fn stream_primitives() {
+        struct Primitives {
+            flag: bool,
+            range: i32,
+            signed: i32,
+            wide_signed: i64,
+            wide: u64,
+        }
+
+        impl NetSerialize for Primitives {
+            fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+                stream.serialize_bool(&mut self.flag)?;
+                stream.serialize_int_range(&mut self.range, -10, 10)?;
+                stream.serialize_i32(&mut self.signed)?;
+                stream.serialize_i64(&mut self.wide_signed)?;
+                stream.serialize_u64(&mut self.wide)
+            }
+        }
+
+        let mut expected = Primitives {
+            flag: true,
+            range: -7,
+            signed: -12345,
+            wide_signed: -1 << 40,
+            wide: 1 << 63,
+        };
+        assert_eq!(measure(&mut expected).unwrap(), 1 + 5 + 32 + 64 + 64);
+
+        let mut bytes = [0u8; 32];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(expected.serialize(&mut writer).is_ok());
+        writer.flush();
+
+        let mut result = Primitives {
+            flag: false,
+            range: 0,
+            signed: 0,
+            wide_signed: 0,
+            wide: 0,
+        };
+        let mut reader = BitReader::new(&bytes);
+        assert!(result.serialize(&mut reader).is_ok());
+        assert_eq!(result.flag, expected.flag);
+        assert_eq!(result.range, expected.range);
+        assert_eq!(result.signed, expected.signed);
+        assert_eq!(result.wide_signed, expected.wide_signed);
+        assert_eq!(result.wide, expected.wide);
+
+        expected.range = 11;
+        assert!(matches!(
+            measure(&mut expected),
+            Err(Error::ValueOutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn float() {
+        let mut bytes = [0u8; 16];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_float(-10.0, -10.0, 10.0, 0.01).is_ok());
+        assert!(writer.write_float(10.0, -10.0, 10.0, 0.01).is_ok());
+        assert!(writer.write_float(1.234, -10.0, 10.0, 0.01).is_ok());
+        assert_eq!(writer.written_bits(), 3 * 11);
+        assert!(matches!(
+            writer.write_float(10.01, -10.0, 10.0, 0.01),
+            Err(Error::ValueOutOfBounds)
+        ));
+        assert!(matches!(
+            writer.write_float(f32::NAN, -10.0, 10.0, 0.01),
+            Err(Error::ValueOutOfBounds)
+        ));
+        assert!(matches!(
+            writer.write_float(0.0, 10.0, -10.0, 0.01),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            writer.write_float(0.0, -10.0, 10.0, 0.0),
+            Err(Error::InvalidArgument)
+        ));
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_float(-10.0, 10.0, 0.01).unwrap(), -10.0);
+        assert_eq!(reader.read_float(-10.0, 10.0, 0.01).unwrap(), 10.0);
+        assert!((reader.read_float(-10.0, 10.0, 0.01).unwrap() - 1.234).abs() <= 0.005);
+    }
+
+    #[test]
+    fn random_float() {
+        let mut seed: <ChaCha8Rng as SeedableRng>::Seed = Default::default();
+        rand::thread_rng().fill(&mut seed);
+
+        println!("seed: {:?}", seed);
+        let mut rng = ChaChaRng::from_seed(seed);
+
+        const COUNT: usize = 256;
+        let mut values = Vec::<(f32, f32, f32, [f32; 3])>::new();
+        for _ in 0..COUNT {
+            let min = rng.gen_range(-1000.0..1000.0);
+            let max = min + rng.gen_range(0.1..1000.0);
+            let resolution = (max - min) / rng.gen_range(1.0..65536.0);
+            let vector = [
+                rng.gen_range(min..=max),
+                rng.gen_range(min..=max),
+                rng.gen_range(min..=max),
+            ];
+            values.push((min, max, resolution, vector));
+        }
+
+        let mut bytes = [0u8; COUNT * 4 * 4];
+        let mut writer = BitWriter::new(&mut bytes);
+        for (min, max, resolution, vector) in values.iter() {
+            assert!(writer
+                .write_float(vector[0], *min, *max, *resolution)
+                .is_ok());
+            assert!(writer.write_vector(vector, *min, *max, *resolution).is_ok());
+        }
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        for (min, max, resolution, vector) in values.iter() {
+            // half a step plus rounding error of f32 at this magnitude.
+            let bound = resolution * 0.5 + max.abs().max(min.abs()) * f32::EPSILON * 4.0;
+
+            let value = reader.read_float(*min, *max, *resolution).unwrap();
+            assert!(
+                (value - vector[0]).abs() <= bound,
+                "{} vs {} in [{}, {}] by {}",
+                value,
+                vector[0],
+                min,
+                max,
+                resolution
+            );
+
+            let result = reader.read_vector(*min, *max, *resolution).unwrap();
+            for (read, expected) in result.iter().zip(vector.iter()) {
+                assert!((read - expected).abs() <= bound);
+            }
+        }
+    }
+
+    #[test]
+    fn random_quaternion() {
+        let mut seed: <ChaCha8Rng as SeedableRng>::Seed = Default::default();
+        rand::thread_rng().fill(&mut seed);
+
+        println!("seed: {:?}", seed);
+        let mut rng = ChaChaRng::from_seed(seed);
+
+        const COUNT: usize = 256;
+        const BITS: i32 = 12;
+        let mut values = Vec::<[f32; 4]>::new();
+        for _ in 0..COUNT {
+            let mut q = [0f32; 4];
+            for component in q.iter_mut() {
+                *component = rng.gen_range(-1.0..1.0);
+            }
+            let length = q.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if length < 0.001 {
+                continue;
+            }
+            q.iter_mut().for_each(|v| *v /= length);
+            values.push(q);
+        }
+        values.push([0.0, 0.0, 0.0, 1.0]);
+        values.push([0.0, -1.0, 0.0, 0.0]);
+        values.push([0.5, -0.5, 0.5, -0.5]);
+
+        let mut bytes = [0u8; COUNT * 8];
+        let mut writer = BitWriter::new(&mut bytes);
+        for q in values.iter() {
+            assert!(writer.write_quaternion(q, BITS).is_ok());
+        }
+        assert_eq!(
+            writer.written_bits(),
+            values.len() as i64 * (2 + 3 * BITS as i64)
+        );
+        writer.flush();
+
+        let step = std::f32::consts::SQRT_2 / ((1 << BITS) - 1) as f32;
+        let mut reader = BitReader::new(&bytes);
+        for q in values.iter() {
+            let result = reader.read_quaternion(BITS).unwrap();
+            // q and -q are the same rotation.
+            let dot: f32 = q.iter().zip(result.iter()).map(|(a, b)| a * b).sum();
+            let sign = dot.signum();
+            for (expected, read) in q.iter().zip(result.iter()) {
+                assert!(
+                    (expected - sign * read).abs() <= step * 2.0,
+                    "{:?} vs {:?}",
+                    q,
+                    result
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn varint() {
+        let values: [(u64, i64); 7] = [
+            (0, 8),
+            (1, 8),
+            (127, 8),
+            (128, 16),
+            (16383, 16),
+            (u32::MAX as u64, 40),
+            (u64::MAX, 80),
+        ];
+
+        let mut bytes = [0u8; 32];
+        let mut writer = BitWriter::new(&mut bytes);
+        for (value, bits) in values.iter() {
+            let before = writer.written_bits();
+            assert!(writer.write_varint(*value).is_ok());
+            assert_eq!(writer.written_bits() - before, *bits, "{}", value);
+        }
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        for (value, _) in values.iter() {
+            assert_eq!(reader.read_varint().unwrap(), *value);
+        }
+
+        let mut small = [0u8; 1];
+        let mut writer = BitWriter::new(&mut small);
+        assert!(matches!(writer.write_varint(128), Err(Error::OutOfMemory)));
+        assert_eq!(writer.written_bits(), 0);
+
+        // continuation bits past 64 bits of payload.
+        let overflow = [0xffu8; 16];
+        let mut reader = BitReader::new(&overflow);
+        assert_eq!(
+            reader.read_varint(),
+            Err(read_error(0, "varint", Error::ValueOutOfBounds))
+        );
+    }
+
+    #[test]
+    fn relative() {
+        let base = 1000;
+        let values: [(i32, i64); 9] = [
+            (base, 5),
+            (base + 1, 5),
+            (base - 1, 5),
+            (base + 8, 10),
+            (base - 20, 10),
+            (base + 200, 19),
+            (base - 30000, 19),
+            (i32::MAX, 35),
+            (i32::MIN, 35),
+        ];
+
+        let mut bytes = [0u8; 32];
+        let mut writer = BitWriter::new(&mut bytes);
+        for (value, bits) in values.iter() {
+            let before = writer.written_bits();
+            assert!(writer.write_relative(*value, base).is_ok());
+            assert_eq!(writer.written_bits() - before, *bits, "{}", value);
+            assert!(*bits < 32 || (*value as i64 - base as i64).abs() > u16::MAX as i64);
+        }
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        for (value, _) in values.iter() {
+            assert_eq!(reader.read_relative(base).unwrap(), *value);
+        }
+
+        let mut seed: <ChaCha8Rng as SeedableRng>::Seed = Default::default();
+        rand::thread_rng().fill(&mut seed);
+
+        println!("seed: {:?}", seed);
+        let mut rng = ChaChaRng::from_seed(seed);
+
+        let mut pairs = Vec::<(i32, i32)>::new();
+        for _ in 0..256 {
+            let base = rng.gen::<i32>();
+            let delta = match rng.gen_range(0..3) {
+                0 => rng.gen_range(-8..8),
+                1 => rng.gen_range(-100_000..100_000),
+                _ => rng.gen::<i32>(),
+            };
+            pairs.push((base.wrapping_add(delta), base));
+        }
+
+        let mut bytes = [0u8; 256 * 5];
+        let mut writer = BitWriter::new(&mut bytes);
+        for (value, base) in pairs.iter() {
+            assert!(writer.write_relative(*value, *base).is_ok());
+        }
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        for (value, base) in pairs.iter() {
+            assert_eq!(reader.read_relative(*base).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        let payload = [1u8, 2, 3, 4, 5, 6, 7, 8, 9];
+
+        let mut bytes = [0u8; 16];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_bits(5, 3).is_ok());
+        assert!(writer.write_bytes(&payload).is_ok());
+        assert_eq!(writer.written_bits(), 8 + payload.len() as i64 * 8);
+        assert!(writer.write_bool(true).is_ok());
+        assert!(writer.align().is_ok());
+        assert_eq!(writer.written_bits(), 16 + payload.len() as i64 * 8);
+        assert!(writer.align().is_ok());
+        assert_eq!(writer.written_bits(), 16 + payload.len() as i64 * 8);
+        assert!(matches!(
+            writer.write_bytes(&payload),
+            Err(Error::OutOfMemory)
+        ));
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(3).unwrap(), 5);
+        let mut result = [0u8; 9];
+        assert!(reader.read_bytes(&mut result).is_ok());
+        assert_eq!(result, payload);
+        assert!(reader.read_bool().unwrap());
+        assert!(reader.align().is_ok());
+        let mut result = [0u8; 9];
+        assert_eq!(
+            reader.read_bytes(&mut result),
+            Err(read_error(88, "bytes", Error::OutOfMemory))
+        );
+
+        // non-zero padding is rejected.
+        let bytes = [0xffu8; 2];
+        let mut reader = BitReader::new(&bytes);
+        assert!(reader.read_bool().is_ok());
+        assert_eq!(
+            reader.align(),
+            Err(read_error(1, "align", Error::ValueOutOfBounds))
+        );
+    }
+
+    #[test]
+    fn string() {
+        const MAX_LENGTH: usize = 32;
+        let values = ["", "player", "zażółć gęślą jaźń"];
+
+        let mut bytes = [0u8; 128];
+        let mut writer = BitWriter::new(&mut bytes);
+        for value in values.iter() {
+            assert!(writer.write_bool(true).is_ok());
+            assert!(writer.write_string(value, MAX_LENGTH).is_ok());
+        }
+        assert!(matches!(
+            writer.write_string(&"x".repeat(MAX_LENGTH + 1), MAX_LENGTH),
+            Err(Error::ValueOutOfBounds)
+        ));
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        for value in values.iter() {
+            assert!(reader.read_bool().unwrap());
+            assert_eq!(reader.read_string(MAX_LENGTH).unwrap(), *value);
+        }
+
+        // declared length larger than the data left.
+        let mut bytes = [0u8; 4];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_int_range(1000, 0, 1000).is_ok());
+        writer.flush();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(
+            reader.read_string(1000),
+            Err(read_error(0, "string", Error::OutOfMemory))
+        );
+
+        let mut bytes = [0u8; 4];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_int_range(2, 0, 8).is_ok());
+        assert!(writer.write_bytes(&[0xc3, 0x28]).is_ok());
+        writer.flush();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(
+            reader.read_string(8),
+            Err(read_error(0, "string", Error::InvalidString))
+        );
+    }
+
+    #[test]
+    fn stream_bytes() {
+        struct Chat {
+            channel: u32,
+            sender: String,
+            payload: [u8; 5],
+        }
+
+        impl NetSerialize for Chat {
+            fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+                stream.serialize_bits(&mut self.channel, 3)?;
+                stream.serialize_string(&mut self.sender, 16)?;
+                stream.serialize_bytes(&mut self.payload)?;
+                stream.serialize_align()
+            }
+        }
+
+        let mut expected = Chat {
+            channel: 5,
+            sender: String::from("someone"),
+            payload: *b"hello",
+        };
+        let measured = measure(&mut expected).unwrap();
+        assert_eq!(measured, 8 + 7 * 8 + 5 * 8);
+
+        let mut bytes = [0u8; 32];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(expected.serialize(&mut writer).is_ok());
+        assert_eq!(writer.written_bits(), measured);
+        writer.flush();
+
+        let mut result = Chat {
+            channel: 0,
+            sender: String::new(),
+            payload: [0; 5],
+        };
+        let mut reader = BitReader::new(&bytes);
+        assert!(result.serialize(&mut reader).is_ok());
+        assert_eq!(result.channel, expected.channel);
+        assert_eq!(result.sender, expected.sender);
+        assert_eq!(result.payload, expected.payload);
+    }
+
+    #[test]
+    fn growable() {
+        let mut writer = BitWriter::growable(None);
+        for i in 0..1000u32 {
+            assert!(writer.write_bits(i, 11).is_ok());
+        }
+        assert!(writer.write_string("tail", 8).is_ok());
+        // 4 length bits pad the stream to 11008 before the string bytes.
+        assert_eq!(writer.written_bits(), 11008 + 4 * 8);
+        let bytes = writer.into_bytes();
+        assert_eq!(bytes.len(), (11008 + 4 * 8) / 8);
+
+        let mut reader = BitReader::new(&bytes);
+        for i in 0..1000u32 {
+            assert_eq!(reader.read_bits(11).unwrap(), i);
+        }
+        assert_eq!(reader.read_string(8).unwrap(), "tail");
+
+        let mut writer = BitWriter::growable(Some(2));
+        assert!(writer.write_bits(0x1ff, 9).is_ok());
+        assert!(writer.write_bits(0x7f, 7).is_ok());
+        assert!(matches!(writer.write_bool(true), Err(Error::OutOfMemory)));
+        assert!(matches!(writer.write_bytes(&[1]), Err(Error::OutOfMemory)));
+        assert_eq!(writer.into_bytes(), vec![0xff, 0xff]);
+
+        let mut bytes = [0u8; 8];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_bits(0xabc, 12).is_ok());
+        assert_eq!(writer.into_bytes(), vec![0xab, 0xc0]);
+    }
+
+    #[test]
+    fn reader_position() {
+        let mut bytes = [0u8; 16];
+        let mut writer = BitWriter::new(&mut bytes);
+        assert!(writer.write_bits(0x5, 3).is_ok());
+        assert!(writer.write_bits(0xabcd, 16).is_ok());
+        assert!(writer.write_bytes(&[1, 2, 3, 4]).is_ok());
+        assert!(writer.write_bits(0x3, 2).is_ok());
+        writer.flush();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.bits_read(), 0);
+        assert_eq!(reader.bits_remaining(), 128);
+
+        assert_eq!(reader.peek_bits(3).unwrap(), 0x5);
+        assert_eq!(reader.bits_read(), 0);
+        assert!(reader.skip_bits(3).is_ok());
+        assert_eq!(reader.bits_read(), 3);
+
+        assert_eq!(reader.peek_bits(16).unwrap(), 0xabcd);
+        assert_eq!(reader.read_bits(16).unwrap(), 0xabcd);
+        assert_eq!(reader.bits_read(), 19);
+        assert_eq!(reader.bits_remaining(), 128 - 19);
+
+        // padding and two bytes from the scratch, one straight from the source.
+        assert!(reader.skip_bits(5 + 24).is_ok());
+        assert_eq!(reader.bits_read(), 48);
+        assert_eq!(reader.read_byte().unwrap(), 4);
+        assert_eq!(reader.read_bits(2).unwrap(), 0x3);
+        assert_eq!(reader.bits_remaining(), 128 - 58);
+
+        assert_eq!(
+            reader.skip_bits(128),
+            Err(read_error(58, "skip", Error::OutOfMemory))
+        );
+        assert_eq!(
+            reader.peek_bits(33),
+            Err(read_error(58, "bits", Error::InvalidArgument))
+        );
+        assert!(reader.skip_bits(128 - 58).is_ok());
+        assert_eq!(reader.bits_remaining(), 0);
+
+        // failures inside compound values point at the exact read.
+        let mut reader = BitReader::new(&bytes[..3]);
+        assert!(reader.skip_bits(2).is_ok());
+        let error = reader.read_vector(0.0, 1.0, 0.001).unwrap_err();
+        assert_eq!(error, read_error(22, "vector", Error::OutOfMemory));
+        assert_eq!(error.cause(), &Error::OutOfMemory);
+        assert_eq!(error.to_string(), "out of memory reading vector at bit 22");
+    }
+
+    #[test]
+    fn derive() {
+        use network_derive::NetSerialize;
+
+        const TEAM_BITS: i32 = 5;
+
+        #[derive(NetSerialize, Debug, PartialEq, Default)]
+        struct Transform {
+            #[quantize(-512.0, 512.0, 0.125)]
+            position: [f32; 3],
+            #[quantize(0.0, 1.0, 0.001)]
+            scale: f32,
+            rotation: Rotation,
+        }
+
+        #[derive(NetSerialize, Debug, PartialEq, Default)]
+        struct Rotation(#[range(-180, 180)] i16);
+
+        #[derive(NetSerialize, Debug, PartialEq)]
+        enum Event {
+            Start,
+            Chat {
+                #[max_length(32)]
+                text: String,
+                #[bits(TEAM_BITS)]
+                team: u8,
+            },
+            Spawn(u64, Transform, bool),
+            Score(#[range(-100, 100)] i32, i64),
+            Hit(i8, i16),
+        }
+
+        let mut events = [
+            Event::Chat {
+                text: String::from("gg"),
+                team: 17,
+            },
+            Event::Start,
+            Event::Spawn(
+                u64::MAX,
+                Transform {
+                    position: [1.0, -2.5, 300.0],
+                    scale: 0.5,
+                    rotation: Rotation(-90),
+                },
+                true,
+            ),
+            Event::Score(-42, i64::MIN),
+            Event::Hit(i8::MIN, i16::MAX),
+        ];
+
+        // three bits of variant index in front of every event.
+        assert_eq!(measure(&mut events[1]).unwrap(), 3);
+        assert_eq!(measure(&mut events[3]).unwrap(), 3 + 8 + 64);
+        assert_eq!(measure(&mut events[4]).unwrap(), 3 + 8 + 16);
+
+        let mut writer = BitWriter::growable(None);
+        for event in events.iter_mut() {
+            assert!(event.serialize(&mut writer).is_ok());
+        }
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::new(&bytes);
+        for expected in events.iter() {
+            let mut event = Event::Start;
+            assert!(event.serialize(&mut reader).is_ok());
+            assert_eq!(event, *expected);
+        }
+
+        let mut invalid = Event::Chat {
+            text: String::from("gg"),
+            team: 32,
+        };
+        assert!(measure(&mut invalid).is_err());
+
+        // variant index 3 fits into 2 bits but a 4th variant does not exist.
+        #[derive(NetSerialize, Debug, PartialEq)]
+        enum Small {
+            A,
+            B,
+            C,
+        }
+        let bytes = [0xc0u8];
+        let mut reader = BitReader::new(&bytes);
+        let mut small = Small::A;
+        assert!(small.serialize(&mut reader).is_err());
+        assert_eq!(small, Small::A);
+        let _ = (Small::B, Small::C);
     }
 
     #[test]
@@ -294,10 +1960,13 @@ mod tests {
 
         let mut reader = BitReader::new(&bytes);
 
-        for (bits, value) in values {
-            let read = reader.read_bits(bits);
+        for (bits, value) in values.iter() {
+            let read = reader.read_bits(*bits);
             assert!(read.is_ok());
-            assert_eq!(read.unwrap(), value);
+            assert_eq!(read.unwrap(), *value);
         }
+
+        let mut bytes: [u8; NBYTES] = [0; NBYTES];
+        stream_round_trip(Sample { values }, &mut bytes);
     }
 }
@@ -0,0 +1,803 @@
+use super::bits::{measure, BitReader, BitWriter, Error, NetSerialize, Stream};
+use super::sequence::{sequence_greater_than, sequence_less_than, Sequence, SequenceBuffer};
+use std::{collections::VecDeque, fmt, time};
+
+pub type ChannelId = u8;
+pub type MessageType = u8;
+/// Wrapping per-channel message number, compared like packet sequences.
+pub type MessageId = u16;
+
+/// Largest message sent in one piece. Larger reliable messages are split
+/// into a block of fragments.
+pub const MAX_MESSAGE_BYTES: usize = 1024;
+
+/// Largest reliable message.
+pub const MAX_BLOCK_BYTES: usize = 256 * 1024;
+
+// upper bound of the bits a reliable message adds to its data.
+const RELIABLE_OVERHEAD_BYTES: usize = 8;
+
+/// Number of reliable messages tracked per channel, bounds `queue_limit`.
+const MESSAGE_WINDOW: usize = 1024;
+
+/// Resend delay used until the round-trip time is known.
+const INITIAL_RESEND_DELAY: time::Duration = time::Duration::from_millis(100);
+const MIN_RESEND_DELAY: time::Duration = time::Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelType {
+    /// Resent until acknowledged and delivered in the order sent.
+    ReliableOrdered,
+    /// Sent once. Messages older than the latest received are dropped.
+    UnreliableSequenced,
+    /// Sent once and delivered as they arrive.
+    UnreliableUnordered,
+}
+
+impl ChannelType {
+    fn has_ids(self) -> bool {
+        self != ChannelType::UnreliableUnordered
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelConfig {
+    pub kind: ChannelType,
+    /// Most bytes of messages from this channel in one packet.
+    pub packet_budget: usize,
+    /// Most messages waiting to be sent or acknowledged. Sending more fails
+    /// with `SendError::QueueFull`. Unreliable channels also keep at most
+    /// this many received messages, dropping the oldest; the reliable one
+    /// buffers whatever arrives within `MESSAGE_WINDOW` of the next message
+    /// to be received.
+    pub queue_limit: usize,
+}
+
+pub const RELIABLE_CHANNEL: ChannelId = 0;
+pub const SEQUENCED_CHANNEL: ChannelId = 1;
+pub const UNRELIABLE_CHANNEL: ChannelId = 2;
+
+/// Channels used by `NetcodeServer` and `NetcodeClient`, indexed by `ChannelId`.
+pub const CHANNELS: [ChannelConfig; 3] = [
+    ChannelConfig {
+        kind: ChannelType::ReliableOrdered,
+        packet_budget: 512,
+        queue_limit: 256,
+    },
+    ChannelConfig {
+        kind: ChannelType::UnreliableSequenced,
+        packet_budget: 256,
+        queue_limit: 16,
+    },
+    ChannelConfig {
+        kind: ChannelType::UnreliableUnordered,
+        packet_budget: 256,
+        queue_limit: 64,
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Message {
+    pub kind: MessageType,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: MessageType, data: &[u8]) -> Message {
+        Message {
+            kind,
+            data: data.to_vec(),
+        }
+    }
+}
+
+impl NetSerialize for Message {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+        stream.serialize_byte(&mut self.kind)?;
+        let mut length = self.data.len() as u64;
+        stream.serialize_varint(&mut length)?;
+        if length > MAX_MESSAGE_BYTES as u64 {
+            return Err(Error::ValueOutOfBounds);
+        }
+        if stream.is_reading() {
+            self.data.resize(length as usize, 0);
+        }
+        stream.serialize_bytes(&mut self.data)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    UnknownChannel,
+    QueueFull,
+    /// The message does not fit the packet budget of its channel.
+    TooLarge,
+    /// There is no connection with the destination.
+    NotConnected,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::UnknownChannel => write!(f, "unknown channel"),
+            SendError::QueueFull => write!(f, "channel queue is full"),
+            SendError::TooLarge => write!(f, "message exceeds the channel budget"),
+            SendError::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// How long a reliable message waits for an ack before it is sent again.
+pub fn resend_delay(rtt: Option<time::Duration>) -> time::Duration {
+    match rtt {
+        Some(rtt) => rtt.mul_f32(1.5).max(MIN_RESEND_DELAY),
+        None => INITIAL_RESEND_DELAY,
+    }
+}
+
+// bits taken by `message` in a packet: the continuation bit, its id, the
+// block bit and the padding before its bytes.
+fn message_bits(kind: ChannelType, message: &Message) -> i64 {
+    let id_bits = if kind.has_ids() { 16 } else { 0 };
+    let block_bits = if kind == ChannelType::ReliableOrdered {
+        1
+    } else {
+        0
+    };
+    let mut message = message.clone();
+    1 + id_bits + block_bits + measure(&mut message).unwrap_or(i64::MAX / 2) + 7
+}
+
+/// A message as it is written to a packet.
+struct Wire {
+    id: MessageId,
+    /// More fragments of the same block follow, reliable channels only.
+    block: bool,
+    message: Message,
+}
+
+trait Channel {
+    fn send(&mut self, message: Message) -> Result<(), SendError>;
+    fn receive(&mut self) -> Option<Message>;
+    fn deliver(&mut self, wire: Wire);
+    /// True if a packet should be sent for this channel.
+    fn has_pending(&self, now: time::Instant, resend_delay: time::Duration) -> bool;
+    /// Messages for packet `sequence`, at most `budget` bits of them.
+    fn outgoing(
+        &mut self,
+        sequence: Sequence,
+        now: time::Instant,
+        resend_delay: time::Duration,
+        budget: i64,
+    ) -> Vec<Wire>;
+    fn on_acked(&mut self, _sequence: Sequence) {}
+}
+
+struct Outgoing {
+    message: Message,
+    block: bool,
+    last_sent: Option<time::Instant>,
+}
+
+struct ReliableOrdered {
+    config: ChannelConfig,
+    send_queue: SequenceBuffer<Outgoing>,
+    next_send_id: MessageId,
+    oldest_unacked: MessageId,
+    // message ids carried by each sent packet.
+    sent_packets: SequenceBuffer<Vec<MessageId>>,
+    receive_queue: SequenceBuffer<(bool, Message)>,
+    next_receive_id: MessageId,
+    // fragments of the block being received.
+    block: Option<Message>,
+    discarding: bool,
+}
+
+impl ReliableOrdered {
+    fn new(config: ChannelConfig) -> ReliableOrdered {
+        ReliableOrdered {
+            config,
+            send_queue: SequenceBuffer::new(MESSAGE_WINDOW),
+            next_send_id: 0,
+            oldest_unacked: 0,
+            sent_packets: SequenceBuffer::new(MESSAGE_WINDOW),
+            receive_queue: SequenceBuffer::new(MESSAGE_WINDOW),
+            next_receive_id: 0,
+            block: None,
+            discarding: false,
+        }
+    }
+
+    fn fragment_bytes(&self) -> usize {
+        (self.config.packet_budget - RELIABLE_OVERHEAD_BYTES).min(MAX_MESSAGE_BYTES)
+    }
+
+    fn push(&mut self, message: Message, block: bool) {
+        self.send_queue.insert(
+            self.next_send_id,
+            Outgoing {
+                message,
+                block,
+                last_sent: None,
+            },
+        );
+        self.next_send_id = self.next_send_id.wrapping_add(1);
+    }
+
+    fn in_flight(&self) -> usize {
+        self.next_send_id.wrapping_sub(self.oldest_unacked) as usize
+    }
+
+    fn is_due(outgoing: &Outgoing, now: time::Instant, resend_delay: time::Duration) -> bool {
+        match outgoing.last_sent {
+            Some(last_sent) => now.saturating_duration_since(last_sent) >= resend_delay,
+            None => true,
+        }
+    }
+}
+
+impl Channel for ReliableOrdered {
+    fn send(&mut self, message: Message) -> Result<(), SendError> {
+        let fragment_bytes = self.fragment_bytes();
+        let count = message.data.len().div_ceil(fragment_bytes).max(1);
+        if message.data.len() > MAX_BLOCK_BYTES || count > self.config.queue_limit {
+            return Err(SendError::TooLarge);
+        }
+        if self.in_flight() + count > self.config.queue_limit {
+            return Err(SendError::QueueFull);
+        }
+        if count == 1 {
+            self.push(message, false);
+            return Ok(());
+        }
+        for (index, data) in message.data.chunks(fragment_bytes).enumerate() {
+            self.push(Message::new(message.kind, data), index + 1 < count);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        loop {
+            let (block, message) = self.receive_queue.remove(self.next_receive_id)?;
+            self.next_receive_id = self.next_receive_id.wrapping_add(1);
+            let message = match self.block.take() {
+                Some(mut fragments) => {
+                    fragments.data.extend_from_slice(&message.data);
+                    fragments
+                }
+                None => message,
+            };
+            // no sender makes blocks this large, drop the rest of it.
+            if self.discarding || message.data.len() > MAX_BLOCK_BYTES {
+                self.discarding = block;
+                continue;
+            }
+            if !block {
+                return Some(message);
+            }
+            self.block = Some(message);
+        }
+    }
+
+    fn deliver(&mut self, wire: Wire) {
+        let id = wire.id;
+        // already delivered, or further ahead than the sender may be.
+        let window_end = self
+            .next_receive_id
+            .wrapping_add(MESSAGE_WINDOW as MessageId);
+        if sequence_less_than(id, self.next_receive_id) || !sequence_less_than(id, window_end) {
+            return;
+        }
+        if !self.receive_queue.contains(id) {
+            self.receive_queue.insert(id, (wire.block, wire.message));
+        }
+    }
+
+    fn has_pending(&self, now: time::Instant, resend_delay: time::Duration) -> bool {
+        (0..self.in_flight()).any(|offset| {
+            let id = self.oldest_unacked.wrapping_add(offset as MessageId);
+            self.send_queue
+                .get(id)
+                .is_some_and(|outgoing| Self::is_due(outgoing, now, resend_delay))
+        })
+    }
+
+    fn outgoing(
+        &mut self,
+        sequence: Sequence,
+        now: time::Instant,
+        resend_delay: time::Duration,
+        mut budget: i64,
+    ) -> Vec<Wire> {
+        let mut messages = Vec::new();
+        for offset in 0..self.in_flight() {
+            let id = self.oldest_unacked.wrapping_add(offset as MessageId);
+            let outgoing = match self.send_queue.get_mut(id) {
+                Some(outgoing) if Self::is_due(outgoing, now, resend_delay) => outgoing,
+                _ => continue,
+            };
+            let bits = message_bits(self.config.kind, &outgoing.message);
+            if bits > budget {
+                continue;
+            }
+            budget -= bits;
+            outgoing.last_sent = Some(now);
+            messages.push(Wire {
+                id,
+                block: outgoing.block,
+                message: outgoing.message.clone(),
+            });
+        }
+        if !messages.is_empty() {
+            self.sent_packets
+                .insert(sequence, messages.iter().map(|wire| wire.id).collect());
+        }
+        messages
+    }
+
+    fn on_acked(&mut self, sequence: Sequence) {
+        if let Some(ids) = self.sent_packets.remove(sequence) {
+            for id in ids {
+                self.send_queue.remove(id);
+            }
+        }
+        while self.oldest_unacked != self.next_send_id
+            && !self.send_queue.contains(self.oldest_unacked)
+        {
+            self.oldest_unacked = self.oldest_unacked.wrapping_add(1);
+        }
+    }
+}
+
+/// Both unreliable channels: the sequenced one numbers its messages and drops
+/// those older than the latest received.
+struct Unreliable {
+    config: ChannelConfig,
+    send_queue: VecDeque<(MessageId, Message)>,
+    next_send_id: MessageId,
+    receive_queue: VecDeque<Message>,
+    latest_received: Option<MessageId>,
+}
+
+impl Unreliable {
+    fn new(config: ChannelConfig) -> Unreliable {
+        Unreliable {
+            config,
+            send_queue: VecDeque::new(),
+            next_send_id: 0,
+            receive_queue: VecDeque::new(),
+            latest_received: None,
+        }
+    }
+}
+
+impl Channel for Unreliable {
+    fn send(&mut self, message: Message) -> Result<(), SendError> {
+        if message.data.len() > MAX_MESSAGE_BYTES
+            || message_bits(self.config.kind, &message) > self.config.packet_budget as i64 * 8
+        {
+            return Err(SendError::TooLarge);
+        }
+        if self.send_queue.len() >= self.config.queue_limit {
+            return Err(SendError::QueueFull);
+        }
+        self.send_queue.push_back((self.next_send_id, message));
+        self.next_send_id = self.next_send_id.wrapping_add(1);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        self.receive_queue.pop_front()
+    }
+
+    fn deliver(&mut self, wire: Wire) {
+        if self.config.kind == ChannelType::UnreliableSequenced {
+            if let Some(latest) = self.latest_received {
+                if !sequence_greater_than(wire.id, latest) {
+                    return;
+                }
+            }
+            self.latest_received = Some(wire.id);
+        }
+        // nobody reads them, keep the newest.
+        if self.receive_queue.len() >= self.config.queue_limit {
+            self.receive_queue.pop_front();
+        }
+        self.receive_queue.push_back(wire.message);
+    }
+
+    fn has_pending(&self, _now: time::Instant, _resend_delay: time::Duration) -> bool {
+        !self.send_queue.is_empty()
+    }
+
+    fn outgoing(
+        &mut self,
+        _sequence: Sequence,
+        _now: time::Instant,
+        _resend_delay: time::Duration,
+        mut budget: i64,
+    ) -> Vec<Wire> {
+        let mut messages = Vec::new();
+        while let Some((_, message)) = self.send_queue.front() {
+            let bits = message_bits(self.config.kind, message);
+            if bits > budget {
+                break;
+            }
+            budget -= bits;
+            let (id, message) = self.send_queue.pop_front().unwrap();
+            messages.push(Wire {
+                id,
+                block: false,
+                message,
+            });
+        }
+        messages
+    }
+}
+
+/// The messages of all channels, written in front of the payload of a packet.
+pub struct Channels {
+    configs: &'static [ChannelConfig],
+    channels: Vec<Box<dyn Channel>>,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels::new(&CHANNELS)
+    }
+}
+
+impl Channels {
+    pub fn new(configs: &'static [ChannelConfig]) -> Channels {
+        assert!(configs.len() <= ChannelId::MAX as usize + 1);
+        let channels = configs
+            .iter()
+            .map(|config| -> Box<dyn Channel> {
+                match config.kind {
+                    ChannelType::ReliableOrdered => {
+                        assert!(config.queue_limit <= MESSAGE_WINDOW);
+                        assert!(config.packet_budget > RELIABLE_OVERHEAD_BYTES);
+                        Box::new(ReliableOrdered::new(*config))
+                    }
+                    _ => Box::new(Unreliable::new(*config)),
+                }
+            })
+            .collect();
+        Channels { configs, channels }
+    }
+
+    /// Reliable messages up to `MAX_BLOCK_BYTES` are accepted, and sent as
+    /// a block of fragments if they do not fit the packet budget.
+    pub fn send(&mut self, channel: ChannelId, message: Message) -> Result<(), SendError> {
+        self.channels
+            .get_mut(channel as usize)
+            .ok_or(SendError::UnknownChannel)?
+            .send(message)
+    }
+
+    /// Next message received on `channel`, in the order the channel delivers them.
+    pub fn receive(&mut self, channel: ChannelId) -> Option<Message> {
+        self.channels.get_mut(channel as usize)?.receive()
+    }
+
+    pub fn has_pending(&self, now: time::Instant, resend_delay: time::Duration) -> bool {
+        self.channels
+            .iter()
+            .any(|channel| channel.has_pending(now, resend_delay))
+    }
+
+    pub fn on_acked(&mut self, sequence: Sequence) {
+        for channel in &mut self.channels {
+            channel.on_acked(sequence);
+        }
+    }
+
+    /// Writes the messages going into packet `sequence`. Returns whether any
+    /// of them is reliable, and the bytes.
+    pub fn write(
+        &mut self,
+        sequence: Sequence,
+        now: time::Instant,
+        resend_delay: time::Duration,
+    ) -> Result<(bool, Vec<u8>), Error> {
+        let mut writer = BitWriter::growable(None);
+        let mut reliable = false;
+        for (config, channel) in self.configs.iter().zip(&mut self.channels) {
+            let budget = config.packet_budget as i64 * 8;
+            for mut wire in channel.outgoing(sequence, now, resend_delay, budget) {
+                writer.write_bool(true)?;
+                if config.kind.has_ids() {
+                    writer.write_bits(wire.id as u32, 16)?;
+                }
+                if config.kind == ChannelType::ReliableOrdered {
+                    writer.write_bool(wire.block)?;
+                    reliable = true;
+                }
+                wire.message.serialize(&mut writer)?;
+            }
+            writer.write_bool(false)?;
+        }
+        writer.align()?;
+        Ok((reliable, writer.into_bytes()))
+    }
+
+    /// Reads the messages in front of `body` without delivering them. Returns
+    /// them with the offset of the payload after them.
+    pub fn read(body: &[u8], configs: &[ChannelConfig]) -> Result<(Incoming, usize), Error> {
+        let mut reader = BitReader::new(body);
+        let mut messages = Vec::new();
+        for (channel, config) in configs.iter().enumerate() {
+            while reader.read_bool()? {
+                let id = match config.kind.has_ids() {
+                    true => reader.read_bits(16)? as MessageId,
+                    false => 0,
+                };
+                let block = match config.kind {
+                    ChannelType::ReliableOrdered => reader.read_bool()?,
+                    _ => false,
+                };
+                let mut message = Message::default();
+                message.serialize(&mut reader)?;
+                messages.push((channel as ChannelId, Wire { id, block, message }));
+            }
+        }
+        reader.align()?;
+        Ok((Incoming { messages }, (reader.bits_read() / 8) as usize))
+    }
+
+    pub fn configs(&self) -> &'static [ChannelConfig] {
+        self.configs
+    }
+
+    /// Hands messages read with `Channels::read` to their channels. Returns
+    /// true if any was reliable.
+    pub fn deliver(&mut self, incoming: Incoming) -> bool {
+        let mut reliable = false;
+        for (channel, wire) in incoming.messages {
+            reliable |= self.configs[channel as usize].kind == ChannelType::ReliableOrdered;
+            self.channels[channel as usize].deliver(wire);
+        }
+        reliable
+    }
+}
+
+/// Messages read from a packet, see `Channels::read`.
+pub struct Incoming {
+    messages: Vec<(ChannelId, Wire)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        message_bits, resend_delay, ChannelConfig, ChannelType, Channels, Message, ReliableOrdered,
+        SendError, CHANNELS, MAX_BLOCK_BYTES, RELIABLE_CHANNEL, SEQUENCED_CHANNEL,
+        UNRELIABLE_CHANNEL,
+    };
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use std::time;
+
+    fn exchange(from: &mut Channels, to: &mut Channels, sequence: u16, now: time::Instant) {
+        let (_, body) = from.write(sequence, now, resend_delay(None)).unwrap();
+        let (incoming, offset) = Channels::read(&body, to.configs()).unwrap();
+        assert_eq!(offset, body.len());
+        to.deliver(incoming);
+    }
+
+    #[test]
+    fn empty() {
+        let mut channels = Channels::default();
+        let now = time::Instant::now();
+        assert!(!channels.has_pending(now, resend_delay(None)));
+        let (reliable, body) = channels.write(0, now, resend_delay(None)).unwrap();
+        assert!(!reliable);
+        assert_eq!(body.len(), 1);
+
+        let mut body = body;
+        body.extend_from_slice(b"payload");
+        let (_, offset) = Channels::read(&body, &CHANNELS).unwrap();
+        assert_eq!(&body[offset..], b"payload");
+    }
+
+    #[test]
+    fn send_errors() {
+        let mut channels = Channels::default();
+        let message = Message::new(0, b"");
+        assert_eq!(
+            channels.send(3, message.clone()),
+            Err(SendError::UnknownChannel)
+        );
+        assert_eq!(
+            channels.send(SEQUENCED_CHANNEL, Message::new(0, &[0; 300])),
+            Err(SendError::TooLarge)
+        );
+        for _ in 0..CHANNELS[UNRELIABLE_CHANNEL as usize].queue_limit {
+            channels.send(UNRELIABLE_CHANNEL, message.clone()).unwrap();
+        }
+        assert_eq!(
+            channels.send(UNRELIABLE_CHANNEL, message),
+            Err(SendError::QueueFull)
+        );
+    }
+
+    #[test]
+    fn unreliable() {
+        let now = time::Instant::now();
+        let (mut sender, mut receiver) = (Channels::default(), Channels::default());
+        for i in 0..3 {
+            sender
+                .send(UNRELIABLE_CHANNEL, Message::new(i, b"effect"))
+                .unwrap();
+        }
+        exchange(&mut sender, &mut receiver, 0, now);
+        assert!(!sender.has_pending(now, resend_delay(None)));
+        for i in 0..3 {
+            assert_eq!(
+                receiver.receive(UNRELIABLE_CHANNEL),
+                Some(Message::new(i, b"effect"))
+            );
+        }
+        assert_eq!(receiver.receive(UNRELIABLE_CHANNEL), None);
+    }
+
+    #[test]
+    fn sequenced() {
+        let now = time::Instant::now();
+        let (mut sender, mut receiver) = (Channels::default(), Channels::default());
+        sender
+            .send(SEQUENCED_CHANNEL, Message::new(0, b"old"))
+            .unwrap();
+        let (_, old) = sender.write(0, now, resend_delay(None)).unwrap();
+        sender
+            .send(SEQUENCED_CHANNEL, Message::new(0, b"new"))
+            .unwrap();
+        exchange(&mut sender, &mut receiver, 1, now);
+
+        // the older packet arrives late and is dropped.
+        let (incoming, _) = Channels::read(&old, &CHANNELS).unwrap();
+        receiver.deliver(incoming);
+        assert_eq!(
+            receiver.receive(SEQUENCED_CHANNEL),
+            Some(Message::new(0, b"new"))
+        );
+        assert_eq!(receiver.receive(SEQUENCED_CHANNEL), None);
+    }
+
+    #[test]
+    fn budget() {
+        static CONFIGS: [ChannelConfig; 1] = [ChannelConfig {
+            kind: ChannelType::UnreliableUnordered,
+            packet_budget: 64,
+            queue_limit: 8,
+        }];
+        let now = time::Instant::now();
+        let (mut sender, mut receiver) = (Channels::new(&CONFIGS), Channels::new(&CONFIGS));
+        for _ in 0..4 {
+            sender.send(0, Message::new(0, &[0; 20])).unwrap();
+        }
+        exchange(&mut sender, &mut receiver, 0, now);
+        assert_eq!(std::iter::from_fn(|| receiver.receive(0)).count(), 2);
+        exchange(&mut sender, &mut receiver, 1, now);
+        assert_eq!(std::iter::from_fn(|| receiver.receive(0)).count(), 2);
+    }
+
+    #[test]
+    fn reliable() {
+        let seed = rand::random();
+        println!("seed: {}", seed);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+        let start = time::Instant::now();
+        let (mut sender, mut receiver) = (Channels::default(), Channels::default());
+        let mut expected = Vec::new();
+        let mut received = Vec::new();
+        let delay = resend_delay(None);
+
+        for i in 0..2000u32 {
+            let now = start + time::Duration::from_millis(10) * i;
+            if i < 1000 {
+                let message = Message::new(i as u8, &i.to_le_bytes());
+                if sender.send(RELIABLE_CHANNEL, message.clone()).is_ok() {
+                    expected.push(message);
+                }
+            }
+            // half of the packets get lost, acks arrive right away.
+            let sequence = i as u16;
+            let (_, body) = sender.write(sequence, now, delay).unwrap();
+            if rng.gen_bool(0.5) {
+                let (incoming, _) = Channels::read(&body, &CHANNELS).unwrap();
+                receiver.deliver(incoming);
+                sender.on_acked(sequence);
+            }
+            while let Some(message) = receiver.receive(RELIABLE_CHANNEL) {
+                received.push(message);
+            }
+        }
+        assert_eq!(expected.len(), 1000);
+        assert_eq!(received, expected);
+        assert!(!sender.has_pending(start + time::Duration::from_secs(60), delay));
+    }
+
+    #[test]
+    fn reliable_queue_limit() {
+        let mut channels = Channels::default();
+        let limit = CHANNELS[RELIABLE_CHANNEL as usize].queue_limit;
+        for _ in 0..limit {
+            channels
+                .send(RELIABLE_CHANNEL, Message::new(0, b""))
+                .unwrap();
+        }
+        assert_eq!(
+            channels.send(RELIABLE_CHANNEL, Message::new(0, b"")),
+            Err(SendError::QueueFull)
+        );
+
+        // acked messages make room.
+        let now = time::Instant::now();
+        channels.write(0, now, resend_delay(None)).unwrap();
+        channels.on_acked(0);
+        channels
+            .send(RELIABLE_CHANNEL, Message::new(0, b""))
+            .unwrap();
+    }
+
+    #[test]
+    fn blocks() {
+        let config = CHANNELS[RELIABLE_CHANNEL as usize];
+        let fragment_bytes = ReliableOrdered::new(config).fragment_bytes();
+        let fragment = Message::new(0, &vec![0; fragment_bytes]);
+        assert!(message_bits(config.kind, &fragment) <= config.packet_budget as i64 * 8);
+
+        let seed = rand::random();
+        println!("seed: {}", seed);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let level: Vec<u8> = (0..20_000).map(|_| rng.gen()).collect();
+
+        let start = time::Instant::now();
+        let (mut sender, mut receiver) = (Channels::default(), Channels::default());
+        sender
+            .send(RELIABLE_CHANNEL, Message::new(1, b"before"))
+            .unwrap();
+        sender
+            .send(RELIABLE_CHANNEL, Message::new(2, &level))
+            .unwrap();
+        sender
+            .send(RELIABLE_CHANNEL, Message::new(3, b"after"))
+            .unwrap();
+
+        let mut received = Vec::new();
+        for i in 0..500u32 {
+            let now = start + time::Duration::from_millis(10) * i;
+            let (_, body) = sender.write(i as u16, now, resend_delay(None)).unwrap();
+            if rng.gen_bool(0.7) {
+                let (incoming, _) = Channels::read(&body, &CHANNELS).unwrap();
+                receiver.deliver(incoming);
+                sender.on_acked(i as u16);
+            }
+            received.extend(std::iter::from_fn(|| receiver.receive(RELIABLE_CHANNEL)));
+        }
+        assert_eq!(
+            received,
+            vec![
+                Message::new(1, b"before"),
+                Message::new(2, &level),
+                Message::new(3, b"after")
+            ]
+        );
+
+        assert_eq!(
+            sender.send(
+                RELIABLE_CHANNEL,
+                Message::new(0, &vec![0; MAX_BLOCK_BYTES + 1])
+            ),
+            Err(SendError::TooLarge)
+        );
+        let too_many = fragment_bytes * config.queue_limit + 1;
+        assert_eq!(
+            sender.send(RELIABLE_CHANNEL, Message::new(0, &vec![0; too_many])),
+            Err(SendError::TooLarge)
+        );
+    }
+}
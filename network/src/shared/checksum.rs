@@ -0,0 +1,119 @@
+pub const CHECKSUM_BYTES: usize = 4;
+
+/// Identifies the game and build on the wire. Neither value is sent: both are
+/// mixed into the packet checksum, so packets of other games or builds fail
+/// verification like corrupted ones do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Protocol {
+    pub id: u64,
+    pub version: u32,
+}
+
+pub const PROTOCOL: Protocol = Protocol {
+    id: 0x6e65_7463_6f64_6521,
+    version: 1,
+};
+
+impl Protocol {
+    pub fn checksum(&self, payload: &[u8]) -> u32 {
+        let mut crc = crc32_update(CRC32_INIT, &self.id.to_le_bytes());
+        crc = crc32_update(crc, &self.version.to_le_bytes());
+        crc = crc32_update(crc, payload);
+        !crc
+    }
+
+    /// Writes the checksum followed by `payload` into `packet`.
+    pub fn seal(&self, payload: &[u8], packet: &mut Vec<u8>) {
+        packet.clear();
+        packet.extend_from_slice(&self.checksum(payload).to_le_bytes());
+        packet.extend_from_slice(payload);
+    }
+
+    /// Returns the payload of `packet` if its checksum matches.
+    pub fn open<'a>(&self, packet: &'a [u8]) -> Option<&'a [u8]> {
+        if packet.len() < CHECKSUM_BYTES {
+            return None;
+        }
+        let (checksum, payload) = packet.split_at(CHECKSUM_BYTES);
+        let mut expected = [0u8; CHECKSUM_BYTES];
+        expected.copy_from_slice(checksum);
+        match u32::from_le_bytes(expected) == self.checksum(payload) {
+            true => Some(payload),
+            false => None,
+        }
+    }
+}
+
+const CRC32_INIT: u32 = 0xffff_ffff;
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, Protocol, CHECKSUM_BYTES, PROTOCOL};
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn seal_open() {
+        let payload = b"payload";
+        let mut packet = Vec::new();
+        PROTOCOL.seal(payload, &mut packet);
+        assert_eq!(packet.len(), payload.len() + CHECKSUM_BYTES);
+        assert_eq!(PROTOCOL.open(&packet), Some(&payload[..]));
+
+        let old_build = Protocol {
+            version: PROTOCOL.version - 1,
+            ..PROTOCOL
+        };
+        assert_eq!(old_build.open(&packet), None);
+
+        let other_game = Protocol {
+            id: PROTOCOL.id + 1,
+            ..PROTOCOL
+        };
+        assert_eq!(other_game.open(&packet), None);
+
+        for bit in 0..packet.len() * 8 {
+            let mut corrupted = packet.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(PROTOCOL.open(&corrupted), None, "bit {}", bit);
+        }
+
+        assert_eq!(PROTOCOL.open(&packet[..CHECKSUM_BYTES - 1]), None);
+    }
+}
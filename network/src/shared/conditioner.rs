@@ -0,0 +1,488 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::TryRecvError;
+use std::time;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::socketio::Packet;
+use super::transport::Transport;
+
+/// Extra delay of packets picked for reordering, so that the ones received
+/// right after them are delivered first.
+const REORDER_DELAY: time::Duration = time::Duration::from_millis(50);
+
+/// Packets that would wait longer than this for the bandwidth cap are dropped,
+/// like a full router queue would.
+const MAX_BANDWIDTH_BACKLOG: time::Duration = time::Duration::from_secs(1);
+
+/// Simulated network conditions applied to incoming packets.
+/// Percentages are in `[0, 100]`, a `bandwidth_kbps` of 0 means no cap.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ConditionerConfig {
+    pub latency_ms: u32,
+    pub jitter_ms: u32,
+    pub loss_percent: f32,
+    pub duplicate_percent: f32,
+    pub reorder_percent: f32,
+    pub bandwidth_kbps: u32,
+    pub seed: u64,
+}
+
+struct Delayed {
+    deliver_at: time::Instant,
+    order: u64,
+    packet: Packet,
+}
+
+impl Delayed {
+    fn key(&self) -> (time::Instant, u64) {
+        (self.deliver_at, self.order)
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Transport wrapper that loses, duplicates, delays and reorders received
+/// packets. Without a config it passes everything straight through.
+pub struct Conditioner {
+    inner: Box<dyn Transport>,
+    config: Option<ConditionerConfig>,
+    rng: ChaCha8Rng,
+    queue: BinaryHeap<Reverse<Delayed>>,
+    order: u64,
+    link_free_at: Option<time::Instant>,
+    dropped: u64,
+}
+
+impl Conditioner {
+    pub fn new(inner: Box<dyn Transport>) -> Conditioner {
+        Conditioner {
+            inner,
+            config: None,
+            rng: ChaCha8Rng::seed_from_u64(0),
+            queue: BinaryHeap::new(),
+            order: 0,
+            link_free_at: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn config(&self) -> Option<ConditionerConfig> {
+        self.config
+    }
+
+    /// Replaces the simulated conditions and restarts the RNG from the config
+    /// seed. `None` turns the conditioner off; packets already held back are
+    /// then delivered on the next receive.
+    pub fn set_config(&mut self, config: Option<ConditionerConfig>) {
+        if let Some(config) = config {
+            self.rng = ChaCha8Rng::seed_from_u64(config.seed);
+        }
+        self.config = config;
+        self.link_free_at = None;
+    }
+
+    /// Number of packets lost on purpose.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped
+    }
+
+    fn roll(&mut self, percent: f32) -> bool {
+        percent > 0.0 && self.rng.gen::<f32>() * 100.0 < percent
+    }
+
+    fn enqueue(&mut self, deliver_at: time::Instant, packet: Packet) {
+        self.order += 1;
+        self.queue.push(Reverse(Delayed {
+            deliver_at,
+            order: self.order,
+            packet,
+        }));
+    }
+
+    fn lose(&mut self, packet: Packet) {
+        self.dropped += 1;
+        self.inner.recycle(packet);
+    }
+
+    fn condition(&mut self, config: ConditionerConfig, packet: Packet, now: time::Instant) {
+        if self.roll(config.loss_percent) {
+            return self.lose(packet);
+        }
+
+        let mut arrival = now;
+        if config.bandwidth_kbps > 0 {
+            let start = self.link_free_at.map_or(now, |free| free.max(now));
+            if start - now > MAX_BANDWIDTH_BACKLOG {
+                return self.lose(packet);
+            }
+            let bits = (packet.nbytes * 8) as u64;
+            let transmit = time::Duration::from_micros(bits * 1000 / config.bandwidth_kbps as u64);
+            arrival = start + transmit;
+            self.link_free_at = Some(arrival);
+        }
+
+        if self.roll(config.duplicate_percent) {
+            let mut buffer = self.inner.acquire();
+            if buffer.len() < packet.nbytes {
+                buffer.resize(packet.nbytes, 0);
+            }
+            buffer[..packet.nbytes].copy_from_slice(&packet.buffer[..packet.nbytes]);
+            let duplicate = Packet { buffer, ..packet };
+            self.schedule(config, arrival, duplicate);
+        }
+        self.schedule(config, arrival, packet);
+    }
+
+    fn schedule(&mut self, config: ConditionerConfig, arrival: time::Instant, packet: Packet) {
+        let jitter = match config.jitter_ms as i64 {
+            0 => 0,
+            jitter => self.rng.gen_range(-jitter..=jitter),
+        };
+        let delay_ms = (config.latency_ms as i64 + jitter).max(0) as u64;
+        let mut deliver_at = arrival + time::Duration::from_millis(delay_ms);
+        if self.roll(config.reorder_percent) {
+            deliver_at += REORDER_DELAY;
+        }
+        self.enqueue(deliver_at, packet);
+    }
+
+    fn try_recv_at(&mut self, now: time::Instant) -> Result<Packet, TryRecvError> {
+        let mut disconnected = false;
+        loop {
+            match self.inner.try_recv() {
+                Ok(packet) => match self.config {
+                    Some(config) => self.condition(config, packet, now),
+                    None => self.enqueue(now, packet),
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        match self.queue.peek() {
+            Some(Reverse(next)) if self.config.is_none() || next.deliver_at <= now => {
+                Ok(self.queue.pop().unwrap().0.packet)
+            }
+            _ if disconnected && self.queue.is_empty() => Err(TryRecvError::Disconnected),
+            _ => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl Transport for Conditioner {
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+
+    fn send(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        self.inner.send(buf, dest)
+    }
+
+    fn send_batch(&mut self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        self.inner.send_batch(packets)
+    }
+
+    fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
+        self.try_recv_at(time::Instant::now())
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.inner.take_error()
+    }
+
+    fn rejected_packets(&self) -> u64 {
+        self.inner.rejected_packets()
+    }
+
+    fn recycle(&mut self, packet: Packet) {
+        self.inner.recycle(packet)
+    }
+
+    fn acquire(&mut self) -> Vec<u8> {
+        self.inner.acquire()
+    }
+
+    fn pool_exhausted(&self) -> u64 {
+        self.inner.pool_exhausted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Conditioner, ConditionerConfig, REORDER_DELAY};
+    use crate::shared::{
+        pool::BufferPool,
+        socketio::Packet,
+        transport::{MemoryTransport, Transport},
+    };
+    use rand::Rng;
+    use std::{io, net::SocketAddr, sync::mpsc::TryRecvError, time};
+
+    fn conditioned(config: Option<ConditionerConfig>) -> (MemoryTransport, Conditioner) {
+        let (sender, receiver) = MemoryTransport::pair();
+        let mut conditioner = Conditioner::new(Box::new(receiver));
+        conditioner.set_config(config);
+        (sender, conditioner)
+    }
+
+    fn drain(conditioner: &mut Conditioner, now: time::Instant) -> Vec<u8> {
+        let mut received = Vec::new();
+        while let Ok(packet) = conditioner.try_recv_at(now) {
+            received.push(packet.buffer[0]);
+        }
+        received
+    }
+
+    #[test]
+    fn pass_through() {
+        let (mut sender, mut conditioner) = conditioned(None);
+        let dest = conditioner.local_addr();
+        for i in 0..10u8 {
+            sender.send(&[i], dest).unwrap();
+        }
+        let received = drain(&mut conditioner, time::Instant::now());
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+        assert!(matches!(conditioner.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn latency() {
+        let config = ConditionerConfig {
+            latency_ms: 100,
+            ..Default::default()
+        };
+        let (mut sender, mut conditioner) = conditioned(Some(config));
+        let dest = conditioner.local_addr();
+        sender.send(&[1], dest).unwrap();
+
+        let now = time::Instant::now();
+        assert!(drain(&mut conditioner, now).is_empty());
+        let early = now + time::Duration::from_millis(99);
+        assert!(drain(&mut conditioner, early).is_empty());
+        let late = now + time::Duration::from_millis(100);
+        assert_eq!(drain(&mut conditioner, late), vec![1]);
+
+        // switching off releases whatever is still held back.
+        sender.send(&[2], dest).unwrap();
+        assert!(drain(&mut conditioner, now).is_empty());
+        conditioner.set_config(None);
+        assert_eq!(drain(&mut conditioner, now), vec![2]);
+    }
+
+    #[test]
+    fn huge_jitter() {
+        let config = ConditionerConfig {
+            latency_ms: u32::MAX,
+            jitter_ms: u32::MAX,
+            seed: 3,
+            ..Default::default()
+        };
+        let (mut sender, mut conditioner) = conditioned(Some(config));
+        let dest = conditioner.local_addr();
+        for i in 0..10u8 {
+            sender.send(&[i], dest).unwrap();
+        }
+        // all of them are held back, for up to twice the latency.
+        let now = time::Instant::now();
+        assert!(drain(&mut conditioner, now).is_empty());
+        let latest = now + time::Duration::from_millis(u32::MAX as u64 * 2);
+        let mut received = drain(&mut conditioner, latest);
+        received.sort_unstable();
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn loss_and_duplicates() {
+        let seed: u64 = rand::thread_rng().gen();
+        println!("seed: {}", seed);
+
+        let config = ConditionerConfig {
+            loss_percent: 25.0,
+            duplicate_percent: 25.0,
+            seed,
+            ..Default::default()
+        };
+        let (mut sender, mut conditioner) = conditioned(Some(config));
+        let dest = conditioner.local_addr();
+        const COUNT: usize = 4000;
+        for i in 0..COUNT {
+            sender.send(&[i as u8], dest).unwrap();
+        }
+        let received = drain(&mut conditioner, time::Instant::now());
+        let lost = conditioner.dropped_packets() as usize;
+        let duplicated = received.len() + lost - COUNT;
+        assert!(lost > COUNT / 5 && lost < COUNT * 3 / 10, "lost {}", lost);
+        let kept = COUNT - lost;
+        assert!(
+            duplicated > kept / 5 && duplicated < kept * 3 / 10,
+            "duplicated {}",
+            duplicated
+        );
+    }
+
+    #[test]
+    fn reproducible() {
+        let config = ConditionerConfig {
+            latency_ms: 20,
+            jitter_ms: 10,
+            loss_percent: 10.0,
+            duplicate_percent: 10.0,
+            reorder_percent: 10.0,
+            seed: 7,
+            ..Default::default()
+        };
+        let run = || {
+            let (mut sender, mut conditioner) = conditioned(Some(config));
+            let dest = conditioner.local_addr();
+            let now = time::Instant::now();
+            for i in 0..200u8 {
+                sender.send(&[i], dest).unwrap();
+            }
+            drain(&mut conditioner, now);
+            drain(&mut conditioner, now + time::Duration::from_secs(1))
+        };
+        let first = run();
+        assert_eq!(first, run());
+        // jitter and reordering mix up the arrival order.
+        assert!(first.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn reorder() {
+        let config = ConditionerConfig {
+            reorder_percent: 100.0,
+            ..Default::default()
+        };
+        let (mut sender, mut conditioner) = conditioned(Some(config));
+        let dest = conditioner.local_addr();
+        let now = time::Instant::now();
+        sender.send(&[1], dest).unwrap();
+        assert!(drain(&mut conditioner, now).is_empty());
+
+        conditioner.set_config(Some(ConditionerConfig::default()));
+        sender.send(&[2], dest).unwrap();
+        assert_eq!(drain(&mut conditioner, now), vec![2]);
+        assert_eq!(drain(&mut conditioner, now + REORDER_DELAY), vec![1]);
+    }
+
+    #[test]
+    fn bandwidth() {
+        // 8 kbps moves one 100 byte packet every 100ms.
+        let config = ConditionerConfig {
+            bandwidth_kbps: 8,
+            ..Default::default()
+        };
+        let (mut sender, mut conditioner) = conditioned(Some(config));
+        let dest = conditioner.local_addr();
+        for i in 0..20u8 {
+            sender.send(&[i; 100], dest).unwrap();
+        }
+        let now = time::Instant::now();
+        assert!(drain(&mut conditioner, now).is_empty());
+        let received = drain(&mut conditioner, now + time::Duration::from_millis(250));
+        assert_eq!(received, vec![0, 1]);
+
+        // anything queued for longer than a second is dropped.
+        let received = drain(&mut conditioner, now + time::Duration::from_secs(5));
+        assert_eq!(received, (2..11).collect::<Vec<u8>>());
+        assert_eq!(conditioner.dropped_packets(), 9);
+    }
+
+    // a memory transport with a buffer pool, like the socket has.
+    struct Pooled {
+        inner: MemoryTransport,
+        pool: BufferPool,
+    }
+
+    impl Transport for Pooled {
+        fn local_addr(&self) -> SocketAddr {
+            self.inner.local_addr()
+        }
+
+        fn send(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+            self.inner.send(buf, dest)
+        }
+
+        fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
+            let mut packet = self.inner.try_recv()?;
+            let mut buffer = self.pool.acquire();
+            buffer[..packet.nbytes].copy_from_slice(&packet.buffer);
+            packet.buffer = buffer;
+            Ok(packet)
+        }
+
+        fn recycle(&mut self, packet: Packet) {
+            self.pool.release(packet.buffer);
+        }
+
+        fn acquire(&mut self) -> Vec<u8> {
+            self.pool.acquire()
+        }
+
+        fn pool_exhausted(&self) -> u64 {
+            self.pool.exhausted()
+        }
+    }
+
+    #[test]
+    fn recycle() {
+        let config = ConditionerConfig {
+            loss_percent: 30.0,
+            duplicate_percent: 30.0,
+            bandwidth_kbps: 80,
+            ..Default::default()
+        };
+        let (mut sender, receiver) = MemoryTransport::pair();
+        let pool = BufferPool::new(16, 100);
+        let mut conditioner = Conditioner::new(Box::new(Pooled {
+            inner: receiver,
+            pool: pool.clone(),
+        }));
+        conditioner.set_config(Some(config));
+        let dest = conditioner.local_addr();
+
+        // lost, duplicated and queued too long, every buffer comes back.
+        let now = time::Instant::now();
+        for round in 0..=20u64 {
+            // the last round only delivers what is still on the link.
+            for i in (0..4u8).filter(|_| round < 20) {
+                sender.send(&[i; 100], dest).unwrap();
+            }
+            let later = now + time::Duration::from_secs(round * 10);
+            while let Ok(packet) = conditioner.try_recv_at(later) {
+                assert_eq!(packet.buffer[..100], [packet.buffer[0]; 100]);
+                conditioner.recycle(packet);
+            }
+        }
+        assert!(conditioner.dropped_packets() > 0);
+        assert_eq!(pool.available(), 16);
+        assert_eq!(conditioner.pool_exhausted(), 0);
+    }
+}
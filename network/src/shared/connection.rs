@@ -0,0 +1,863 @@
+use super::bits::Error;
+use super::channel::{self, ChannelId, Channels, Message, SendError};
+use super::crypto::{PacketCipher, ENCRYPTION_BYTES};
+use super::fragment::{self, Reassembly, MAX_PACKET_BYTES};
+use super::mtu::{self, MtuProbe};
+use super::packet::{Delivery, Endpoint, PacketHeader, PacketType, HEADER_BYTES};
+use super::sequence::Sequence;
+use std::{borrow::Cow, collections::HashMap, io, net::SocketAddr, time};
+
+/// How far a new sample moves the smoothed estimates.
+const SMOOTHING: f32 = 0.1;
+
+/// Bandwidth is measured over intervals of this length.
+const BANDWIDTH_INTERVAL: time::Duration = time::Duration::from_millis(250);
+
+/// Connections that sent nothing for this long send an empty packet.
+pub const KEEPALIVE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Connections that received nothing for this long are closed.
+pub const CONNECTION_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Number of disconnect packets sent, in case some get lost.
+const DISCONNECT_PACKETS: usize = 3;
+
+/// Link quality of one connection, see `server_connection_stats`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Smoothed round-trip time.
+    pub rtt_ms: f32,
+    /// Smoothed deviation of round-trip samples from `rtt_ms`.
+    pub jitter_ms: f32,
+    /// Smoothed share of sent packets that were lost.
+    pub packet_loss_percent: f32,
+    pub sent_kbps: f32,
+    pub received_kbps: f32,
+    /// Bandwidth of the sent packets that were acknowledged.
+    pub acked_kbps: f32,
+    pub sent_packets: u64,
+    pub received_packets: u64,
+    pub acked_packets: u64,
+    pub lost_packets: u64,
+    /// Largest datagram sent without fragmenting, raised by `probe_mtu`.
+    pub max_packet_bytes: u32,
+}
+
+fn smooth(value: &mut f32, sample: f32) {
+    *value += (sample - *value) * SMOOTHING;
+}
+
+/// Packet acknowledgement, message channels and link statistics for one
+/// remote peer.
+pub struct Connection {
+    endpoint: Endpoint,
+    channels: Channels,
+    // received reliable messages that were not acknowledged yet.
+    ack_pending: bool,
+    reassembly: Reassembly,
+    max_packet_bytes: usize,
+    mtu_probe: Option<MtuProbe>,
+    // sizes of received probes that were not acknowledged yet.
+    probe_acks: Vec<usize>,
+    cipher: Option<PacketCipher>,
+    last_sent: time::Instant,
+    last_received: time::Instant,
+    disconnected: bool,
+    stats: ConnectionStats,
+    has_rtt: bool,
+    interval_start: time::Instant,
+    sent_bytes: usize,
+    received_bytes: usize,
+    acked_bytes: usize,
+}
+
+impl Connection {
+    pub fn new(now: time::Instant) -> Connection {
+        Connection {
+            endpoint: Endpoint::new(),
+            channels: Channels::default(),
+            ack_pending: false,
+            reassembly: Reassembly::default(),
+            max_packet_bytes: MAX_PACKET_BYTES,
+            mtu_probe: None,
+            probe_acks: Vec::new(),
+            cipher: None,
+            last_sent: now,
+            last_received: now,
+            disconnected: false,
+            stats: ConnectionStats {
+                max_packet_bytes: MAX_PACKET_BYTES as u32,
+                ..ConnectionStats::default()
+            },
+            has_rtt: false,
+            interval_start: now,
+            sent_bytes: 0,
+            received_bytes: 0,
+            acked_bytes: 0,
+        }
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Largest datagram sent before packets are split into fragments.
+    pub fn max_packet_bytes(&self) -> usize {
+        self.max_packet_bytes
+    }
+
+    pub fn set_max_packet_bytes(&mut self, max_packet_bytes: usize) {
+        self.max_packet_bytes = max_packet_bytes;
+        self.stats.max_packet_bytes = max_packet_bytes as u32;
+    }
+
+    /// Encrypts every datagram sent from now on and only accepts encrypted
+    /// ones, see `PacketCipher`.
+    pub fn encrypt(&mut self, cipher: PacketCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    // bytes encryption adds to every datagram.
+    fn overhead(&self) -> usize {
+        self.cipher.as_ref().map_or(0, |_| ENCRYPTION_BYTES)
+    }
+
+    fn seal(&mut self, datagrams: &mut [Vec<u8>]) {
+        if let Some(cipher) = &mut self.cipher {
+            for datagram in datagrams {
+                cipher.seal(datagram);
+            }
+        }
+    }
+
+    /// True once the other side sent a disconnect packet.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// True if nothing was received for `CONNECTION_TIMEOUT`.
+    pub fn is_timed_out(&self, now: time::Instant) -> bool {
+        now.saturating_duration_since(self.last_received) >= CONNECTION_TIMEOUT
+    }
+
+    /// True if nothing was sent for `KEEPALIVE_INTERVAL`.
+    pub fn needs_keepalive(&self, now: time::Instant) -> bool {
+        now.saturating_duration_since(self.last_sent) >= KEEPALIVE_INTERVAL
+    }
+
+    /// Writes the datagrams that tell the other side the connection ends.
+    pub fn write_disconnect(&mut self, datagrams: &mut Vec<Vec<u8>>) {
+        datagrams.clear();
+        datagrams.resize(DISCONNECT_PACKETS, vec![PacketType::Disconnect as u8]);
+        self.seal(datagrams);
+    }
+
+    fn resend_delay(&self) -> time::Duration {
+        let rtt = time::Duration::from_secs_f32(self.stats.rtt_ms / 1000.0);
+        channel::resend_delay(Some(rtt).filter(|_| self.has_rtt))
+    }
+
+    pub fn send_message(&mut self, channel: ChannelId, message: Message) -> Result<(), SendError> {
+        self.channels.send(channel, message)
+    }
+
+    pub fn receive_message(&mut self, channel: ChannelId) -> Option<Message> {
+        self.channels.receive(channel)
+    }
+
+    /// True if there are messages to send or reliable messages to acknowledge.
+    pub fn has_pending(&self, now: time::Instant) -> bool {
+        self.ack_pending || self.channels.has_pending(now, self.resend_delay())
+    }
+
+    /// Writes a packet header, the queued channel messages and `payload`,
+    /// see `Endpoint::write_packet`.
+    pub fn write_packet(
+        &mut self,
+        payload: &[u8],
+        now: time::Instant,
+        packet: &mut Vec<u8>,
+    ) -> Sequence {
+        let sequence = self.endpoint.next_sequence();
+        let (_, mut body) = self
+            .channels
+            .write(sequence, now, self.resend_delay())
+            .expect("growable writer");
+        body.extend_from_slice(payload);
+        self.endpoint.write_packet(&body, now, packet);
+        self.ack_pending = false;
+        self.last_sent = now;
+        self.stats.sent_packets += 1;
+        self.sent_bytes += packet.len();
+        self.update(now);
+        sequence
+    }
+
+    /// Reads the packet header and the channel messages, and updates the
+    /// statistics with its acks. Returns the header and the payload after
+    /// the messages. Nothing is changed if the packet cannot be read.
+    pub fn read_packet<'a>(
+        &mut self,
+        packet: &'a [u8],
+        now: time::Instant,
+    ) -> Result<(PacketHeader, &'a [u8]), Error> {
+        let body = packet.get(HEADER_BYTES..).unwrap_or_default();
+        let (incoming, offset) = Channels::read(body, self.channels.configs())?;
+        let (header, body) = self.endpoint.read_packet(packet, now)?;
+        self.ack_pending |= self.channels.deliver(incoming);
+        self.stats.received_packets += 1;
+        self.received_bytes += packet.len();
+
+        let deliveries: Vec<_> = self.endpoint.drain_deliveries().collect();
+        for delivery in deliveries {
+            match delivery {
+                Delivery::Acked(sequence) => {
+                    self.channels.on_acked(sequence);
+                    let sent = *self.endpoint.sent_packet(sequence).unwrap();
+                    self.on_acked(now.saturating_duration_since(sent.send_time), sent.bytes);
+                }
+                Delivery::Lost(_) => {
+                    self.stats.lost_packets += 1;
+                    smooth(&mut self.stats.packet_loss_percent, 100.0);
+                }
+            }
+        }
+        self.update(now);
+        Ok((header, &body[offset..]))
+    }
+
+    /// Writes a packet like `write_packet` and turns it into datagrams of at
+    /// most `max_packet_bytes`, fragmenting it if needed.
+    pub fn write_datagrams(
+        &mut self,
+        payload: &[u8],
+        now: time::Instant,
+        datagrams: &mut Vec<Vec<u8>>,
+    ) -> Result<Sequence, Error> {
+        let mut packet = Vec::new();
+        let sequence = self.write_packet(payload, now, &mut packet);
+        let max_bytes = self.max_packet_bytes - self.overhead();
+        fragment::write_datagrams(&packet, sequence, max_bytes, datagrams)?;
+        self.seal(datagrams);
+        Ok(sequence)
+    }
+
+    /// Reads a datagram written by `write_datagrams`. Returns the payload of
+    /// the packet, or `None` while fragments of it are missing.
+    pub fn read_datagram<'a>(
+        &mut self,
+        datagram: &'a [u8],
+        now: time::Instant,
+    ) -> Result<Option<Cow<'a, [u8]>>, Error> {
+        let payload = match &mut self.cipher {
+            Some(cipher) => {
+                let opened = cipher.open(datagram)?;
+                let payload = self.read_opened(&opened, now)?;
+                payload.map(|payload| Cow::Owned(payload.into_owned()))
+            }
+            None => self.read_opened(datagram, now)?,
+        };
+        self.last_received = now;
+        Ok(payload)
+    }
+
+    // reads a datagram that is not or no longer encrypted.
+    fn read_opened<'a>(
+        &mut self,
+        datagram: &'a [u8],
+        now: time::Instant,
+    ) -> Result<Option<Cow<'a, [u8]>>, Error> {
+        let (&kind, rest) = datagram.split_first().ok_or(Error::OutOfMemory)?;
+        match PacketType::from_byte(kind) {
+            Some(PacketType::Payload) => {
+                let (_, payload) = self.read_packet(rest, now)?;
+                Ok(Some(Cow::Borrowed(payload)))
+            }
+            Some(PacketType::Fragment) => match self.reassembly.insert(rest, now)? {
+                Some(packet) => {
+                    let (_, payload) = self.read_packet(&packet, now)?;
+                    Ok(Some(Cow::Owned(payload.to_vec())))
+                }
+                None => Ok(None),
+            },
+            Some(PacketType::Probe) => {
+                self.probe_acks
+                    .push(mtu::read_size(PacketType::Probe, rest)?);
+                Ok(None)
+            }
+            Some(PacketType::ProbeAck) => {
+                // probes are sent that much smaller to fit once encrypted.
+                let size = mtu::read_size(PacketType::ProbeAck, rest)? + self.overhead();
+                if let Some(probe) = &mut self.mtu_probe {
+                    probe.on_ack(size);
+                    if let Some(result) = probe.result() {
+                        self.set_max_packet_bytes(result);
+                        self.mtu_probe = None;
+                    }
+                }
+                Ok(None)
+            }
+            Some(PacketType::Disconnect) => {
+                self.disconnected = true;
+                Ok(None)
+            }
+            // handshake packets are handled before there is a connection.
+            _ => Err(Error::ValueOutOfBounds),
+        }
+    }
+
+    /// Starts sending probes of decreasing size. The largest one that is
+    /// acknowledged becomes `max_packet_bytes`, if none is the limit stays.
+    pub fn probe_mtu(&mut self) {
+        self.mtu_probe = Some(MtuProbe::new());
+    }
+
+    pub fn is_probing_mtu(&self) -> bool {
+        self.mtu_probe.is_some()
+    }
+
+    /// Writes the probe due at `now` and the acks for received probes.
+    pub fn write_probes(&mut self, now: time::Instant, datagrams: &mut Vec<Vec<u8>>) {
+        let overhead = self.overhead();
+        datagrams.clear();
+        for size in self.probe_acks.drain(..) {
+            let mut datagram = Vec::new();
+            mtu::write_probe_ack(size, &mut datagram);
+            datagrams.push(datagram);
+        }
+        if let Some(probe) = &mut self.mtu_probe {
+            if let Some(size) = probe.poll(now) {
+                let mut datagram = Vec::new();
+                mtu::write_probe(size - overhead, &mut datagram);
+                datagrams.push(datagram);
+            } else if probe.is_done() {
+                self.mtu_probe = None;
+            }
+        }
+        self.seal(datagrams);
+    }
+
+    fn on_acked(&mut self, rtt: time::Duration, bytes: usize) {
+        let sample = rtt.as_secs_f32() * 1000.0;
+        if self.has_rtt {
+            smooth(
+                &mut self.stats.jitter_ms,
+                (sample - self.stats.rtt_ms).abs(),
+            );
+            smooth(&mut self.stats.rtt_ms, sample);
+        } else {
+            self.stats.rtt_ms = sample;
+            self.has_rtt = true;
+        }
+        self.stats.acked_packets += 1;
+        self.acked_bytes += bytes;
+        smooth(&mut self.stats.packet_loss_percent, 0.0);
+    }
+
+    /// Updates the bandwidth estimates once per measurement interval.
+    pub fn update(&mut self, now: time::Instant) {
+        let elapsed = now.saturating_duration_since(self.interval_start);
+        if elapsed < BANDWIDTH_INTERVAL {
+            return;
+        }
+        let kbps = |bytes: usize| bytes as f32 * 8.0 / 1000.0 / elapsed.as_secs_f32();
+        smooth(&mut self.stats.sent_kbps, kbps(self.sent_bytes));
+        smooth(&mut self.stats.received_kbps, kbps(self.received_bytes));
+        smooth(&mut self.stats.acked_kbps, kbps(self.acked_bytes));
+        self.interval_start = now;
+        self.sent_bytes = 0;
+        self.received_bytes = 0;
+        self.acked_bytes = 0;
+    }
+}
+
+/// Connections keyed by the address of the remote peer.
+#[derive(Default)]
+pub struct Connections {
+    connections: HashMap<SocketAddr, Connection>,
+    datagrams: Vec<Vec<u8>>,
+}
+
+impl Connections {
+    pub fn get(&self, addr: SocketAddr) -> Option<&Connection> {
+        self.connections.get(&addr)
+    }
+
+    pub fn get_mut(&mut self, addr: SocketAddr) -> Option<&mut Connection> {
+        self.connections.get_mut(&addr)
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) -> Option<Connection> {
+        self.connections.remove(&addr)
+    }
+
+    /// Addresses of the connections that were disconnected by the other
+    /// side or timed out at `now`.
+    pub fn closed(&self, now: time::Instant) -> Vec<SocketAddr> {
+        self.connections
+            .iter()
+            .filter(|(_, connection)| connection.is_disconnected() || connection.is_timed_out(now))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Starts a new connection with `addr`, replacing any previous one.
+    pub fn insert(&mut self, addr: SocketAddr, now: time::Instant) -> &mut Connection {
+        let connection = self
+            .connections
+            .entry(addr)
+            .or_insert_with(|| Connection::new(now));
+        *connection = Connection::new(now);
+        connection
+    }
+
+    /// Returns the datagrams carrying `payload` to `dest`, see
+    /// `Connection::write_datagrams`.
+    pub fn write_datagrams(
+        &mut self,
+        payload: &[u8],
+        dest: SocketAddr,
+        now: time::Instant,
+    ) -> Result<&[Vec<u8>], Error> {
+        let connection = self
+            .connections
+            .entry(dest)
+            .or_insert_with(|| Connection::new(now));
+        connection.write_datagrams(payload, now, &mut self.datagrams)?;
+        Ok(&self.datagrams)
+    }
+
+    /// Reads a datagram received from `addr`, see `Connection::read_datagram`.
+    /// A connection is only created for datagrams that could be read.
+    pub fn read_datagram<'a>(
+        &mut self,
+        datagram: &'a [u8],
+        addr: SocketAddr,
+        now: time::Instant,
+    ) -> Result<Option<Cow<'a, [u8]>>, Error> {
+        let is_new = !self.connections.contains_key(&addr);
+        let connection = self
+            .connections
+            .entry(addr)
+            .or_insert_with(|| Connection::new(now));
+        let result = connection.read_datagram(datagram, now);
+        if result.is_err() && is_new {
+            self.connections.remove(&addr);
+        }
+        result
+    }
+
+    /// Queues `message` for `dest`. It goes out with the next packet.
+    pub fn send_message(
+        &mut self,
+        dest: SocketAddr,
+        channel: ChannelId,
+        message: Message,
+        now: time::Instant,
+    ) -> Result<(), SendError> {
+        self.connections
+            .entry(dest)
+            .or_insert_with(|| Connection::new(now))
+            .send_message(channel, message)
+    }
+
+    pub fn receive_message(&mut self, from: SocketAddr, channel: ChannelId) -> Option<Message> {
+        self.connections.get_mut(&from)?.receive_message(channel)
+    }
+
+    pub fn update(&mut self, now: time::Instant) {
+        for connection in self.connections.values_mut() {
+            connection.update(now);
+        }
+    }
+
+    /// Starts probing the path MTU to `dest`, see `Connection::probe_mtu`.
+    /// Returns false if there is no connection with `dest`.
+    pub fn probe_mtu(&mut self, dest: SocketAddr) -> bool {
+        match self.connections.get_mut(&dest) {
+            Some(connection) => {
+                connection.probe_mtu();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends pending mtu probes and their acks, and a packet without payload
+    /// to every connection with pending messages or acks, or that needs a
+    /// keepalive.
+    pub fn flush<F>(&mut self, now: time::Instant, mut send: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], SocketAddr) -> io::Result<usize>,
+    {
+        for (addr, connection) in &mut self.connections {
+            connection.write_probes(now, &mut self.datagrams);
+            for datagram in &self.datagrams {
+                send(datagram, *addr)?;
+            }
+            if connection.has_pending(now) || connection.needs_keepalive(now) {
+                connection
+                    .write_datagrams(&[], now, &mut self.datagrams)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                for datagram in &self.datagrams {
+                    send(datagram, *addr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Connection, Connections, BANDWIDTH_INTERVAL, CONNECTION_TIMEOUT, KEEPALIVE_INTERVAL,
+    };
+    use crate::shared::channel::{Message, RELIABLE_CHANNEL, UNRELIABLE_CHANNEL};
+    use crate::shared::crypto::PacketCipher;
+    use crate::shared::fragment::MAX_PACKET_BYTES;
+    use crate::shared::packet::PacketType;
+    use std::{net::SocketAddr, time};
+
+    #[test]
+    fn rtt_and_loss() {
+        let start = time::Instant::now();
+        let (mut client, mut server) = (Connection::new(start), Connection::new(start));
+        let mut packet = Vec::new();
+
+        // every 4th packet is lost, replies arrive 50ms after a 10ms tick.
+        let tick = time::Duration::from_millis(10);
+        let rtt = time::Duration::from_millis(50);
+        const COUNT: u32 = 2000;
+        for i in 0..COUNT {
+            let now = start + tick * i;
+            client.write_packet(b"ping", now, &mut packet);
+            if i % 4 != 0 {
+                server.read_packet(&packet, now).unwrap();
+            }
+            server.write_packet(b"pong", now, &mut packet);
+            client.read_packet(&packet, now + rtt).unwrap();
+        }
+
+        let stats = client.stats();
+        assert!((stats.rtt_ms - 50.0).abs() < 0.1, "{:?}", stats);
+        assert!(stats.jitter_ms < 0.1);
+        assert!((stats.packet_loss_percent - 25.0).abs() < 10.0);
+        assert_eq!(stats.sent_packets, COUNT as u64);
+        assert_eq!(stats.received_packets, COUNT as u64);
+        assert_eq!(stats.acked_packets, COUNT as u64 / 4 * 3);
+        // the last ack window is still undecided.
+        assert!(stats.lost_packets >= COUNT as u64 / 4 - 8);
+        assert!(stats.lost_packets < COUNT as u64 / 4);
+
+        // received bandwidth settles at 13 bytes per 10ms.
+        assert!((stats.received_kbps - 10.4).abs() < 0.5, "{:?}", stats);
+        assert!((stats.sent_kbps - 10.4).abs() < 0.5);
+        assert!((stats.acked_kbps - 7.8).abs() < 0.5);
+    }
+
+    #[test]
+    fn jitter() {
+        let start = time::Instant::now();
+        let (mut client, mut server) = (Connection::new(start), Connection::new(start));
+        let mut packet = Vec::new();
+        for i in 0..200u32 {
+            let now = start + BANDWIDTH_INTERVAL * i;
+            client.write_packet(b"", now, &mut packet);
+            server.read_packet(&packet, now).unwrap();
+            server.write_packet(b"", now, &mut packet);
+            let delay = time::Duration::from_millis(if i % 2 == 0 { 40 } else { 60 });
+            client.read_packet(&packet, now + delay).unwrap();
+        }
+        let stats = client.stats();
+        assert!((stats.rtt_ms - 50.0).abs() < 2.0, "{:?}", stats);
+        assert!((stats.jitter_ms - 10.0).abs() < 2.0, "{:?}", stats);
+        assert_eq!(stats.packet_loss_percent, 0.0);
+    }
+
+    #[test]
+    fn connections() {
+        let now = time::Instant::now();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let (mut client, mut server) = (Connections::default(), Connections::default());
+
+        assert!(server.read_datagram(b"\0short", addr, now).is_err());
+        assert!(server.read_datagram(b"", addr, now).is_err());
+        assert!(server.get(addr).is_none());
+
+        let datagrams = client.write_datagrams(b"hello", addr, now).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        let payload = server.read_datagram(&datagrams[0], addr, now).unwrap();
+        assert_eq!(payload.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(server.get(addr).unwrap().stats().received_packets, 1);
+        assert_eq!(client.get(addr).unwrap().stats().sent_packets, 1);
+    }
+
+    #[test]
+    fn duplicates() {
+        let now = time::Instant::now();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let (mut client, mut server) = (Connections::default(), Connections::default());
+        let message = Message::new(0, b"fx");
+        client
+            .send_message(addr, UNRELIABLE_CHANNEL, message.clone(), now)
+            .unwrap();
+        let datagram = client.write_datagrams(b"hello", addr, now).unwrap()[0].clone();
+
+        let payload = server.read_datagram(&datagram, addr, now).unwrap();
+        assert_eq!(payload.as_deref(), Some(&b"hello"[..]));
+        assert!(server.read_datagram(&datagram, addr, now).is_err());
+        assert_eq!(
+            server.receive_message(addr, UNRELIABLE_CHANNEL),
+            Some(message)
+        );
+        assert_eq!(server.receive_message(addr, UNRELIABLE_CHANNEL), None);
+        assert_eq!(server.get(addr).unwrap().stats().received_packets, 1);
+    }
+
+    #[test]
+    fn closing() {
+        let start = time::Instant::now();
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 2000));
+        let (mut client, mut server) = (Connections::default(), Connections::default());
+        client.insert(server_addr, start);
+        server.insert(client_addr, start);
+
+        // an idle connection is kept alive.
+        let flush = |client: &mut Connections, now| {
+            let mut sent = Vec::new();
+            client
+                .flush(now, |datagram, _| {
+                    sent.push(datagram.to_vec());
+                    Ok(datagram.len())
+                })
+                .unwrap();
+            sent
+        };
+        assert!(flush(&mut client, start).is_empty());
+        let now = start + KEEPALIVE_INTERVAL;
+        let sent = flush(&mut client, now);
+        assert_eq!(sent.len(), 1);
+        server.read_datagram(&sent[0], client_addr, now).unwrap();
+        assert!(server.closed(start + CONNECTION_TIMEOUT).is_empty());
+        assert_eq!(server.closed(now + CONNECTION_TIMEOUT), [client_addr]);
+
+        let mut datagrams = Vec::new();
+        let connection = client.get_mut(server_addr).unwrap();
+        connection.write_disconnect(&mut datagrams);
+        assert!(datagrams.len() > 1);
+        for datagram in &datagrams {
+            assert_eq!(server.read_datagram(datagram, client_addr, now), Ok(None));
+        }
+        assert!(server.get(client_addr).unwrap().is_disconnected());
+        assert_eq!(server.closed(now), [client_addr]);
+        assert!(server.remove(client_addr).is_some());
+        assert!(server.closed(now).is_empty());
+    }
+
+    #[test]
+    fn fragments() {
+        let now = time::Instant::now();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let (mut client, mut server) = (Connections::default(), Connections::default());
+        let snapshot: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        let datagrams = client
+            .write_datagrams(&snapshot, addr, now)
+            .unwrap()
+            .to_vec();
+        assert!(datagrams.len() > 1);
+        let (last, fragments) = datagrams.split_last().unwrap();
+        for fragment in fragments {
+            assert_eq!(server.read_datagram(fragment, addr, now).unwrap(), None);
+        }
+        let payload = server.read_datagram(last, addr, now).unwrap().unwrap();
+        assert_eq!(payload.as_ref(), &snapshot[..]);
+
+        // a smaller path mtu makes more fragments.
+        client.get_mut(addr).unwrap().set_max_packet_bytes(500);
+        let more = client.write_datagrams(&snapshot, addr, now).unwrap();
+        assert!(more.len() > datagrams.len());
+        assert!(more.iter().all(|datagram| datagram.len() <= 500));
+    }
+
+    #[test]
+    fn mtu_probe() {
+        let start = time::Instant::now();
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 2000));
+        let (mut client, mut server) = (Connections::default(), Connections::default());
+        client
+            .write_datagrams(b"hello", server_addr, start)
+            .unwrap();
+        assert!(!client.probe_mtu(client_addr));
+        assert!(client.probe_mtu(server_addr));
+
+        // the path drops every datagram above 1300 bytes.
+        let mut now = start;
+        let mut sent = Vec::new();
+        while client.get(server_addr).unwrap().is_probing_mtu() {
+            let mut to_server = Vec::new();
+            client
+                .flush(now, |datagram, _| {
+                    // keepalives go out as well.
+                    if datagram[0] == PacketType::Probe as u8 {
+                        sent.push(datagram.len());
+                    }
+                    to_server.push(datagram.to_vec());
+                    Ok(datagram.len())
+                })
+                .unwrap();
+            let mut to_client = Vec::new();
+            for datagram in to_server.iter().filter(|datagram| datagram.len() <= 1300) {
+                server.read_datagram(datagram, client_addr, now).unwrap();
+            }
+            server
+                .flush(now, |datagram, _| {
+                    to_client.push(datagram.to_vec());
+                    Ok(datagram.len())
+                })
+                .unwrap();
+            for datagram in &to_client {
+                client.read_datagram(datagram, server_addr, now).unwrap();
+            }
+            now += time::Duration::from_millis(50);
+            assert!(now - start < time::Duration::from_secs(10));
+        }
+        assert!(sent.windows(2).all(|pair| pair[0] >= pair[1]));
+        let connection = client.get(server_addr).unwrap();
+        assert_eq!(connection.max_packet_bytes(), 1280);
+        assert_eq!(connection.stats().max_packet_bytes, 1280);
+        // the other side keeps its own limit.
+        assert_eq!(
+            server.get(client_addr).unwrap().max_packet_bytes(),
+            MAX_PACKET_BYTES
+        );
+
+        let snapshot = vec![1u8; 1250];
+        let datagrams = client.write_datagrams(&snapshot, server_addr, now).unwrap();
+        assert_eq!(datagrams.len(), 1);
+    }
+
+    #[test]
+    fn messages() {
+        let start = time::Instant::now();
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 2000));
+        let (mut client, mut server) = (Connections::default(), Connections::default());
+
+        for i in 0..10u8 {
+            let message = Message::new(i, b"join");
+            client
+                .send_message(server_addr, RELIABLE_CHANNEL, message, start)
+                .unwrap();
+        }
+        client
+            .send_message(
+                server_addr,
+                UNRELIABLE_CHANNEL,
+                Message::new(0, b"fx"),
+                start,
+            )
+            .unwrap();
+
+        // the first packet is lost, the reliable messages get resent.
+        let mut packets = Vec::new();
+        let mut received = Vec::new();
+        for i in 0..50u32 {
+            let now = start + time::Duration::from_millis(10) * i;
+            client
+                .flush(now, |packet, addr| {
+                    packets.push((packet.to_vec(), addr));
+                    Ok(packet.len())
+                })
+                .unwrap();
+            for (packet, _) in packets.drain(..).filter(|_| i > 0) {
+                server.read_datagram(&packet, client_addr, now).unwrap();
+            }
+            server
+                .flush(now, |packet, _| {
+                    client.read_datagram(packet, server_addr, now).unwrap();
+                    Ok(packet.len())
+                })
+                .unwrap();
+            while let Some(message) = server.receive_message(client_addr, RELIABLE_CHANNEL) {
+                received.push(message.kind);
+            }
+        }
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        assert_eq!(
+            server.receive_message(client_addr, UNRELIABLE_CHANNEL),
+            None
+        );
+        assert!(!client
+            .get(server_addr)
+            .unwrap()
+            .has_pending(start + time::Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn encryption() {
+        let start = time::Instant::now();
+        let (up, down, cookie) = ([1; 32], [2; 32], [3; 24]);
+        let (mut client, mut server) = (Connection::new(start), Connection::new(start));
+        client.encrypt(PacketCipher::new(&up, &down, &cookie));
+        server.encrypt(PacketCipher::new(&down, &up, &cookie));
+        let mut datagrams = Vec::new();
+
+        client
+            .write_datagrams(b"buttons", start, &mut datagrams)
+            .unwrap();
+        assert!(!datagrams[0].windows(7).any(|window| window == b"buttons"));
+        let mut tampered = datagrams[0].clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(server.read_datagram(&tampered, start).is_err());
+        let payload = server.read_datagram(&datagrams[0], start).unwrap();
+        assert_eq!(payload.as_deref(), Some(&b"buttons"[..]));
+        // replayed.
+        assert!(server.read_datagram(&datagrams[0], start).is_err());
+
+        // plain datagrams are not accepted anymore.
+        let mut plain = Connection::new(start);
+        plain
+            .write_datagrams(b"buttons", start, &mut datagrams)
+            .unwrap();
+        assert!(server.read_datagram(&datagrams[0], start).is_err());
+        assert_eq!(server.stats().received_packets, 1);
+
+        // fragments and probes stay within the path mtu.
+        let snapshot = vec![7u8; 5000];
+        client.set_max_packet_bytes(1280);
+        client
+            .write_datagrams(&snapshot, start, &mut datagrams)
+            .unwrap();
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= 1280));
+        let payloads: Vec<_> = datagrams
+            .iter()
+            .filter_map(|datagram| server.read_datagram(datagram, start).unwrap())
+            .collect();
+        assert_eq!(payloads, [&snapshot[..]]);
+
+        client.probe_mtu();
+        let mut acks = Vec::new();
+        let mut now = start;
+        while client.is_probing_mtu() {
+            client.write_probes(now, &mut datagrams);
+            for datagram in datagrams.iter().filter(|datagram| datagram.len() <= 1300) {
+                assert_eq!(server.read_datagram(datagram, now), Ok(None));
+            }
+            server.write_probes(now, &mut acks);
+            for ack in &acks {
+                assert_eq!(client.read_datagram(ack, now), Ok(None));
+            }
+            now += time::Duration::from_millis(50);
+        }
+        assert_eq!(client.max_packet_bytes(), 1280);
+    }
+}
@@ -0,0 +1,264 @@
+use super::handshake::{Cookie, PROTOCOL_VERSION};
+use super::token::Key;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+/// Sequence number in front of and tag behind the encrypted part of a
+/// datagram.
+pub const ENCRYPTION_BYTES: usize = 8 + 16;
+
+/// Sequences this much older than the newest received one are dropped.
+pub const REPLAY_WINDOW: u64 = 128;
+
+/// Why `PacketCipher::open` dropped a datagram.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CipherError {
+    /// Too short for the sequence number and the tag.
+    Truncated,
+    /// The sequence was received before or is too old to tell.
+    Replayed,
+    /// The tag does not match: the datagram was changed on the way or sealed
+    /// with another key.
+    Forged,
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::Truncated => write!(f, "truncated encrypted datagram"),
+            CipherError::Replayed => write!(f, "replayed datagram"),
+            CipherError::Forged => write!(f, "datagram failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+/// Remembers which of the last `REPLAY_WINDOW` sequences were received.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    latest: Option<u64>,
+    // bit n is set if `latest - n` was received.
+    received: u128,
+}
+
+impl ReplayWindow {
+    /// True if `sequence` was received before or is too old to tell.
+    pub fn is_replay(&self, sequence: u64) -> bool {
+        match self.latest {
+            Some(latest) if sequence <= latest => {
+                let age = latest - sequence;
+                age >= REPLAY_WINDOW || self.received & (1 << age) != 0
+            }
+            _ => false,
+        }
+    }
+
+    pub fn insert(&mut self, sequence: u64) {
+        match self.latest {
+            Some(latest) if sequence <= latest => {
+                if latest - sequence < REPLAY_WINDOW {
+                    self.received |= 1 << (latest - sequence);
+                }
+            }
+            _ => {
+                let shift = self
+                    .latest
+                    .map_or(REPLAY_WINDOW, |latest| sequence - latest);
+                self.received = match shift < REPLAY_WINDOW {
+                    true => self.received << shift,
+                    false => 0,
+                };
+                self.received |= 1;
+                self.latest = Some(sequence);
+            }
+        }
+    }
+}
+
+// the connect token keys are mixed with the cookie of the handshake, so a
+// token used again never repeats a key and nonce pair.
+fn derive_key(key: &Key, cookie: &Cookie) -> ChaCha20Poly1305 {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key size works");
+    mac.update(cookie);
+    ChaCha20Poly1305::new(&mac.finalize().into_bytes())
+}
+
+fn nonce(sequence: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+    nonce
+}
+
+// the packet type stays readable and is authenticated with the version.
+fn associated_data(kind: u8) -> [u8; 5] {
+    let mut data = [kind, 0, 0, 0, 0];
+    data[1..].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    data
+}
+
+/// Encrypts and authenticates the datagrams of one connection with
+/// ChaCha20-Poly1305. Every datagram gets the next sequence number as its
+/// nonce, received ones are checked against a `ReplayWindow`.
+pub struct PacketCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    next_sequence: u64,
+    replay: ReplayWindow,
+}
+
+impl PacketCipher {
+    /// Keys of the two directions, from the connect token, and the cookie
+    /// of the handshake that set up the connection.
+    pub fn new(send_key: &Key, receive_key: &Key, cookie: &Cookie) -> PacketCipher {
+        PacketCipher {
+            send: derive_key(send_key, cookie),
+            receive: derive_key(receive_key, cookie),
+            next_sequence: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    /// Encrypts everything after the packet type byte of `datagram`, which
+    /// grows by `ENCRYPTION_BYTES`.
+    pub fn seal(&mut self, datagram: &mut Vec<u8>) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let kind = datagram[0];
+        let sealed = self
+            .send
+            .encrypt(
+                &nonce(sequence),
+                Payload {
+                    msg: &datagram[1..],
+                    aad: &associated_data(kind),
+                },
+            )
+            .expect("datagrams are far below the size limit");
+        datagram.truncate(1);
+        datagram.extend_from_slice(&sequence.to_le_bytes());
+        datagram.extend_from_slice(&sealed);
+    }
+
+    /// The datagram as it was before `seal`. Fails if it was changed on
+    /// the way or was received before.
+    pub fn open(&mut self, datagram: &[u8]) -> Result<Vec<u8>, CipherError> {
+        if datagram.len() < 1 + ENCRYPTION_BYTES {
+            return Err(CipherError::Truncated);
+        }
+        let mut sequence = [0u8; 8];
+        sequence.copy_from_slice(&datagram[1..9]);
+        let sequence = u64::from_le_bytes(sequence);
+        if self.replay.is_replay(sequence) {
+            return Err(CipherError::Replayed);
+        }
+        let plain = self
+            .receive
+            .decrypt(
+                &nonce(sequence),
+                Payload {
+                    msg: &datagram[9..],
+                    aad: &associated_data(datagram[0]),
+                },
+            )
+            .map_err(|_| CipherError::Forged)?;
+        self.replay.insert(sequence);
+        let mut opened = Vec::with_capacity(1 + plain.len());
+        opened.push(datagram[0]);
+        opened.extend_from_slice(&plain);
+        Ok(opened)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CipherError, PacketCipher, ReplayWindow, ENCRYPTION_BYTES, REPLAY_WINDOW};
+
+    fn pair() -> (PacketCipher, PacketCipher) {
+        let (up, down, cookie) = ([1; 32], [2; 32], [3; 24]);
+        (
+            PacketCipher::new(&up, &down, &cookie),
+            PacketCipher::new(&down, &up, &cookie),
+        )
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.is_replay(5));
+        window.insert(5);
+        assert!(window.is_replay(5));
+        assert!(!window.is_replay(3));
+        window.insert(3);
+        assert!(window.is_replay(3));
+        assert!(!window.is_replay(4));
+
+        window.insert(5 + REPLAY_WINDOW - 1);
+        assert!(window.is_replay(5));
+        assert!(!window.is_replay(6));
+        // 3 and 4 fell out of the window.
+        assert!(window.is_replay(4));
+        window.insert(1000);
+        assert!(window.is_replay(1000 - REPLAY_WINDOW));
+        assert!(!window.is_replay(1001 - REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (mut client, mut server) = pair();
+        let mut datagram = b"\0jump and shoot".to_vec();
+        client.seal(&mut datagram);
+        assert_eq!(datagram.len(), 15 + ENCRYPTION_BYTES);
+        assert!(!datagram.windows(4).any(|window| window == b"jump"));
+        assert_eq!(server.open(&datagram).unwrap(), b"\0jump and shoot");
+
+        // replayed.
+        assert_eq!(server.open(&datagram), Err(CipherError::Replayed));
+
+        // tampered with, including the packet type and the sequence.
+        let mut sent = b"\0crouch".to_vec();
+        client.seal(&mut sent);
+        for i in 0..sent.len() {
+            let mut tampered = sent.clone();
+            tampered[i] ^= 1;
+            // sequence 1 turned into the received sequence 0.
+            let error = match i {
+                1 => CipherError::Replayed,
+                _ => CipherError::Forged,
+            };
+            assert_eq!(server.open(&tampered), Err(error));
+        }
+        let truncated = &sent[..sent.len() - 1];
+        assert_eq!(server.open(truncated), Err(CipherError::Forged));
+        assert_eq!(server.open(&sent[..5]), Err(CipherError::Truncated));
+        assert!(server.open(&sent).is_ok());
+
+        // each direction has its own key.
+        let mut reflected = b"\0crouch".to_vec();
+        client.seal(&mut reflected);
+        assert_eq!(client.open(&reflected), Err(CipherError::Forged));
+
+        // out of order within the window.
+        let mut late = b"\x01a".to_vec();
+        let mut early = b"\x01b".to_vec();
+        server.seal(&mut late);
+        server.seal(&mut early);
+        assert_eq!(client.open(&early).unwrap(), b"\x01b");
+        assert_eq!(client.open(&late).unwrap(), b"\x01a");
+    }
+
+    #[test]
+    fn cookies() {
+        let key = [1; 32];
+        let mut first = PacketCipher::new(&key, &key, &[0; 24]);
+        let mut second = PacketCipher::new(&key, &key, &[1; 24]);
+        let mut datagram = b"\0x".to_vec();
+        first.seal(&mut datagram);
+        assert_eq!(second.open(&datagram), Err(CipherError::Forged));
+    }
+}
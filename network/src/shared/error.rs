@@ -0,0 +1,54 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+use std::ptr;
+
+/// Returned by C entry points that succeeded.
+pub const NETCODE_OK: i32 = 0;
+/// Returned by C entry points that failed, see `netcode_last_error`.
+pub const NETCODE_ERROR: i32 = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Remembers `error` as the description returned by `netcode_last_error` on this thread.
+pub fn set_last_error(error: impl fmt::Display) {
+    let message = error.to_string().replace('\0', " ");
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Describes the last failed call made on this thread, or null if none failed.
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn netcode_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{netcode_last_error, set_last_error};
+    use std::{ffi::CStr, thread};
+
+    #[test]
+    fn last_error() {
+        assert!(netcode_last_error().is_null());
+        set_last_error("address in use");
+        let message = unsafe { CStr::from_ptr(netcode_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "address in use");
+
+        // errors are per thread.
+        thread::spawn(|| assert!(netcode_last_error().is_null()))
+            .join()
+            .unwrap();
+
+        set_last_error("embedded\0nul");
+        let message = unsafe { CStr::from_ptr(netcode_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "embedded nul");
+    }
+}
@@ -0,0 +1,333 @@
+use super::bits::{BitReader, BitWriter, Error, NetSerialize, Stream};
+use super::packet::PacketType;
+use super::sequence::Sequence;
+use std::{collections::HashMap, mem, time};
+
+/// Datagrams above this size are split into fragments, unless the path MTU
+/// of the connection is known.
+pub const MAX_PACKET_BYTES: usize = 1200;
+
+/// Packet type byte and `FragmentHeader` in front of every fragment.
+pub const FRAGMENT_OVERHEAD_BYTES: usize = 5;
+
+pub const MAX_FRAGMENTS: usize = 256;
+
+/// Incomplete packets are dropped as a whole after this long.
+const REASSEMBLY_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// Most bytes of fragments and their bookkeeping waiting for reassembly per
+/// connection.
+const MAX_REASSEMBLY_BYTES: usize = 1024 * 1024;
+
+/// Most packets being reassembled at once per connection.
+const MAX_PARTIAL_PACKETS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FragmentHeader {
+    /// Sequence of the packet the fragment belongs to.
+    pub sequence: Sequence,
+    pub index: u8,
+    /// Number of fragments of the packet, in `[1, MAX_FRAGMENTS]`.
+    pub count: u16,
+}
+
+impl NetSerialize for FragmentHeader {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+        let mut sequence = self.sequence as u32;
+        let mut index = self.index as u32;
+        let mut count = self.count.wrapping_sub(1) as u32;
+        stream.serialize_bits(&mut sequence, 16)?;
+        stream.serialize_bits(&mut index, 8)?;
+        stream.serialize_bits(&mut count, 8)?;
+        self.sequence = sequence as Sequence;
+        self.index = index as u8;
+        self.count = count as u16 + 1;
+        Ok(())
+    }
+}
+
+/// Turns `packet` into datagrams of at most `max_bytes`, either the packet
+/// itself or its fragments. Fails if it needs more than `MAX_FRAGMENTS`.
+pub fn write_datagrams(
+    packet: &[u8],
+    sequence: Sequence,
+    max_bytes: usize,
+    datagrams: &mut Vec<Vec<u8>>,
+) -> Result<(), Error> {
+    datagrams.clear();
+    if packet.len() < max_bytes {
+        let mut datagram = Vec::with_capacity(packet.len() + 1);
+        datagram.push(PacketType::Payload as u8);
+        datagram.extend_from_slice(packet);
+        datagrams.push(datagram);
+        return Ok(());
+    }
+
+    let fragment_bytes = max_bytes
+        .checked_sub(FRAGMENT_OVERHEAD_BYTES)
+        .filter(|bytes| *bytes > 0)
+        .ok_or(Error::InvalidArgument)?;
+    let count = packet.len().div_ceil(fragment_bytes);
+    if count > MAX_FRAGMENTS {
+        return Err(Error::ValueOutOfBounds);
+    }
+    for (index, data) in packet.chunks(fragment_bytes).enumerate() {
+        let mut header = FragmentHeader {
+            sequence,
+            index: index as u8,
+            count: count as u16,
+        };
+        let mut datagram = vec![0u8; FRAGMENT_OVERHEAD_BYTES];
+        datagram[0] = PacketType::Fragment as u8;
+        let mut writer = BitWriter::new(&mut datagram[1..]);
+        header.serialize(&mut writer)?;
+        writer.flush();
+        datagram.extend_from_slice(data);
+        datagrams.push(datagram);
+    }
+    Ok(())
+}
+
+struct Partial {
+    started: time::Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+impl Partial {
+    // memory taken by the slot table and the fragments received so far.
+    fn bytes(&self) -> usize {
+        let slots = self.fragments.len() * mem::size_of::<Option<Vec<u8>>>();
+        slots + self.fragments.iter().flatten().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// Collects the fragments of packets from one connection.
+#[derive(Default)]
+pub struct Reassembly {
+    packets: HashMap<Sequence, Partial>,
+    bytes: usize,
+    dropped: u64,
+}
+
+impl Reassembly {
+    /// Number of packets dropped because a fragment did not arrive in time.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Bytes of fragments waiting for the rest of their packet, including
+    /// their bookkeeping.
+    pub fn pending_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn expire(&mut self, now: time::Instant) {
+        let bytes = &mut self.bytes;
+        let dropped = &mut self.dropped;
+        self.packets.retain(|_, partial| {
+            if now.saturating_duration_since(partial.started) < REASSEMBLY_TIMEOUT {
+                return true;
+            }
+            *bytes -= partial.bytes();
+            *dropped += 1;
+            false
+        });
+    }
+
+    /// Stores a fragment, the datagram without its packet type byte. Returns
+    /// the packet once all of its fragments arrived. Empty fragments are
+    /// rejected, so every partial packet costs its sender data.
+    pub fn insert(
+        &mut self,
+        fragment: &[u8],
+        now: time::Instant,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut header = FragmentHeader::default();
+        header.serialize(&mut BitReader::new(fragment))?;
+        let data = &fragment[FRAGMENT_OVERHEAD_BYTES - 1..];
+        if header.index as u16 >= header.count || data.is_empty() {
+            return Err(Error::ValueOutOfBounds);
+        }
+
+        self.expire(now);
+        let slots = match self.packets.contains_key(&header.sequence) {
+            true => 0,
+            false if self.packets.len() == MAX_PARTIAL_PACKETS => {
+                return Err(Error::OutOfMemory);
+            }
+            false => header.count as usize * mem::size_of::<Option<Vec<u8>>>(),
+        };
+        if self.bytes + slots + data.len() > MAX_REASSEMBLY_BYTES {
+            return Err(Error::OutOfMemory);
+        }
+        self.bytes += slots;
+        let partial = self
+            .packets
+            .entry(header.sequence)
+            .or_insert_with(|| Partial {
+                started: now,
+                fragments: vec![None; header.count as usize],
+                received: 0,
+            });
+        if partial.fragments.len() != header.count as usize {
+            return Err(Error::ValueOutOfBounds);
+        }
+        let slot = &mut partial.fragments[header.index as usize];
+        if slot.is_some() {
+            return Ok(None);
+        }
+        *slot = Some(data.to_vec());
+        partial.received += 1;
+        self.bytes += data.len();
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+
+        let partial = self.packets.remove(&header.sequence).unwrap();
+        self.bytes -= partial.bytes();
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        write_datagrams, FragmentHeader, Reassembly, FRAGMENT_OVERHEAD_BYTES, MAX_FRAGMENTS,
+        MAX_PACKET_BYTES, MAX_PARTIAL_PACKETS, MAX_REASSEMBLY_BYTES, REASSEMBLY_TIMEOUT,
+    };
+    use crate::shared::{
+        bits::{BitWriter, Error, NetSerialize},
+        packet::PacketType,
+    };
+    use std::{mem, time};
+
+    fn packet(bytes: usize) -> Vec<u8> {
+        (0..bytes).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn small_packet() {
+        let mut datagrams = Vec::new();
+        write_datagrams(b"small", 3, MAX_PACKET_BYTES, &mut datagrams).unwrap();
+        assert_eq!(datagrams, vec![b"\0small".to_vec()]);
+        assert_eq!(datagrams[0][0], PacketType::Payload as u8);
+    }
+
+    #[test]
+    fn reassembly() {
+        let now = time::Instant::now();
+        let packet = packet(5000);
+        let mut datagrams = Vec::new();
+        write_datagrams(&packet, 65535, MAX_PACKET_BYTES, &mut datagrams).unwrap();
+        assert_eq!(datagrams.len(), 5);
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram.len() <= MAX_PACKET_BYTES));
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram[0] == PacketType::Fragment as u8));
+
+        // out of order and duplicated.
+        let mut reassembly = Reassembly::default();
+        for index in [4, 0, 2, 2, 1].iter() {
+            assert_eq!(reassembly.insert(&datagrams[*index][1..], now), Ok(None));
+        }
+        assert!(reassembly.pending_bytes() > 0);
+        let reassembled = reassembly.insert(&datagrams[3][1..], now).unwrap();
+        assert_eq!(reassembled, Some(packet));
+        assert_eq!(reassembly.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn lost_fragment() {
+        let now = time::Instant::now();
+        let mut datagrams = Vec::new();
+        write_datagrams(&packet(3000), 1, MAX_PACKET_BYTES, &mut datagrams).unwrap();
+
+        let mut reassembly = Reassembly::default();
+        for datagram in &datagrams[1..] {
+            reassembly.insert(&datagram[1..], now).unwrap();
+        }
+        // the first fragment shows up too late, the packet is gone.
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembly.insert(&datagrams[0][1..], later), Ok(None));
+        assert_eq!(reassembly.dropped(), 1);
+        let later = later + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembly.insert(&datagrams[0][1..], later), Ok(None));
+        assert_eq!(reassembly.dropped(), 2);
+        let slots = datagrams.len() * mem::size_of::<Option<Vec<u8>>>();
+        assert_eq!(
+            reassembly.pending_bytes(),
+            datagrams[0].len() - FRAGMENT_OVERHEAD_BYTES + slots
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let now = time::Instant::now();
+        let mut datagrams = Vec::new();
+        let too_large = packet(MAX_FRAGMENTS * (100 - FRAGMENT_OVERHEAD_BYTES) + 1);
+        assert_eq!(
+            write_datagrams(&too_large, 0, 100, &mut datagrams),
+            Err(Error::ValueOutOfBounds)
+        );
+
+        // memory cap: a flood of first fragments that never complete.
+        let mut reassembly = Reassembly::default();
+        let mut result = Ok(None);
+        for sequence in 0..2000 {
+            write_datagrams(&packet(2000), sequence, MAX_PACKET_BYTES, &mut datagrams).unwrap();
+            result = reassembly.insert(&datagrams[0][1..], now);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, Err(Error::OutOfMemory));
+
+        // fragments of the most packets there can be with almost no data.
+        let mut reassembly = Reassembly::default();
+        let fragment = |sequence, bytes| {
+            let mut header = FragmentHeader {
+                sequence,
+                index: 0,
+                count: MAX_FRAGMENTS as u16,
+            };
+            let mut datagram = vec![0u8; FRAGMENT_OVERHEAD_BYTES - 1];
+            let mut writer = BitWriter::new(&mut datagram);
+            header.serialize(&mut writer).unwrap();
+            writer.flush();
+            datagram.resize(FRAGMENT_OVERHEAD_BYTES - 1 + bytes, 7);
+            datagram
+        };
+        assert_eq!(
+            reassembly.insert(&fragment(0, 0), now),
+            Err(Error::ValueOutOfBounds)
+        );
+        for sequence in 0..MAX_PARTIAL_PACKETS as u16 {
+            assert_eq!(reassembly.insert(&fragment(sequence, 1), now), Ok(None));
+        }
+        assert_eq!(
+            reassembly.insert(&fragment(1000, 1), now),
+            Err(Error::OutOfMemory)
+        );
+        assert!(reassembly.pending_bytes() <= MAX_REASSEMBLY_BYTES);
+        assert!(reassembly.pending_bytes() > MAX_PARTIAL_PACKETS * MAX_FRAGMENTS);
+        // they expire like any other.
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembly.insert(&fragment(1000, 1), later), Ok(None));
+        assert_eq!(reassembly.dropped(), MAX_PARTIAL_PACKETS as u64);
+
+        // fragments that disagree on the count are rejected.
+        let mut reassembly = Reassembly::default();
+        write_datagrams(&packet(2000), 9, MAX_PACKET_BYTES, &mut datagrams).unwrap();
+        reassembly.insert(&datagrams[0][1..], now).unwrap();
+        write_datagrams(&packet(4000), 9, MAX_PACKET_BYTES, &mut datagrams).unwrap();
+        assert_eq!(
+            reassembly.insert(&datagrams[1][1..], now),
+            Err(Error::ValueOutOfBounds)
+        );
+    }
+}
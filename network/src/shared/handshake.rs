@@ -0,0 +1,696 @@
+use super::bits::{BitReader, BitWriter, Error, NetSerialize, Stream};
+use super::packet::PacketType;
+use super::token::{ConnectToken, Key, SealedToken, SEALED_BYTES};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{fmt, net::SocketAddr, time};
+
+/// Clients with a different version are denied.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Issue time and truncated mac of the client address.
+pub const COOKIE_BYTES: usize = 24;
+
+const MAC_BYTES: usize = COOKIE_BYTES - 8;
+
+/// Truncated mac of an accept or deny, with the server to client key of the
+/// connect token.
+pub const SIGNATURE_BYTES: usize = 16;
+
+/// Packet type, version, nonce and connect token. The challenge sent back is
+/// much smaller than the request that caused it.
+pub const REQUEST_BYTES: usize = 1 + 4 + 8 + 8 + 24 + SEALED_BYTES;
+
+/// Cookies older than this are not accepted anymore.
+const COOKIE_LIFETIME: time::Duration = time::Duration::from_secs(5);
+
+/// Requests and responses are sent again if the server did not answer.
+const RESEND_DELAY: time::Duration = time::Duration::from_millis(100);
+
+/// A client gives up on a server that did not accept or deny it.
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+pub type Cookie = [u8; COOKIE_BYTES];
+
+pub type Signature = [u8; SIGNATURE_BYTES];
+
+/// Why a server refused a client, see `client_deny_reason`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    None = 0,
+    ServerFull = 1,
+    VersionMismatch = 2,
+    /// The connect token was not issued for this server or was tampered with.
+    InvalidToken = 3,
+    TokenExpired = 4,
+    /// Another client already uses the same connect token.
+    AlreadyConnected = 5,
+}
+
+impl DenyReason {
+    fn from_byte(byte: u8) -> Option<DenyReason> {
+        match byte {
+            1 => Some(DenyReason::ServerFull),
+            2 => Some(DenyReason::VersionMismatch),
+            3 => Some(DenyReason::InvalidToken),
+            4 => Some(DenyReason::TokenExpired),
+            5 => Some(DenyReason::AlreadyConnected),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenyReason::None => write!(f, "not denied"),
+            DenyReason::ServerFull => write!(f, "server full"),
+            DenyReason::VersionMismatch => write!(f, "version mismatch"),
+            DenyReason::InvalidToken => write!(f, "invalid connect token"),
+            DenyReason::TokenExpired => write!(f, "connect token expired"),
+            DenyReason::AlreadyConnected => write!(f, "already connected"),
+        }
+    }
+}
+
+/// Packets exchanged before a connection exists. Requests and responses
+/// carry the connect token, so the server needs no state until the client
+/// is accepted. The server echoes the random nonce of the client, so only
+/// senders that saw the request can answer it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Handshake {
+    Request {
+        version: u32,
+        nonce: u64,
+        token: Box<SealedToken>,
+    },
+    Challenge {
+        cookie: Cookie,
+        nonce: u64,
+    },
+    Response {
+        cookie: Cookie,
+        nonce: u64,
+        token: Box<SealedToken>,
+    },
+    /// Signed if the client brought a connect token, see `sign`. Tells the
+    /// client whether to encrypt the connection.
+    Accepted {
+        nonce: u64,
+        encrypted: bool,
+        signature: Signature,
+    },
+    Denied {
+        reason: DenyReason,
+        nonce: u64,
+        signature: Signature,
+    },
+}
+
+impl Handshake {
+    pub fn accepted(nonce: u64, encrypted: bool) -> Handshake {
+        Handshake::Accepted {
+            nonce,
+            encrypted,
+            signature: [0; SIGNATURE_BYTES],
+        }
+    }
+
+    pub fn denied(reason: DenyReason, nonce: u64) -> Handshake {
+        Handshake::Denied {
+            reason,
+            nonce,
+            signature: [0; SIGNATURE_BYTES],
+        }
+    }
+
+    // mac of an accept or deny without its signature.
+    fn mac(&self, key: &Key) -> Option<Hmac<Sha256>> {
+        let mut unsigned = self.clone();
+        match &mut unsigned {
+            Handshake::Accepted { signature, .. } | Handshake::Denied { signature, .. } => {
+                *signature = [0; SIGNATURE_BYTES]
+            }
+            _ => return None,
+        }
+        let mut datagram = Vec::new();
+        unsigned.write(&mut datagram);
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key size works");
+        mac.update(&datagram);
+        Some(mac)
+    }
+
+    /// Signs an accept or deny with `key`, the server to client key of the
+    /// connect token of the client. Other packets are left as they are.
+    pub fn sign(mut self, key: &Key) -> Handshake {
+        if let Some(mac) = self.mac(key) {
+            let mac = mac.finalize().into_bytes();
+            if let Handshake::Accepted { signature, .. } | Handshake::Denied { signature, .. } =
+                &mut self
+            {
+                signature.copy_from_slice(&mac[..SIGNATURE_BYTES]);
+            }
+        }
+        self
+    }
+
+    /// True if this is an accept or deny signed with `key`.
+    pub fn is_signed(&self, key: &Key) -> bool {
+        match (self, self.mac(key)) {
+            (Handshake::Accepted { signature, .. }, Some(mac))
+            | (Handshake::Denied { signature, .. }, Some(mac)) => {
+                mac.verify_truncated_left(signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    pub fn write(&self, datagram: &mut Vec<u8>) {
+        let mut writer = BitWriter::growable(None);
+        let result = match self {
+            Handshake::Request {
+                version,
+                nonce,
+                token,
+            } => writer
+                .write_byte(PacketType::ConnectionRequest as u8)
+                .and_then(|_| writer.write_bits(*version, 32))
+                .and_then(|_| writer.write_bytes(&nonce.to_le_bytes()))
+                .and_then(|_| token.clone().serialize(&mut writer)),
+            Handshake::Challenge { cookie, nonce } => writer
+                .write_byte(PacketType::Challenge as u8)
+                .and_then(|_| writer.write_bytes(cookie))
+                .and_then(|_| writer.write_bytes(&nonce.to_le_bytes())),
+            Handshake::Response {
+                cookie,
+                nonce,
+                token,
+            } => writer
+                .write_byte(PacketType::ChallengeResponse as u8)
+                .and_then(|_| writer.write_bytes(cookie))
+                .and_then(|_| writer.write_bytes(&nonce.to_le_bytes()))
+                .and_then(|_| token.clone().serialize(&mut writer)),
+            Handshake::Accepted {
+                nonce,
+                encrypted,
+                signature,
+            } => writer
+                .write_byte(PacketType::ConnectionAccepted as u8)
+                .and_then(|_| writer.write_bytes(&nonce.to_le_bytes()))
+                .and_then(|_| writer.write_byte(*encrypted as u8))
+                .and_then(|_| writer.write_bytes(signature)),
+            Handshake::Denied {
+                reason,
+                nonce,
+                signature,
+            } => writer
+                .write_byte(PacketType::ConnectionDenied as u8)
+                .and_then(|_| writer.write_byte(*reason as u8))
+                .and_then(|_| writer.write_bytes(&nonce.to_le_bytes()))
+                .and_then(|_| writer.write_bytes(signature)),
+        };
+        result.expect("growable writers do not run out of memory");
+        datagram.clear();
+        datagram.extend_from_slice(&writer.into_bytes());
+    }
+
+    /// Reads a handshake packet, `None` if the datagram is of another type.
+    pub fn read(datagram: &[u8]) -> Result<Option<Handshake>, Error> {
+        let (&kind, rest) = datagram.split_first().ok_or(Error::OutOfMemory)?;
+        let kind = match PacketType::from_byte(kind) {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+        let mut reader = BitReader::new(rest);
+        let mut cookie = [0u8; COOKIE_BYTES];
+        let mut nonce = [0u8; 8];
+        let mut signature = [0u8; SIGNATURE_BYTES];
+        let handshake = match kind {
+            PacketType::ConnectionRequest => {
+                let mut version = 0;
+                let mut token = Box::<SealedToken>::default();
+                reader.serialize_bits(&mut version, 32)?;
+                reader.serialize_bytes(&mut nonce)?;
+                token.serialize(&mut reader)?;
+                Handshake::Request {
+                    version,
+                    nonce: u64::from_le_bytes(nonce),
+                    token,
+                }
+            }
+            PacketType::Challenge => {
+                reader.serialize_bytes(&mut cookie)?;
+                reader.serialize_bytes(&mut nonce)?;
+                Handshake::Challenge {
+                    cookie,
+                    nonce: u64::from_le_bytes(nonce),
+                }
+            }
+            PacketType::ChallengeResponse => {
+                let mut token = Box::<SealedToken>::default();
+                reader.serialize_bytes(&mut cookie)?;
+                reader.serialize_bytes(&mut nonce)?;
+                token.serialize(&mut reader)?;
+                Handshake::Response {
+                    cookie,
+                    nonce: u64::from_le_bytes(nonce),
+                    token,
+                }
+            }
+            PacketType::ConnectionAccepted => {
+                let mut encrypted = 0;
+                reader.serialize_bytes(&mut nonce)?;
+                reader.serialize_byte(&mut encrypted)?;
+                reader.serialize_bytes(&mut signature)?;
+                Handshake::Accepted {
+                    nonce: u64::from_le_bytes(nonce),
+                    encrypted: match encrypted {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(Error::ValueOutOfBounds),
+                    },
+                    signature,
+                }
+            }
+            PacketType::ConnectionDenied => {
+                let mut reason = 0;
+                reader.serialize_byte(&mut reason)?;
+                reader.serialize_bytes(&mut nonce)?;
+                reader.serialize_bytes(&mut signature)?;
+                Handshake::Denied {
+                    reason: DenyReason::from_byte(reason).ok_or(Error::ValueOutOfBounds)?,
+                    nonce: u64::from_le_bytes(nonce),
+                    signature,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(handshake))
+    }
+}
+
+/// Issues and checks the cookies of a server. They are a mac of the client
+/// address, so the server keeps no state until a cookie comes back.
+pub struct Cookies {
+    key: [u8; 32],
+    epoch: time::Instant,
+}
+
+impl Cookies {
+    pub fn new(now: time::Instant) -> Cookies {
+        Cookies {
+            key: rand::random(),
+            epoch: now,
+        }
+    }
+
+    fn mac(&self, addr: SocketAddr, issued: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key size works");
+        mac.update(&issued.to_be_bytes());
+        match addr {
+            SocketAddr::V4(addr) => mac.update(&addr.ip().octets()),
+            SocketAddr::V6(addr) => mac.update(&addr.ip().octets()),
+        }
+        mac.update(&addr.port().to_be_bytes());
+        mac
+    }
+
+    /// Milliseconds after the start of the server `cookie` was issued at.
+    pub fn issued(cookie: &Cookie) -> u64 {
+        let mut issued = [0u8; 8];
+        issued.copy_from_slice(&cookie[..8]);
+        u64::from_be_bytes(issued)
+    }
+
+    pub fn issue(&self, addr: SocketAddr, now: time::Instant) -> Cookie {
+        let issued = now.saturating_duration_since(self.epoch).as_millis() as u64;
+        let mut cookie = [0u8; COOKIE_BYTES];
+        cookie[..8].copy_from_slice(&issued.to_be_bytes());
+        let mac = self.mac(addr, issued).finalize().into_bytes();
+        cookie[8..].copy_from_slice(&mac[..MAC_BYTES]);
+        cookie
+    }
+
+    /// True if the cookie was issued to `addr` and did not expire.
+    pub fn verify(&self, cookie: &Cookie, addr: SocketAddr, now: time::Instant) -> bool {
+        let issued = Cookies::issued(cookie);
+        let age = now.saturating_duration_since(self.epoch).as_millis() as u64;
+        match age.checked_sub(issued) {
+            Some(age) if age <= COOKIE_LIFETIME.as_millis() as u64 => self
+                .mac(addr, issued)
+                .verify_truncated_left(&cookie[8..])
+                .is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// Progress of a client connecting to a server, see `client_state`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    Disconnected = 0,
+    Connecting = 1,
+    Connected = 2,
+    /// The server refused the client, see `client_deny_reason`.
+    Denied = 3,
+    TimedOut = 4,
+}
+
+/// Client side of the handshake with one server.
+pub struct ClientHandshake {
+    server: SocketAddr,
+    token: Box<SealedToken>,
+    // the server to client key of the connect token, accepts and most denies
+    // have to be signed with it.
+    key: Option<Key>,
+    nonce: u64,
+    state: ClientState,
+    deny_reason: DenyReason,
+    cookie: Option<Cookie>,
+    // whether the server encrypts the connection it accepted.
+    encrypted: bool,
+    started: time::Instant,
+    last_sent: Option<time::Instant>,
+}
+
+impl ClientHandshake {
+    /// Servers that do not require connect tokens deny any `token` other
+    /// than the default one.
+    pub fn new(server: SocketAddr, token: SealedToken, now: time::Instant) -> ClientHandshake {
+        ClientHandshake {
+            server,
+            token: Box::new(token),
+            key: None,
+            nonce: rand::random(),
+            state: ClientState::Connecting,
+            deny_reason: DenyReason::None,
+            cookie: None,
+            encrypted: false,
+            started: now,
+            last_sent: None,
+        }
+    }
+
+    /// Handshake with `server` that only accepts the server if it could
+    /// open `token`.
+    pub fn from_token(server: SocketAddr, token: &ConnectToken, now: time::Instant) -> Self {
+        ClientHandshake {
+            key: Some(token.server_to_client_key),
+            ..ClientHandshake::new(server, token.sealed.clone(), now)
+        }
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn deny_reason(&self) -> DenyReason {
+        self.deny_reason
+    }
+
+    /// Ends the connection the handshake set up, as `Disconnected` by the
+    /// server or `TimedOut`.
+    pub fn close(&mut self, state: ClientState) {
+        self.state = state;
+    }
+
+    /// True if the server accepted the client with an encrypted connection.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// The cookie the server challenged the client with.
+    pub fn cookie(&self) -> Option<&Cookie> {
+        self.cookie.as_ref()
+    }
+
+    /// The request or response to send at `now`, if any.
+    pub fn poll(&mut self, now: time::Instant) -> Option<Handshake> {
+        if self.state != ClientState::Connecting {
+            return None;
+        }
+        if now.saturating_duration_since(self.started) >= HANDSHAKE_TIMEOUT {
+            self.state = ClientState::TimedOut;
+            return None;
+        }
+        if let Some(last_sent) = self.last_sent {
+            if now.saturating_duration_since(last_sent) < RESEND_DELAY {
+                return None;
+            }
+        }
+        self.last_sent = Some(now);
+        let (nonce, token) = (self.nonce, self.token.clone());
+        Some(match self.cookie {
+            Some(cookie) => Handshake::Response {
+                cookie,
+                nonce,
+                token,
+            },
+            None => Handshake::Request {
+                version: PROTOCOL_VERSION,
+                nonce,
+                token,
+            },
+        })
+    }
+
+    // true if `packet` is signed with the token key, or the server may not
+    // have been able to open the token to sign it.
+    fn is_authentic(&self, packet: &Handshake) -> bool {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return true,
+        };
+        match packet {
+            Handshake::Denied {
+                reason:
+                    DenyReason::VersionMismatch | DenyReason::InvalidToken | DenyReason::TokenExpired,
+                ..
+            } => true,
+            packet => packet.is_signed(key),
+        }
+    }
+
+    /// Handles a handshake packet from the server. Packets that do not echo
+    /// the nonce of the client are ignored.
+    pub fn receive(&mut self, packet: Handshake) {
+        if self.state != ClientState::Connecting {
+            return;
+        }
+        match packet {
+            // the server derives the keys from the first cookie it gets back,
+            // later challenges to resent requests are ignored.
+            Handshake::Challenge { cookie, nonce }
+                if nonce == self.nonce && self.cookie.is_none() =>
+            {
+                self.cookie = Some(cookie);
+                // answer right away instead of after the resend delay.
+                self.last_sent = None;
+            }
+            Handshake::Accepted {
+                nonce, encrypted, ..
+            } if nonce == self.nonce && self.cookie.is_some() && self.is_authentic(&packet) => {
+                self.state = ClientState::Connected;
+                self.encrypted = encrypted;
+            }
+            Handshake::Denied { reason, nonce, .. }
+                if nonce == self.nonce && self.is_authentic(&packet) =>
+            {
+                self.state = ClientState::Denied;
+                self.deny_reason = reason;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ClientHandshake, ClientState, Cookie, Cookies, DenyReason, Handshake, COOKIE_LIFETIME,
+        HANDSHAKE_TIMEOUT, PROTOCOL_VERSION, REQUEST_BYTES, RESEND_DELAY,
+    };
+    use crate::shared::token::{self, ConnectToken, SealedToken};
+    use std::{net::SocketAddr, time};
+
+    fn request(version: u32, nonce: u64) -> Handshake {
+        Handshake::Request {
+            version,
+            nonce,
+            token: Box::default(),
+        }
+    }
+
+    fn response(cookie: Cookie, nonce: u64) -> Handshake {
+        Handshake::Response {
+            cookie,
+            nonce,
+            token: Box::default(),
+        }
+    }
+
+    // the nonce the client sends its requests with.
+    fn request_nonce(client: &mut ClientHandshake, now: time::Instant) -> u64 {
+        match client.poll(now) {
+            Some(Handshake::Request { nonce, .. }) => nonce,
+            packet => panic!("expected a request, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let packets = [
+            request(PROTOCOL_VERSION, 1),
+            Handshake::Challenge {
+                cookie: [3; 24],
+                nonce: 2,
+            },
+            response([4; 24], 3),
+            Handshake::accepted(4, true),
+            Handshake::denied(DenyReason::ServerFull, 5).sign(&[6; 32]),
+        ];
+        let mut datagram = Vec::new();
+        for packet in packets.iter() {
+            packet.write(&mut datagram);
+            assert_eq!(Handshake::read(&datagram), Ok(Some(packet.clone())));
+        }
+
+        // no amplification: the challenge is smaller than the request.
+        request(1, 0).write(&mut datagram);
+        assert_eq!(datagram.len(), REQUEST_BYTES);
+        assert!(Handshake::read(&datagram[..REQUEST_BYTES - 1]).is_err());
+        let challenge = Handshake::Challenge {
+            cookie: [0; 24],
+            nonce: 0,
+        };
+        challenge.write(&mut datagram);
+        assert!(datagram.len() < REQUEST_BYTES);
+
+        assert_eq!(Handshake::read(&[0, 1, 2]), Ok(None));
+        assert!(Handshake::read(&[8, 0]).is_err());
+        assert!(Handshake::read(&[8, 1]).is_err());
+        assert!(Handshake::read(&[7]).is_err());
+        let mut datagram = Vec::new();
+        Handshake::accepted(0, true).write(&mut datagram);
+        datagram[9] = 2;
+        assert!(Handshake::read(&datagram).is_err());
+    }
+
+    #[test]
+    fn signatures() {
+        let (key, other) = ([1; 32], [2; 32]);
+        let accepted = Handshake::accepted(9, false).sign(&key);
+        assert!(accepted.is_signed(&key));
+        assert!(!accepted.is_signed(&other));
+        assert!(!Handshake::accepted(9, false).is_signed(&key));
+        // the signature covers the whole packet.
+        let mut datagram = Vec::new();
+        accepted.write(&mut datagram);
+        datagram[1] ^= 1;
+        assert!(!Handshake::read(&datagram).unwrap().unwrap().is_signed(&key));
+        let denied = Handshake::denied(DenyReason::ServerFull, 9).sign(&key);
+        let mut datagram = Vec::new();
+        denied.write(&mut datagram);
+        datagram[1] = DenyReason::AlreadyConnected as u8;
+        assert!(!Handshake::read(&datagram).unwrap().unwrap().is_signed(&key));
+        // only accepts and denies are signed.
+        assert!(!response([0; 24], 9).sign(&key).is_signed(&key));
+    }
+
+    #[test]
+    fn cookies() {
+        let now = time::Instant::now();
+        let client = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let spoofed = SocketAddr::from(([127, 0, 0, 1], 4001));
+        let cookies = Cookies::new(now);
+        let cookie = cookies.issue(client, now);
+        assert!(cookies.verify(&cookie, client, now + COOKIE_LIFETIME));
+        assert!(!cookies.verify(&cookie, spoofed, now));
+        let later = now + COOKIE_LIFETIME + time::Duration::from_millis(1);
+        assert!(!cookies.verify(&cookie, client, later));
+
+        let mut forged = cookie;
+        forged[20] ^= 1;
+        assert!(!cookies.verify(&forged, client, now));
+        // another server does not take the cookie.
+        assert!(!Cookies::new(now).verify(&cookie, client, now));
+    }
+
+    #[test]
+    fn client() {
+        let now = time::Instant::now();
+        let server = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let mut client = ClientHandshake::new(server, SealedToken::default(), now);
+        let nonce = request_nonce(&mut client, now);
+        assert_eq!(client.poll(now), None);
+        // accepts without a challenge are ignored.
+        client.receive(Handshake::accepted(nonce, false));
+        assert_eq!(client.state(), ClientState::Connecting);
+
+        // so are answers to requests someone else sent.
+        let challenge = |cookie, nonce| Handshake::Challenge { cookie, nonce };
+        client.receive(challenge([8; 24], nonce + 1));
+        assert_eq!(client.poll(now), None);
+        client.receive(challenge([9; 24], nonce));
+        assert_eq!(client.poll(now), Some(response([9; 24], nonce)));
+        // the server keys the connection with the first cookie it gets back.
+        client.receive(challenge([10; 24], nonce));
+        assert_eq!(client.poll(now + RESEND_DELAY / 2), None);
+        assert_eq!(
+            client.poll(now + RESEND_DELAY),
+            Some(response([9; 24], nonce))
+        );
+        client.receive(Handshake::denied(DenyReason::ServerFull, nonce + 1));
+        client.receive(Handshake::accepted(nonce + 1, false));
+        assert_eq!(client.state(), ClientState::Connecting);
+        client.receive(Handshake::accepted(nonce, false));
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(client.poll(now + RESEND_DELAY * 2), None);
+
+        let mut client = ClientHandshake::new(server, SealedToken::default(), now);
+        let nonce = request_nonce(&mut client, now);
+        client.receive(Handshake::denied(DenyReason::VersionMismatch, nonce));
+        assert_eq!(client.state(), ClientState::Denied);
+        assert_eq!(client.deny_reason(), DenyReason::VersionMismatch);
+
+        let mut client = ClientHandshake::new(server, SealedToken::default(), now);
+        assert_eq!(client.poll(now + HANDSHAKE_TIMEOUT), None);
+        assert_eq!(client.state(), ClientState::TimedOut);
+    }
+
+    #[test]
+    fn signed_replies() {
+        let now = time::Instant::now();
+        let server = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let lifetime = time::Duration::from_secs(30);
+        let token =
+            ConnectToken::generate(&token::generate_key(), 1, lifetime, &[server], &[]).unwrap();
+        let key = token.server_to_client_key;
+        let mut client = ClientHandshake::from_token(server, &token, now);
+        let nonce = request_nonce(&mut client, now);
+
+        // denies the server gives before it could open the token.
+        client.receive(Handshake::denied(DenyReason::InvalidToken, nonce));
+        assert_eq!(client.state(), ClientState::Denied);
+
+        let mut client = ClientHandshake::from_token(server, &token, now);
+        let nonce = request_nonce(&mut client, now);
+        client.receive(Handshake::Challenge {
+            cookie: [9; 24],
+            nonce,
+        });
+        client.receive(Handshake::denied(DenyReason::ServerFull, nonce));
+        client.receive(Handshake::accepted(nonce, false));
+        client.receive(Handshake::accepted(nonce, true).sign(&[0; 32]));
+        assert_eq!(client.state(), ClientState::Connecting);
+        client.receive(Handshake::accepted(nonce, true).sign(&key));
+        assert_eq!(client.state(), ClientState::Connected);
+        assert!(client.is_encrypted());
+    }
+}
@@ -1,5 +1,21 @@
+pub mod address;
+pub mod batch;
 pub mod bits;
+pub mod channel;
+pub mod checksum;
+pub mod conditioner;
+pub mod connection;
+pub mod crypto;
+pub mod error;
+pub mod fragment;
+pub mod handshake;
+pub mod mtu;
+pub mod packet;
+pub mod pool;
+pub mod sequence;
 pub mod socketio;
+pub mod token;
+pub mod transport;
 pub mod types;
 pub mod world;
 
@@ -0,0 +1,194 @@
+use super::bits::{BitReader, BitWriter, Error, Stream};
+use super::packet::PacketType;
+use std::time;
+
+/// Datagram sizes tried by a probe, largest first. They leave room for the
+/// checksum and the IP and UDP headers of common links.
+pub const PROBE_SIZES: [usize; 7] = [
+    1468, // ethernet, ipv4
+    1448, // ethernet, ipv6
+    1400, // vpn and tunnel encapsulation
+    1280, // pppoe and double encapsulation
+    1228, // ipv6 minimum mtu
+    1024, 548,
+];
+
+/// Packet type byte and size in front of the padding.
+const PROBE_HEADER_BYTES: usize = 3;
+
+/// A probe that gets no ack within this long is sent again.
+const PROBE_TIMEOUT: time::Duration = time::Duration::from_millis(250);
+
+/// Attempts per size before trying the next smaller one.
+const PROBE_ATTEMPTS: u32 = 3;
+
+fn write_size(kind: PacketType, size: usize, datagram: &mut Vec<u8>) {
+    let mut header = [0u8; PROBE_HEADER_BYTES];
+    header[0] = kind as u8;
+    let mut writer = BitWriter::new(&mut header[1..]);
+    writer
+        .write_bits(size as u32, 16)
+        .expect("probe sizes fit 16 bits");
+    writer.flush();
+    datagram.clear();
+    datagram.extend_from_slice(&header);
+}
+
+/// A probe datagram of `size` bytes, padded with zeros.
+pub fn write_probe(size: usize, datagram: &mut Vec<u8>) {
+    write_size(PacketType::Probe, size, datagram);
+    datagram.resize(size.max(PROBE_HEADER_BYTES), 0);
+}
+
+pub fn write_probe_ack(size: usize, datagram: &mut Vec<u8>) {
+    write_size(PacketType::ProbeAck, size, datagram);
+}
+
+/// Reads the size of a probe or probe ack, the datagram without its packet
+/// type byte. Probes have to be as large as they claim.
+pub fn read_size(kind: PacketType, datagram: &[u8]) -> Result<usize, Error> {
+    let mut size = 0;
+    BitReader::new(datagram).serialize_bits(&mut size, 16)?;
+    let size = size as usize;
+    if kind == PacketType::Probe && size != datagram.len() + 1 {
+        return Err(Error::ValueOutOfBounds);
+    }
+    Ok(size)
+}
+
+/// Tries the sizes in `PROBE_SIZES` one after the other until one of them is
+/// acknowledged.
+pub struct MtuProbe {
+    index: usize,
+    attempts: u32,
+    last_sent: Option<time::Instant>,
+    result: Option<usize>,
+}
+
+impl Default for MtuProbe {
+    fn default() -> Self {
+        MtuProbe::new()
+    }
+}
+
+impl MtuProbe {
+    pub fn new() -> MtuProbe {
+        MtuProbe {
+            index: 0,
+            attempts: 0,
+            last_sent: None,
+            result: None,
+        }
+    }
+
+    /// True once a size was acknowledged or every size failed.
+    pub fn is_done(&self) -> bool {
+        self.result.is_some() || self.index == PROBE_SIZES.len()
+    }
+
+    /// Largest acknowledged size.
+    pub fn result(&self) -> Option<usize> {
+        self.result
+    }
+
+    /// Size of the probe to send now, if any.
+    pub fn poll(&mut self, now: time::Instant) -> Option<usize> {
+        if self.is_done() {
+            return None;
+        }
+        if let Some(last_sent) = self.last_sent {
+            if now.saturating_duration_since(last_sent) < PROBE_TIMEOUT {
+                return None;
+            }
+        }
+        if self.attempts == PROBE_ATTEMPTS {
+            self.index += 1;
+            self.attempts = 0;
+            if self.is_done() {
+                return None;
+            }
+        }
+        self.attempts += 1;
+        self.last_sent = Some(now);
+        Some(PROBE_SIZES[self.index])
+    }
+
+    /// Acks for sizes larger than the one being tried are late acks of
+    /// earlier attempts and count as well.
+    pub fn on_ack(&mut self, size: usize) {
+        if self.result.is_none()
+            && PROBE_SIZES[..=self.index.min(PROBE_SIZES.len() - 1)].contains(&size)
+        {
+            self.result = Some(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_size, write_probe, write_probe_ack, MtuProbe, PROBE_ATTEMPTS, PROBE_SIZES,
+        PROBE_TIMEOUT,
+    };
+    use crate::shared::{bits::Error, packet::PacketType};
+    use std::time;
+
+    #[test]
+    fn datagrams() {
+        let mut datagram = Vec::new();
+        write_probe(1400, &mut datagram);
+        assert_eq!(datagram.len(), 1400);
+        assert_eq!(datagram[0], PacketType::Probe as u8);
+        assert_eq!(read_size(PacketType::Probe, &datagram[1..]), Ok(1400));
+        // a probe cut short on the way.
+        assert_eq!(
+            read_size(PacketType::Probe, &datagram[1..1000]),
+            Err(Error::ValueOutOfBounds)
+        );
+
+        write_probe_ack(1400, &mut datagram);
+        assert_eq!(datagram[0], PacketType::ProbeAck as u8);
+        assert_eq!(read_size(PacketType::ProbeAck, &datagram[1..]), Ok(1400));
+    }
+
+    #[test]
+    fn decreasing_sizes() {
+        let start = time::Instant::now();
+        let mut probe = MtuProbe::new();
+        let mut sent = Vec::new();
+        let mut now = start;
+        // the link drops everything above 1300 bytes.
+        while !probe.is_done() {
+            if let Some(size) = probe.poll(now) {
+                assert!(probe.poll(now).is_none());
+                sent.push(size);
+                if size <= 1300 {
+                    probe.on_ack(size);
+                }
+            }
+            now += PROBE_TIMEOUT;
+        }
+        assert_eq!(probe.result(), Some(1280));
+        assert_eq!(sent.len(), 3 * PROBE_ATTEMPTS as usize + 1);
+        assert!(sent.windows(2).all(|pair| pair[0] >= pair[1]));
+
+        // nothing gets through.
+        let mut probe = MtuProbe::new();
+        let mut attempts = 0;
+        while !probe.is_done() {
+            attempts += probe.poll(now).is_some() as usize;
+            now += PROBE_TIMEOUT;
+        }
+        assert_eq!(probe.result(), None);
+        assert_eq!(attempts, PROBE_SIZES.len() * PROBE_ATTEMPTS as usize);
+
+        // acks for sizes never tried are ignored.
+        let mut probe = MtuProbe::new();
+        probe.poll(now);
+        probe.on_ack(1400);
+        probe.on_ack(1337);
+        assert!(!probe.is_done());
+        probe.on_ack(PROBE_SIZES[0]);
+        assert_eq!(probe.result(), Some(PROBE_SIZES[0]));
+    }
+}
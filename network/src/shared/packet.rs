@@ -0,0 +1,389 @@
+use std::time;
+
+use super::bits::{BitReader, BitWriter, Error, NetSerialize, Stream};
+use super::sequence::{sequence_less_than, Sequence, SequenceBuffer};
+
+pub const HEADER_BYTES: usize = 8;
+
+/// First byte of every datagram.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum PacketType {
+    /// Followed by a `PacketHeader` and the packet body.
+    Payload = 0,
+    /// Followed by a `FragmentHeader` and part of a packet.
+    Fragment = 1,
+    /// Followed by its own size and padding, see `mtu::write_probe`.
+    Probe = 2,
+    /// Followed by the size of the probe it acknowledges.
+    ProbeAck = 3,
+    /// The handshake packets, see `handshake::Handshake`.
+    ConnectionRequest = 4,
+    Challenge = 5,
+    ChallengeResponse = 6,
+    ConnectionAccepted = 7,
+    ConnectionDenied = 8,
+    /// Ends a connection, sent a few times as it is not acknowledged.
+    Disconnect = 9,
+}
+
+impl PacketType {
+    pub fn from_byte(byte: u8) -> Option<PacketType> {
+        match byte {
+            0 => Some(PacketType::Payload),
+            1 => Some(PacketType::Fragment),
+            2 => Some(PacketType::Probe),
+            3 => Some(PacketType::ProbeAck),
+            4 => Some(PacketType::ConnectionRequest),
+            5 => Some(PacketType::Challenge),
+            6 => Some(PacketType::ChallengeResponse),
+            7 => Some(PacketType::ConnectionAccepted),
+            8 => Some(PacketType::ConnectionDenied),
+            9 => Some(PacketType::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Number of packets a header acknowledges: `ack` and the 31 before it.
+pub const ACK_WINDOW: u16 = 32;
+
+/// Number of sent and received packets remembered per endpoint.
+const HISTORY: usize = 1024;
+
+/// Prefixed to every packet. Bit `i` of `ack_bits` is set if packet
+/// `ack - i` was received, so nothing is acknowledged until bit 0 is set.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PacketHeader {
+    pub sequence: Sequence,
+    /// Most recent sequence received from the other side.
+    pub ack: Sequence,
+    pub ack_bits: u32,
+}
+
+impl NetSerialize for PacketHeader {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+        let mut sequence = self.sequence as u32;
+        let mut ack = self.ack as u32;
+        stream.serialize_bits(&mut sequence, 16)?;
+        stream.serialize_bits(&mut ack, 16)?;
+        stream.serialize_bits(&mut self.ack_bits, 32)?;
+        self.sequence = sequence as Sequence;
+        self.ack = ack as Sequence;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SentPacket {
+    pub send_time: time::Instant,
+    /// Size including the header.
+    pub bytes: usize,
+    pub acked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReceivedPacket {
+    pub recv_time: time::Instant,
+    /// Size including the header.
+    pub bytes: usize,
+}
+
+/// Fate of a sent packet, reported once per packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Acked(Sequence),
+    /// The packet left the ack window without being acknowledged.
+    Lost(Sequence),
+}
+
+/// Numbers outgoing packets and acknowledges incoming ones for one remote peer.
+pub struct Endpoint {
+    sequence: Sequence,
+    sent: SequenceBuffer<SentPacket>,
+    received: SequenceBuffer<ReceivedPacket>,
+    // oldest sent packet whose fate has not been decided yet.
+    unresolved: Sequence,
+    deliveries: Vec<Delivery>,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint::new()
+    }
+}
+
+impl Endpoint {
+    pub fn new() -> Endpoint {
+        Endpoint {
+            sequence: 0,
+            sent: SequenceBuffer::new(HISTORY),
+            received: SequenceBuffer::new(HISTORY),
+            unresolved: 0,
+            deliveries: Vec::new(),
+        }
+    }
+
+    /// Sequence the next written packet gets.
+    pub fn next_sequence(&self) -> Sequence {
+        self.sequence
+    }
+
+    /// Most recent sequence received from the other side.
+    pub fn remote_sequence(&self) -> Option<Sequence> {
+        self.received.latest()
+    }
+
+    pub fn sent_packet(&self, sequence: Sequence) -> Option<&SentPacket> {
+        self.sent.get(sequence)
+    }
+
+    pub fn received_packet(&self, sequence: Sequence) -> Option<&ReceivedPacket> {
+        self.received.get(sequence)
+    }
+
+    fn ack_header(&self) -> (Sequence, u32) {
+        let ack = match self.received.latest() {
+            Some(ack) => ack,
+            None => return (0, 0),
+        };
+        let mut ack_bits = 0;
+        for i in 0..ACK_WINDOW {
+            if self.received.contains(ack.wrapping_sub(i)) {
+                ack_bits |= 1 << i;
+            }
+        }
+        (ack, ack_bits)
+    }
+
+    /// Writes the header followed by `payload` into `packet`. Returns the
+    /// sequence of the packet.
+    pub fn write_packet(
+        &mut self,
+        payload: &[u8],
+        now: time::Instant,
+        packet: &mut Vec<u8>,
+    ) -> Sequence {
+        let (ack, ack_bits) = self.ack_header();
+        let mut header = PacketHeader {
+            sequence: self.sequence,
+            ack,
+            ack_bits,
+        };
+        let mut bytes = [0u8; HEADER_BYTES];
+        let mut writer = BitWriter::new(&mut bytes);
+        header
+            .serialize(&mut writer)
+            .expect("header fits its buffer");
+        writer.flush();
+
+        packet.clear();
+        packet.extend_from_slice(&bytes);
+        packet.extend_from_slice(payload);
+
+        self.sent.insert(
+            header.sequence,
+            SentPacket {
+                send_time: now,
+                bytes: packet.len(),
+                acked: false,
+            },
+        );
+        self.sequence = self.sequence.wrapping_add(1);
+        header.sequence
+    }
+
+    /// Reads the header of `packet`, records it as received and processes
+    /// its acks. Returns the header and the payload after it. Duplicates and
+    /// packets too old to be acknowledged anymore fail with
+    /// `Error::ValueOutOfBounds`.
+    pub fn read_packet<'a>(
+        &mut self,
+        packet: &'a [u8],
+        now: time::Instant,
+    ) -> Result<(PacketHeader, &'a [u8]), Error> {
+        let mut header = PacketHeader::default();
+        let mut reader = BitReader::new(packet);
+        header.serialize(&mut reader)?;
+        if self.received.is_too_old(header.sequence) || self.received.contains(header.sequence) {
+            return Err(Error::ValueOutOfBounds);
+        }
+        self.received.insert(
+            header.sequence,
+            ReceivedPacket {
+                recv_time: now,
+                bytes: packet.len(),
+            },
+        );
+        self.process_acks(header.ack, header.ack_bits);
+        Ok((header, &packet[HEADER_BYTES..]))
+    }
+
+    fn process_acks(&mut self, ack: Sequence, ack_bits: u32) {
+        // acks for packets never sent are ignored.
+        if !sequence_less_than(ack, self.sequence) {
+            return;
+        }
+        for i in 0..ACK_WINDOW {
+            if ack_bits & (1 << i) == 0 {
+                continue;
+            }
+            let sequence = ack.wrapping_sub(i);
+            if let Some(sent) = self.sent.get_mut(sequence) {
+                if !sent.acked {
+                    sent.acked = true;
+                    self.deliveries.push(Delivery::Acked(sequence));
+                }
+            }
+        }
+
+        let window_start = ack.wrapping_sub(ACK_WINDOW - 1);
+        if self.sent.is_too_old(self.unresolved) {
+            self.unresolved = self.sequence.wrapping_sub(HISTORY as Sequence);
+        }
+        while sequence_less_than(self.unresolved, window_start) {
+            if let Some(sent) = self.sent.get(self.unresolved) {
+                if !sent.acked {
+                    self.deliveries.push(Delivery::Lost(self.unresolved));
+                }
+            }
+            self.unresolved = self.unresolved.wrapping_add(1);
+        }
+    }
+
+    /// Acked and lost packets in the order they were found out.
+    pub fn drain_deliveries(&mut self) -> std::vec::Drain<'_, Delivery> {
+        self.deliveries.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Delivery, Endpoint, PacketHeader, ACK_WINDOW, HEADER_BYTES};
+    use crate::shared::bits::{measure, BitReader, BitWriter, Error, NetSerialize};
+    use std::{collections::HashSet, time};
+
+    #[test]
+    fn header() {
+        let mut header = PacketHeader {
+            sequence: 65535,
+            ack: 12,
+            ack_bits: 0x8000_0001,
+        };
+        assert_eq!(measure(&mut header).unwrap(), HEADER_BYTES as i64 * 8);
+
+        let mut buffer = [0u8; HEADER_BYTES];
+        let mut writer = BitWriter::new(&mut buffer);
+        header.serialize(&mut writer).unwrap();
+        writer.flush();
+
+        let mut read = PacketHeader::default();
+        read.serialize(&mut BitReader::new(&buffer)).unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn acks() {
+        let now = time::Instant::now();
+        let (mut client, mut server) = (Endpoint::new(), Endpoint::new());
+        let mut packet = Vec::new();
+
+        for sequence in 0..4 {
+            assert_eq!(client.write_packet(b"data", now, &mut packet), sequence);
+            // the second packet gets lost.
+            if sequence != 1 {
+                let (header, payload) = server.read_packet(&packet, now).unwrap();
+                assert_eq!(header.sequence, sequence);
+                assert_eq!(payload, b"data");
+            }
+        }
+        assert_eq!(client.drain_deliveries().count(), 0);
+
+        server.write_packet(b"", now, &mut packet);
+        let (header, payload) = client.read_packet(&packet, now).unwrap();
+        assert_eq!(header.ack, 3);
+        assert_eq!(header.ack_bits, 0b1011);
+        assert!(payload.is_empty());
+        let deliveries: Vec<_> = client.drain_deliveries().collect();
+        assert_eq!(
+            deliveries,
+            vec![Delivery::Acked(3), Delivery::Acked(2), Delivery::Acked(0)]
+        );
+        assert!(client.sent_packet(0).unwrap().acked);
+        assert!(!client.sent_packet(1).unwrap().acked);
+
+        // packet 1 is given up once it leaves the window.
+        for _ in 0..ACK_WINDOW {
+            client.write_packet(b"data", now, &mut packet);
+            server.read_packet(&packet, now).unwrap();
+        }
+        server.write_packet(b"", now, &mut packet);
+        client.read_packet(&packet, now).unwrap();
+        let deliveries: Vec<_> = client.drain_deliveries().collect();
+        assert_eq!(deliveries.len(), ACK_WINDOW as usize + 1);
+        assert!(deliveries.contains(&Delivery::Lost(1)));
+        assert!(!deliveries.contains(&Delivery::Lost(0)));
+    }
+
+    #[test]
+    fn malformed() {
+        let now = time::Instant::now();
+        let mut endpoint = Endpoint::new();
+        assert!(endpoint.read_packet(&[0; HEADER_BYTES - 1], now).is_err());
+
+        let mut sender = Endpoint::new();
+        let mut packet = Vec::new();
+        let mut old = Vec::new();
+        sender.write_packet(b"old", now, &mut old);
+        for _ in 0..2000 {
+            sender.write_packet(b"new", now, &mut packet);
+        }
+        endpoint.read_packet(&packet, now).unwrap();
+        assert_eq!(
+            endpoint.read_packet(&old, now),
+            Err(Error::ValueOutOfBounds)
+        );
+        // the network delivered it twice.
+        assert_eq!(
+            endpoint.read_packet(&packet, now),
+            Err(Error::ValueOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn wrap_around() {
+        let now = time::Instant::now();
+        let (mut client, mut server) = (Endpoint::new(), Endpoint::new());
+        let mut packet = Vec::new();
+        let mut delivered = HashSet::new();
+        let mut acked = HashSet::new();
+        let mut lost = HashSet::new();
+
+        const COUNT: u32 = 70000;
+        for i in 0..COUNT {
+            let sequence = client.write_packet(&i.to_le_bytes(), now, &mut packet);
+            if i % 7 != 3 {
+                server.read_packet(&packet, now).unwrap();
+                delivered.insert(i);
+            }
+            server.write_packet(b"", now, &mut packet);
+            client.read_packet(&packet, now).unwrap();
+            for delivery in client.drain_deliveries() {
+                // sequences repeat, so map them back to the packet index.
+                let (sequence_of, set) = match delivery {
+                    Delivery::Acked(s) => (s, &mut acked),
+                    Delivery::Lost(s) => (s, &mut lost),
+                };
+                let index = i - sequence.wrapping_sub(sequence_of) as u32;
+                assert!(set.insert(index), "reported twice: {}", index);
+            }
+        }
+
+        assert_eq!(acked, delivered);
+        assert!(acked.is_disjoint(&lost));
+        // everything older than the last ack window has been decided.
+        for i in 0..COUNT - ACK_WINDOW as u32 {
+            assert!(acked.contains(&i) || lost.contains(&i), "undecided: {}", i);
+        }
+    }
+}
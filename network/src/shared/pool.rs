@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Fixed number of packet buffers recycled between the receive path, the send
+/// path and the game. Buffers are plain `Vec<u8>` of `buffer_bytes` length.
+/// When the pool is empty a new buffer is allocated and counted as exhaustion;
+/// the pool never holds more than `capacity` buffers.
+#[derive(Clone)]
+pub struct BufferPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    free: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+    buffer_bytes: usize,
+    exhausted: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, buffer_bytes: usize) -> BufferPool {
+        let free = (0..capacity).map(|_| vec![0; buffer_bytes]).collect();
+        BufferPool {
+            shared: Arc::new(Shared {
+                free: Mutex::new(free),
+                capacity,
+                buffer_bytes,
+                exhausted: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Takes a buffer of `buffer_bytes()` length out of the pool.
+    pub fn acquire(&self) -> Vec<u8> {
+        match self.shared.free.lock().unwrap().pop() {
+            Some(buffer) => buffer,
+            None => {
+                self.shared.exhausted.fetch_add(1, Ordering::Relaxed);
+                vec![0; self.shared.buffer_bytes]
+            }
+        }
+    }
+
+    /// Hands a buffer back. Buffers that are too small or do not fit in the
+    /// pool anymore are freed.
+    pub fn release(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() < self.shared.buffer_bytes {
+            return;
+        }
+        let mut free = self.shared.free.lock().unwrap();
+        if free.len() < self.shared.capacity {
+            buffer.resize(self.shared.buffer_bytes, 0);
+            free.push(buffer);
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn buffer_bytes(&self) -> usize {
+        self.shared.buffer_bytes
+    }
+
+    /// Number of buffers ready to be acquired.
+    pub fn available(&self) -> usize {
+        self.shared.free.lock().unwrap().len()
+    }
+
+    /// Number of times `acquire` found the pool empty and had to allocate.
+    pub fn exhausted(&self) -> u64 {
+        self.shared.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+    use std::thread;
+
+    #[test]
+    fn recycle() {
+        let pool = BufferPool::new(2, 16);
+        assert_eq!(pool.available(), 2);
+
+        let mut first = pool.acquire();
+        let second = pool.acquire();
+        assert_eq!(first.len(), 16);
+        assert_eq!(pool.available(), 0);
+        assert_eq!(pool.exhausted(), 0);
+
+        let third = pool.acquire();
+        assert_eq!(third.len(), 16);
+        assert_eq!(pool.exhausted(), 1);
+
+        // handed back buffers get their full length back without reallocating.
+        first.truncate(3);
+        let address = first.as_ptr();
+        pool.release(first);
+        let again = pool.acquire();
+        assert_eq!(again.len(), 16);
+        assert_eq!(again.as_ptr(), address);
+
+        pool.release(again);
+        pool.release(second);
+        pool.release(third);
+        assert_eq!(pool.available(), 2);
+
+        pool.acquire();
+        pool.acquire();
+        pool.release(Vec::new());
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn shared() {
+        let pool = BufferPool::new(8, 4);
+        let remote = pool.clone();
+        let buffers = thread::spawn(move || (0..8).map(|_| remote.acquire()).collect::<Vec<_>>())
+            .join()
+            .unwrap();
+        assert_eq!(pool.available(), 0);
+        for buffer in buffers {
+            pool.release(buffer);
+        }
+        assert_eq!(pool.available(), 8);
+        assert_eq!(pool.exhausted(), 0);
+    }
+}
@@ -0,0 +1,168 @@
+/// Wrapping 16-bit packet sequence number.
+pub type Sequence = u16;
+
+/// True if `a` is newer than `b`, treating the numbers as wrapping around.
+pub fn sequence_greater_than(a: Sequence, b: Sequence) -> bool {
+    const HALF: Sequence = 32768;
+    (a > b && a - b <= HALF) || (a < b && b - a > HALF)
+}
+
+pub fn sequence_less_than(a: Sequence, b: Sequence) -> bool {
+    sequence_greater_than(b, a)
+}
+
+/// Fixed-size ring of entries indexed by sequence number. Only the most
+/// recent `capacity` sequences can be stored; inserting a newer sequence
+/// clears the slots it skips over.
+pub struct SequenceBuffer<T> {
+    entries: Vec<Option<(Sequence, T)>>,
+    latest: Sequence,
+    empty: bool,
+}
+
+impl<T> SequenceBuffer<T> {
+    /// `capacity` has to divide 65536 so slots stay put when sequences wrap,
+    /// and can be at most half the sequence space.
+    pub fn new(capacity: usize) -> SequenceBuffer<T> {
+        assert!(capacity > 0 && capacity <= 32768 && 65536 % capacity == 0);
+        SequenceBuffer {
+            entries: (0..capacity).map(|_| None).collect(),
+            latest: 0,
+            empty: true,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Most recent sequence inserted so far.
+    pub fn latest(&self) -> Option<Sequence> {
+        match self.empty {
+            true => None,
+            false => Some(self.latest),
+        }
+    }
+
+    fn index(&self, sequence: Sequence) -> usize {
+        sequence as usize % self.entries.len()
+    }
+
+    /// True if `sequence` fell out of the window of stored sequences.
+    pub fn is_too_old(&self, sequence: Sequence) -> bool {
+        !self.empty
+            && sequence_less_than(
+                sequence,
+                self.latest
+                    .wrapping_sub(self.entries.len() as Sequence)
+                    .wrapping_add(1),
+            )
+    }
+
+    /// Stores `value` for `sequence`. Returns `None` if the sequence is too old.
+    pub fn insert(&mut self, sequence: Sequence, value: T) -> Option<&mut T> {
+        if self.is_too_old(sequence) {
+            return None;
+        }
+        if self.empty || sequence_greater_than(sequence, self.latest) {
+            if !self.empty {
+                let skipped = sequence.wrapping_sub(self.latest) as usize;
+                for offset in 1..skipped.min(self.entries.len() + 1) {
+                    let index = self.index(self.latest.wrapping_add(offset as Sequence));
+                    self.entries[index] = None;
+                }
+            }
+            self.latest = sequence;
+            self.empty = false;
+        }
+        let index = self.index(sequence);
+        self.entries[index] = Some((sequence, value));
+        self.entries[index].as_mut().map(|(_, value)| value)
+    }
+
+    pub fn get(&self, sequence: Sequence) -> Option<&T> {
+        match &self.entries[self.index(sequence)] {
+            Some((stored, value)) if *stored == sequence => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, sequence: Sequence) -> Option<&mut T> {
+        let index = self.index(sequence);
+        match &mut self.entries[index] {
+            Some((stored, value)) if *stored == sequence => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, sequence: Sequence) -> bool {
+        self.get(sequence).is_some()
+    }
+
+    pub fn remove(&mut self, sequence: Sequence) -> Option<T> {
+        let index = self.index(sequence);
+        match &self.entries[index] {
+            Some((stored, _)) if *stored == sequence => self.entries[index].take().map(|e| e.1),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sequence_greater_than, sequence_less_than, SequenceBuffer};
+
+    #[test]
+    fn compare() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(!sequence_greater_than(0, 0));
+        assert!(sequence_greater_than(0, 65535));
+        assert!(sequence_greater_than(100, 65000));
+        assert!(!sequence_greater_than(65000, 100));
+        assert!(sequence_less_than(65535, 0));
+        assert!(sequence_less_than(32767, 65535));
+    }
+
+    #[test]
+    fn buffer() {
+        let mut buffer = SequenceBuffer::new(16);
+        assert_eq!(buffer.latest(), None);
+        assert!(buffer.get(0).is_none());
+
+        *buffer.insert(3, 'a').unwrap() = 'b';
+        assert_eq!(buffer.get(3), Some(&'b'));
+        assert_eq!(buffer.latest(), Some(3));
+        assert!(!buffer.contains(3 + 16));
+
+        // skipping ahead clears what was in between.
+        buffer.insert(5, 'c');
+        buffer.insert(19, 'd');
+        assert!(!buffer.contains(3));
+        assert!(buffer.contains(5) && buffer.contains(19));
+        assert!(buffer.is_too_old(3));
+        assert!(buffer.insert(3, 'x').is_none());
+
+        buffer.insert(40, 'e');
+        assert!(!buffer.contains(5) && !buffer.contains(19));
+
+        assert_eq!(buffer.remove(40), Some('e'));
+        assert_eq!(buffer.remove(40), None);
+        assert_eq!(buffer.latest(), Some(40));
+    }
+
+    #[test]
+    fn wrap() {
+        let mut buffer = SequenceBuffer::new(1024);
+        let mut sequence: u16 = 65000;
+        for i in 0..2000 {
+            buffer.insert(sequence, sequence);
+            assert_eq!(buffer.get(sequence), Some(&sequence));
+            if i >= 1023 {
+                assert!(buffer.contains(sequence.wrapping_sub(1023)));
+            }
+            sequence = sequence.wrapping_add(1);
+        }
+        assert_eq!(buffer.latest(), Some(sequence.wrapping_sub(1)));
+        assert!(buffer.is_too_old(sequence.wrapping_sub(1025)));
+    }
+}
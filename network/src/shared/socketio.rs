@@ -1,96 +1,410 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use std::thread;
 use std::time;
 
+use super::batch;
+use super::checksum::{Protocol, CHECKSUM_BYTES};
+use super::pool::BufferPool;
+
 use mpsc::TryRecvError;
+use socket2::{Domain, Socket, Type};
 
+#[derive(Clone)]
 pub struct Packet {
+    pub addr: SocketAddr,
     pub recv_time: time::Instant,
     pub nbytes: usize,
     pub buffer: Vec<u8>,
 }
 
 pub struct Context {
-    recv_thread: Option<thread::JoinHandle<()>>, // "the option dance"
-    read_rx: Receiver<Packet>,
+    receiver: Receiver,
     socket: UdpSocket,
     local_addr: SocketAddr, // used to send message to self to unblock the thread
+    protocol: Protocol,
+    rejected: Arc<AtomicU64>,
+    pool: BufferPool,
+    send_queue: Vec<(Vec<u8>, SocketAddr)>,
+    error: Option<io::Error>,
+}
+
+enum Receiver {
+    /// A thread blocks on the socket and hands packets over through a channel.
+    Thread {
+        thread: Option<thread::JoinHandle<()>>, // "the option dance"
+        read_rx: mpsc::Receiver<io::Result<Packet>>,
+        running: Arc<AtomicBool>,
+    },
+    /// The socket is non-blocking and drained by `try_recv` on the caller's thread.
+    Poll {
+        batch: RecvBatch,
+        ready: VecDeque<Packet>,
+    },
+}
+
+/// Pool buffers the next datagrams are read into, up to `batch::MAX_BATCH` per call.
+struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    fn new(pool: &BufferPool) -> RecvBatch {
+        RecvBatch {
+            buffers: (0..batch::MAX_BATCH).map(|_| pool.acquire()).collect(),
+            received: Vec::with_capacity(batch::MAX_BATCH),
+        }
+    }
+
+    fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        batch::recv_batch(socket, &mut self.buffers, &mut self.received)
+    }
+
+    // hands out the valid packets of the last `recv`. their buffers are
+    // replaced from the pool, rejected datagrams leave theirs to be read into again.
+    fn open(
+        &mut self,
+        protocol: &Protocol,
+        rejected: &AtomicU64,
+        pool: &BufferPool,
+        mut deliver: impl FnMut(Packet),
+    ) {
+        let recv_time = time::Instant::now();
+        for (buffer, &(nbytes, src_addr)) in self.buffers.iter_mut().zip(self.received.iter()) {
+            let nbytes = match open_datagram(protocol, rejected, buffer, nbytes) {
+                Some(nbytes) => nbytes,
+                None => continue,
+            };
+            let mut buffer = mem::replace(buffer, pool.acquire());
+            buffer.truncate(nbytes);
+            deliver(Packet {
+                addr: unmapped(src_addr),
+                nbytes,
+                recv_time,
+                buffer,
+            });
+        }
+        self.received.clear();
+    }
+}
+
+/// Size of the receive buffers, the MTU of ethernet. Larger datagrams are
+/// cut short, so `mtu::PROBE_SIZES` stays below it.
+const MAX_DATAGRAM_BYTES: usize = 1500;
+
+/// Packet buffers shared by the receive and send path of one context.
+const PACKET_POOL_BUFFERS: usize = 256;
+
+/// Binds a UDP socket to an IPv4 or IPv6 address. Binding the unspecified
+/// IPv6 address `[::]` makes a dual-stack socket that also receives IPv4
+/// where the OS supports it.
+fn bind(local_addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(local_addr), Type::DGRAM, None)?;
+    if let SocketAddr::V6(addr) = local_addr {
+        if addr.ip().is_unspecified() {
+            // not fatal: the socket then only talks ipv6.
+            let _ = socket.set_only_v6(false);
+        }
+    }
+    socket.bind(&local_addr.into())?;
+    Ok(socket.into())
+}
+
+// ipv4 senders show up as ipv4-mapped ipv6 addresses on dual-stack sockets.
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+// a socket bound to the unspecified address is reached through loopback.
+fn wake_addr(local_addr: SocketAddr) -> SocketAddr {
+    match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), local_addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), local_addr.port())
+        }
+        _ => local_addr,
+    }
+}
+
+// errors that do not stop the socket from receiving.
+fn is_transient(error: &io::Error) -> bool {
+    // icmp port unreachable of an earlier send on some platforms.
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted
+    )
+}
+
+// larger payloads would be cut short by the receive buffers of the peer.
+fn check_size(payload: &[u8]) -> io::Result<()> {
+    if payload.len() + CHECKSUM_BYTES > MAX_DATAGRAM_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} byte payload exceeds the {} byte datagram limit",
+                payload.len(),
+                MAX_DATAGRAM_BYTES - CHECKSUM_BYTES
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// verifies the checksum and moves the payload to the front of `buffer`.
+fn open_datagram(
+    protocol: &Protocol,
+    rejected: &AtomicU64,
+    buffer: &mut [u8],
+    nbytes: usize,
+) -> Option<usize> {
+    // packets of other games, builds or damaged in transit.
+    match protocol.open(&buffer[..nbytes]) {
+        Some(payload) => {
+            let nbytes = payload.len();
+            buffer.copy_within(CHECKSUM_BYTES..CHECKSUM_BYTES + nbytes, 0);
+            Some(nbytes)
+        }
+        None => {
+            rejected.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
 }
 
 impl Context {
-    pub fn new(mut local_addr: SocketAddr) -> (Context, u16) {
-        let socket = UdpSocket::bind(local_addr).unwrap();
-        local_addr = socket.local_addr().unwrap();
-        let recv_socket = socket.try_clone().unwrap();
-
-        let (tx, rx): (Sender<Packet>, Receiver<Packet>) = mpsc::channel();
-
-        let thread = thread::spawn(move || {
-            loop {
-                let mut buffer = vec![0; 1500];
-                match recv_socket.recv_from(&mut buffer.as_mut_slice()) {
-                    Ok((nbytes, src_addr)) => {
-                        let recv_time = time::Instant::now();
-                        if nbytes == 1 {
-                            break; // see a note in client_destroy.
+    /// Binds a socket and starts a thread receiving from it.
+    pub fn new(local_addr: SocketAddr, protocol: Protocol) -> io::Result<(Context, u16)> {
+        let socket = bind(local_addr)?;
+        let recv_socket = socket.try_clone()?;
+        let pool = BufferPool::new(PACKET_POOL_BUFFERS, MAX_DATAGRAM_BYTES);
+        let recv_pool = pool.clone();
+
+        let (tx, rx): (
+            Sender<io::Result<Packet>>,
+            mpsc::Receiver<io::Result<Packet>>,
+        ) = mpsc::channel();
+        let rejected = Arc::new(AtomicU64::new(0));
+        let recv_rejected = rejected.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let recv_running = running.clone();
+
+        let thread = thread::Builder::new()
+            .name("netcode recv".to_string())
+            .spawn(move || {
+                let mut batch = RecvBatch::new(&recv_pool);
+                loop {
+                    let result = batch.recv(&recv_socket);
+                    if !recv_running.load(Ordering::Acquire) {
+                        break; // see a note in drop.
+                    }
+                    match result {
+                        Ok(_) => batch.open(&protocol, &recv_rejected, &recv_pool, |packet| {
+                            let _ = tx.send(Ok(packet));
+                        }),
+                        Err(e) if is_transient(&e) => continue,
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                            break;
                         }
-                        buffer.resize(nbytes, 0);
-                        println!("received {} bytes from {}", nbytes, src_addr);
-                        tx.send(Packet {
-                            nbytes,
-                            recv_time,
-                            buffer,
-                        })
-                        .unwrap();
                     }
-                    Err(_) => break,
                 }
-            }
-        });
+            })?;
+
+        let receiver = Receiver::Thread {
+            thread: Some(thread),
+            read_rx: rx,
+            running,
+        };
+        Context::with_receiver(socket, protocol, rejected, pool, receiver)
+    }
+
+    /// Binds a non-blocking socket without a receive thread. Packets are read
+    /// on the caller's thread by `try_recv`, straight into pool buffers.
+    pub fn polled(local_addr: SocketAddr, protocol: Protocol) -> io::Result<(Context, u16)> {
+        let socket = bind(local_addr)?;
+        socket.set_nonblocking(true)?;
+        let pool = BufferPool::new(PACKET_POOL_BUFFERS, MAX_DATAGRAM_BYTES);
+        let receiver = Receiver::Poll {
+            batch: RecvBatch::new(&pool),
+            ready: VecDeque::with_capacity(batch::MAX_BATCH),
+        };
+        let rejected = Arc::new(AtomicU64::new(0));
+        Context::with_receiver(socket, protocol, rejected, pool, receiver)
+    }
 
+    fn with_receiver(
+        socket: UdpSocket,
+        protocol: Protocol,
+        rejected: Arc<AtomicU64>,
+        pool: BufferPool,
+        receiver: Receiver,
+    ) -> io::Result<(Context, u16)> {
+        let local_addr = socket.local_addr()?;
         let local_port = local_addr.port();
-        (
+        Ok((
             Context {
-                recv_thread: Some(thread),
-                read_rx: rx,
+                receiver,
                 socket,
                 local_addr,
+                protocol,
+                rejected,
+                pool,
+                send_queue: Vec::new(),
+                error: None,
             },
             local_port,
-        )
+        ))
+    }
+
+    /// Sends `buf` prefixed with its checksum. Returns the number of payload bytes sent.
+    /// Fails for payloads that do not fit a datagram, use fragments for those.
+    pub fn send(&mut self, buf: &[u8], dest: SocketAddr) -> std::io::Result<usize> {
+        check_size(buf)?;
+        let mut packet = self.pool.acquire();
+        self.protocol.seal(buf, &mut packet);
+        let result = self.socket.send_to(&packet, self.destination(dest));
+        self.pool.release(packet);
+        Ok(result?.saturating_sub(CHECKSUM_BYTES))
+    }
+
+    /// Sends every payload to its address, as few syscalls as the platform
+    /// allows. Returns the number of packets sent.
+    pub fn send_batch(&mut self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        for (payload, _) in packets {
+            check_size(payload)?;
+        }
+        let mut sealed = mem::take(&mut self.send_queue);
+        for (payload, dest) in packets {
+            let mut packet = self.pool.acquire();
+            self.protocol.seal(payload, &mut packet);
+            sealed.push((packet, self.destination(*dest)));
+        }
+        let result = batch::send_batch(&self.socket, &sealed);
+        for (packet, _) in sealed.drain(..) {
+            self.pool.release(packet);
+        }
+        self.send_queue = sealed;
+        result
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // ipv4 destinations are reached through their mapped form on ipv6 sockets.
+    fn destination(&self, dest: SocketAddr) -> SocketAddr {
+        match (self.local_addr, dest) {
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            _ => dest,
+        }
+    }
+
+    /// Number of received packets dropped because of a checksum mismatch.
+    pub fn rejected_packets(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    /// Hands the buffer of a processed packet back to the pool.
+    pub fn recycle(&self, packet: Packet) {
+        self.pool.release(packet.buffer);
     }
 
-    pub fn send(&self, buf: &[u8], dest: SocketAddr) -> std::io::Result<usize> {
-        self.socket.send_to(buf, dest)
+    /// Takes the socket error that stopped receiving, if any. `try_recv`
+    /// reports such errors as `TryRecvError::Disconnected`.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
     }
 
-    pub fn try_recv(&self) -> Result<Packet, TryRecvError> {
-        self.read_rx.try_recv()
+    pub fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
+        match &mut self.receiver {
+            Receiver::Thread { read_rx, .. } => match read_rx.try_recv() {
+                Ok(Ok(packet)) => Ok(packet),
+                Ok(Err(e)) => {
+                    self.error = Some(e);
+                    Err(TryRecvError::Disconnected)
+                }
+                Err(e) => Err(e),
+            },
+            Receiver::Poll { batch, ready } => loop {
+                if let Some(packet) = ready.pop_front() {
+                    return Ok(packet);
+                }
+                match batch.recv(&self.socket) {
+                    Ok(_) => batch.open(&self.protocol, &self.rejected, &self.pool, |packet| {
+                        ready.push_back(packet)
+                    }),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Err(TryRecvError::Empty)
+                    }
+                    Err(e) if is_transient(&e) => continue,
+                    Err(e) => {
+                        self.error = Some(e);
+                        return Err(TryRecvError::Disconnected);
+                    }
+                }
+            },
+        }
     }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
-        // note(kstasik):
-        // 1 bytes is sent from the main thread when terminating library as rust exposes no way to close a socket.
-        let empty = [0; 1];
-        self.send(&empty, self.local_addr).unwrap();
-        self.recv_thread.take().unwrap().join().unwrap();
+        if let Receiver::Thread {
+            thread, running, ..
+        } = &mut self.receiver
+        {
+            // note(kstasik):
+            // rust exposes no way to close a socket, so the blocked thread is
+            // woken up with an empty datagram sent to itself after clearing `running`.
+            // if that fails the thread is left behind rather than joined forever.
+            running.store(false, Ordering::Release);
+            if self.socket.send_to(&[], wake_addr(self.local_addr)).is_ok() {
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Context;
+    use super::{Context, MAX_DATAGRAM_BYTES};
+    use crate::shared::batch::MAX_BATCH;
+    use crate::shared::checksum::{Protocol, CHECKSUM_BYTES, PROTOCOL};
     use core::panic;
-    use std::{net::SocketAddr, str::FromStr};
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        str::FromStr,
+        thread, time,
+    };
 
     #[test]
     fn lifetime() {
-        let addr = SocketAddr::from_str("127.0.0.1:8888").unwrap();
-        let (_context, port) = Context::new(addr);
+        let addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let (_context, port) = Context::new(addr, PROTOCOL).unwrap();
+        assert_ne!(port, 0);
         println!("socket opened on port: {}", port);
     }
 
@@ -98,7 +412,7 @@ mod tests {
     fn messaging() {
         let hostname = [127, 0, 0, 1];
         let mut addr = SocketAddr::from((hostname, 0));
-        let (context, port) = Context::new(addr);
+        let (mut context, port) = Context::new(addr, PROTOCOL).unwrap();
         println!("socket opened on port: {}", port);
 
         addr = SocketAddr::from((hostname, port));
@@ -111,6 +425,7 @@ mod tests {
             match context.try_recv() {
                 Ok(packet) => {
                     assert_eq!(packet.buffer.len(), msg.len());
+                    assert_eq!(packet.addr, addr);
                     break;
                 }
                 Err(std::sync::mpsc::TryRecvError::Empty) => continue,
@@ -118,4 +433,255 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn rejection() {
+        let hostname = [127, 0, 0, 1];
+        let (mut context, port) = Context::new(SocketAddr::from((hostname, 0)), PROTOCOL).unwrap();
+        let addr = SocketAddr::from((hostname, port));
+
+        let other_build = Protocol {
+            version: PROTOCOL.version + 1,
+            ..PROTOCOL
+        };
+        let mut packet = Vec::new();
+        other_build.seal(b"old build", &mut packet);
+
+        let sender = UdpSocket::bind(SocketAddr::from((hostname, 0))).unwrap();
+        sender.send_to(&packet, addr).unwrap();
+        sender.send_to(b"garbage", addr).unwrap();
+        sender.send_to(b"", addr).unwrap();
+        context.send(b"valid", addr).unwrap();
+
+        let start = time::Instant::now();
+        let mut received = Vec::new();
+        while received.is_empty() || context.rejected_packets() < 3 {
+            if let Ok(packet) = context.try_recv() {
+                received.push(packet);
+            }
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].buffer, b"valid");
+        assert_eq!(context.rejected_packets(), 3);
+    }
+
+    #[test]
+    fn one_byte_datagram() {
+        let hostname = [127, 0, 0, 1];
+        let (mut context, port) = Context::new(SocketAddr::from((hostname, 0)), PROTOCOL).unwrap();
+        let addr = SocketAddr::from((hostname, port));
+
+        // used to shut the receive thread down.
+        let sender = UdpSocket::bind(SocketAddr::from((hostname, 0))).unwrap();
+        sender.send_to(&[0], addr).unwrap();
+        context.send(b"alive", addr).unwrap();
+
+        let start = time::Instant::now();
+        loop {
+            match context.try_recv() {
+                Ok(packet) => {
+                    assert_eq!(packet.buffer, b"alive");
+                    break;
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {
+                    assert!(start.elapsed() < time::Duration::from_secs(5));
+                    thread::yield_now();
+                }
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => panic!("socket failure?"),
+            }
+        }
+        assert_eq!(context.rejected_packets(), 1);
+    }
+
+    #[test]
+    fn polled() {
+        let hostname = [127, 0, 0, 1];
+        let (mut context, port) =
+            Context::polled(SocketAddr::from((hostname, 0)), PROTOCOL).unwrap();
+        let addr = SocketAddr::from((hostname, port));
+        assert!(matches!(
+            context.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Empty)
+        ));
+
+        let sender = UdpSocket::bind(SocketAddr::from((hostname, 0))).unwrap();
+        sender.send_to(b"garbage", addr).unwrap();
+        context.send(b"first", addr).unwrap();
+        context.send(b"second", addr).unwrap();
+
+        let start = time::Instant::now();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match context.try_recv() {
+                Ok(packet) => {
+                    assert_eq!(packet.addr, addr);
+                    assert_eq!(packet.nbytes, packet.buffer.len());
+                    received.push(packet.buffer);
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {
+                    assert!(start.elapsed() < time::Duration::from_secs(5));
+                    thread::yield_now();
+                }
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => panic!("socket failure?"),
+            }
+        }
+        assert_eq!(received, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(context.rejected_packets(), 1);
+    }
+
+    #[test]
+    fn pool() {
+        let hostname = [127, 0, 0, 1];
+        let (mut context, port) =
+            Context::polled(SocketAddr::from((hostname, 0)), PROTOCOL).unwrap();
+        let addr = SocketAddr::from((hostname, port));
+        let capacity = context.pool().capacity();
+        // a batch worth of buffers is always waiting for the next datagrams.
+        assert_eq!(context.pool().available(), capacity - MAX_BATCH);
+
+        for _ in 0..capacity * 2 {
+            context.send(b"recycled", addr).unwrap();
+            let start = time::Instant::now();
+            let packet = loop {
+                if let Ok(packet) = context.try_recv() {
+                    break packet;
+                }
+                assert!(start.elapsed() < time::Duration::from_secs(5));
+                thread::yield_now();
+            };
+            assert_eq!(packet.buffer, b"recycled");
+            assert_eq!(context.pool().available(), capacity - MAX_BATCH - 1);
+            context.recycle(packet);
+        }
+        assert_eq!(context.pool().available(), capacity - MAX_BATCH);
+        assert_eq!(context.pool().exhausted(), 0);
+    }
+
+    #[test]
+    fn send_batch() {
+        let hostname = [127, 0, 0, 1];
+        let (mut context, port) =
+            Context::polled(SocketAddr::from((hostname, 0)), PROTOCOL).unwrap();
+        let addr = SocketAddr::from((hostname, port));
+
+        let payloads: Vec<Vec<u8>> = (0..MAX_BATCH as u8 + 3).map(|i| vec![i; 10]).collect();
+        let packets: Vec<(&[u8], SocketAddr)> = payloads
+            .iter()
+            .map(|payload| (&payload[..], addr))
+            .collect();
+        assert_eq!(context.send_batch(&packets).unwrap(), packets.len());
+
+        let start = time::Instant::now();
+        let mut received = Vec::new();
+        while received.len() < payloads.len() {
+            match context.try_recv() {
+                Ok(packet) => {
+                    received.push(packet.buffer.clone());
+                    context.recycle(packet);
+                }
+                Err(_) => {
+                    assert!(start.elapsed() < time::Duration::from_secs(5));
+                    thread::yield_now();
+                }
+            }
+        }
+        assert_eq!(received, payloads);
+        assert_eq!(context.pool().exhausted(), 0);
+    }
+
+    #[test]
+    fn bind_error() {
+        let taken = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = taken.local_addr().unwrap();
+        let error = Context::new(addr, PROTOCOL).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+        let error = Context::polled(addr, PROTOCOL).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn oversized() {
+        let hostname = [127, 0, 0, 1];
+        let (mut context, port) =
+            Context::polled(SocketAddr::from((hostname, 0)), PROTOCOL).unwrap();
+        let dest = SocketAddr::from((hostname, port));
+        let payload = [0u8; MAX_DATAGRAM_BYTES - CHECKSUM_BYTES];
+        assert_eq!(context.send(&payload, dest).unwrap(), payload.len());
+
+        let payload = [0u8; MAX_DATAGRAM_BYTES - CHECKSUM_BYTES + 1];
+        let error = context.send(&payload, dest).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = context.send_batch(&[(&payload, dest)]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // hosts without ipv6 skip the ipv6 tests.
+    fn ipv6_available() -> bool {
+        UdpSocket::bind("[::1]:0").is_ok()
+    }
+
+    fn recv_one(context: &mut Context) -> super::Packet {
+        let start = time::Instant::now();
+        loop {
+            if let Ok(packet) = context.try_recv() {
+                return packet;
+            }
+            assert!(start.elapsed() < time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn ipv6() {
+        if !ipv6_available() {
+            return;
+        }
+        let (mut context, port) =
+            Context::new(SocketAddr::from_str("[::1]:0").unwrap(), PROTOCOL).unwrap();
+        let addr = SocketAddr::from_str(&format!("[::1]:{}", port)).unwrap();
+        assert_eq!(context.local_addr(), addr);
+
+        context.send(b"six", addr).unwrap();
+        let packet = recv_one(&mut context);
+        assert_eq!(packet.buffer, b"six");
+        assert_eq!(packet.addr, addr);
+    }
+
+    #[test]
+    fn dual_stack() {
+        if !ipv6_available() {
+            return;
+        }
+        let unspecified = SocketAddr::from_str("[::]:0").unwrap();
+        let (mut context, port) = Context::polled(unspecified, PROTOCOL).unwrap();
+
+        // ipv4 senders keep their ipv4 address.
+        let (mut v4, v4_port) =
+            Context::polled(SocketAddr::from(([127, 0, 0, 1], 0)), PROTOCOL).unwrap();
+        v4.send(b"four", SocketAddr::from(([127, 0, 0, 1], port)))
+            .unwrap();
+        let packet = recv_one(&mut context);
+        assert_eq!(packet.buffer, b"four");
+        assert_eq!(packet.addr, SocketAddr::from(([127, 0, 0, 1], v4_port)));
+
+        context.send(b"reply", packet.addr).unwrap();
+        let reply = recv_one(&mut v4);
+        assert_eq!(reply.buffer, b"reply");
+
+        let (mut v6, _) =
+            Context::polled(SocketAddr::from_str("[::1]:0").unwrap(), PROTOCOL).unwrap();
+        v6.send(
+            b"six",
+            SocketAddr::from_str(&format!("[::1]:{}", port)).unwrap(),
+        )
+        .unwrap();
+        let packet = recv_one(&mut context);
+        assert_eq!(packet.addr, v6.local_addr());
+
+        // the receive thread of an unspecified address still shuts down.
+        let (threaded, _) = Context::new(unspecified, PROTOCOL).unwrap();
+        drop(threaded);
+    }
 }
@@ -0,0 +1,349 @@
+use super::address::NetcodeAddress;
+use super::bits::{BitReader, BitWriter, Error, NetSerialize, Stream};
+use super::handshake::PROTOCOL_VERSION;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use std::{net::SocketAddr, time};
+
+pub const KEY_BYTES: usize = 32;
+
+/// Key shared by the backend that issues connect tokens and the servers that
+/// accept them.
+pub type Key = [u8; KEY_BYTES];
+
+pub const USER_DATA_BYTES: usize = 256;
+
+pub const MAX_SERVER_ADDRESSES: usize = 8;
+
+const NONCE_BYTES: usize = 24;
+
+/// The private part is padded to this size before it is sealed.
+const PRIVATE_BYTES: usize = 512;
+
+const TAG_BYTES: usize = 16;
+
+pub const SEALED_BYTES: usize = PRIVATE_BYTES + TAG_BYTES;
+
+pub fn generate_key() -> Key {
+    rand::random()
+}
+
+/// Seconds since the unix epoch, the clock tokens expire by.
+pub fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn serialize_address<S: Stream>(stream: &mut S, addr: &mut SocketAddr) -> Result<(), Error> {
+    let mut address = NetcodeAddress::from(*addr);
+    let mut port = address.port as u32;
+    stream.serialize_bool(&mut address.is_ipv6)?;
+    match address.is_ipv6 {
+        true => stream.serialize_bytes(&mut address.ip)?,
+        false => stream.serialize_bytes(&mut address.ip[..4])?,
+    }
+    stream.serialize_bits(&mut port, 16)?;
+    address.port = port as u16;
+    *addr = SocketAddr::from(address);
+    Ok(())
+}
+
+fn serialize_addresses<S: Stream>(
+    stream: &mut S,
+    addresses: &mut Vec<SocketAddr>,
+) -> Result<(), Error> {
+    let mut count = addresses.len() as i32;
+    stream.serialize_int_range(&mut count, 0, MAX_SERVER_ADDRESSES as i32)?;
+    addresses.resize(count as usize, SocketAddr::from(([0, 0, 0, 0], 0)));
+    for addr in addresses.iter_mut() {
+        serialize_address(stream, addr)?;
+    }
+    Ok(())
+}
+
+/// What only the server gets to see of a connect token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivateToken {
+    pub client_id: u64,
+    /// Servers the token may be used on.
+    pub server_addresses: Vec<SocketAddr>,
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+    pub user_data: [u8; USER_DATA_BYTES],
+}
+
+impl NetSerialize for PrivateToken {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+        stream.serialize_u64(&mut self.client_id)?;
+        serialize_addresses(stream, &mut self.server_addresses)?;
+        stream.serialize_bytes(&mut self.client_to_server_key)?;
+        stream.serialize_bytes(&mut self.server_to_client_key)?;
+        stream.serialize_bytes(&mut self.user_data)
+    }
+}
+
+/// The private token sealed with the backend key. Clients pass it on to the
+/// server in the handshake without being able to read or change it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealedToken {
+    /// Unix time after which servers reject the token.
+    pub expire_timestamp: u64,
+    nonce: [u8; NONCE_BYTES],
+    data: [u8; SEALED_BYTES],
+}
+
+impl Default for SealedToken {
+    fn default() -> Self {
+        SealedToken {
+            expire_timestamp: 0,
+            nonce: [0; NONCE_BYTES],
+            data: [0; SEALED_BYTES],
+        }
+    }
+}
+
+impl NetSerialize for SealedToken {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+        stream.serialize_u64(&mut self.expire_timestamp)?;
+        stream.serialize_bytes(&mut self.nonce)?;
+        stream.serialize_bytes(&mut self.data)
+    }
+}
+
+// the version and expiry are authenticated along with the private part.
+fn associated_data(expire_timestamp: u64) -> [u8; 12] {
+    let mut data = [0u8; 12];
+    data[..4].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    data[4..].copy_from_slice(&expire_timestamp.to_be_bytes());
+    data
+}
+
+impl SealedToken {
+    pub fn seal(
+        private: &PrivateToken,
+        key: &Key,
+        expire_timestamp: u64,
+    ) -> Result<SealedToken, Error> {
+        let mut plain = [0u8; PRIVATE_BYTES];
+        let mut writer = BitWriter::new(&mut plain);
+        private.clone().serialize(&mut writer)?;
+        writer.flush();
+
+        let nonce: [u8; NONCE_BYTES] = rand::random();
+        let cipher = XChaCha20Poly1305::new(key.into());
+        let sealed = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &plain,
+                    aad: &associated_data(expire_timestamp),
+                },
+            )
+            .map_err(|_| Error::InvalidArgument)?;
+        let mut data = [0u8; SEALED_BYTES];
+        data.copy_from_slice(&sealed);
+        Ok(SealedToken {
+            expire_timestamp,
+            nonce,
+            data,
+        })
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_timestamp <= now
+    }
+
+    /// The private part, if the token was sealed with `key` and nobody
+    /// tampered with it.
+    pub fn open(&self, key: &Key) -> Option<PrivateToken> {
+        let cipher = XChaCha20Poly1305::new(key.into());
+        let plain = cipher
+            .decrypt(
+                XNonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.data,
+                    aad: &associated_data(self.expire_timestamp),
+                },
+            )
+            .ok()?;
+        let mut private = PrivateToken {
+            client_id: 0,
+            server_addresses: Vec::new(),
+            client_to_server_key: [0; KEY_BYTES],
+            server_to_client_key: [0; KEY_BYTES],
+            user_data: [0; USER_DATA_BYTES],
+        };
+        private.serialize(&mut BitReader::new(&plain)).ok()?;
+        Some(private)
+    }
+}
+
+/// Grants one client access to a set of servers until it expires. Issued
+/// by a backend that shares the key with the servers, see `connect_token`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectToken {
+    pub server_addresses: Vec<SocketAddr>,
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+    pub sealed: SealedToken,
+}
+
+impl NetSerialize for ConnectToken {
+    fn serialize<S: Stream>(&mut self, stream: &mut S) -> Result<(), Error> {
+        let mut version = PROTOCOL_VERSION;
+        stream.serialize_bits(&mut version, 32)?;
+        if version != PROTOCOL_VERSION {
+            return Err(Error::ValueOutOfBounds);
+        }
+        serialize_addresses(stream, &mut self.server_addresses)?;
+        stream.serialize_bytes(&mut self.client_to_server_key)?;
+        stream.serialize_bytes(&mut self.server_to_client_key)?;
+        self.sealed.serialize(stream)
+    }
+}
+
+impl ConnectToken {
+    /// Issues a token for `client_id` that expires after `lifetime`. Fails if
+    /// there are no or too many addresses, too much user data, or the token
+    /// would expire after the end of unix time.
+    pub fn generate(
+        key: &Key,
+        client_id: u64,
+        lifetime: time::Duration,
+        server_addresses: &[SocketAddr],
+        user_data: &[u8],
+    ) -> Result<ConnectToken, Error> {
+        let expire_timestamp = unix_time().checked_add(lifetime.as_secs());
+        if server_addresses.is_empty()
+            || server_addresses.len() > MAX_SERVER_ADDRESSES
+            || user_data.len() > USER_DATA_BYTES
+            || expire_timestamp.is_none()
+        {
+            return Err(Error::ValueOutOfBounds);
+        }
+        let mut private = PrivateToken {
+            client_id,
+            server_addresses: server_addresses.to_vec(),
+            client_to_server_key: generate_key(),
+            server_to_client_key: generate_key(),
+            user_data: [0; USER_DATA_BYTES],
+        };
+        private.user_data[..user_data.len()].copy_from_slice(user_data);
+        let sealed = SealedToken::seal(&private, key, expire_timestamp.unwrap())?;
+        Ok(ConnectToken {
+            server_addresses: private.server_addresses,
+            client_to_server_key: private.client_to_server_key,
+            server_to_client_key: private.server_to_client_key,
+            sealed,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = BitWriter::growable(None);
+        self.clone()
+            .serialize(&mut writer)
+            .expect("tokens are checked when generated");
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ConnectToken, Error> {
+        let mut token = ConnectToken {
+            server_addresses: Vec::new(),
+            client_to_server_key: [0; KEY_BYTES],
+            server_to_client_key: [0; KEY_BYTES],
+            sealed: SealedToken::default(),
+        };
+        token.serialize(&mut BitReader::new(bytes))?;
+        if token.server_addresses.is_empty() {
+            return Err(Error::ValueOutOfBounds);
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_key, unix_time, ConnectToken, SealedToken, MAX_SERVER_ADDRESSES};
+    use crate::shared::bits::{BitReader, BitWriter, Error, NetSerialize};
+    use std::{net::SocketAddr, time};
+
+    fn servers() -> Vec<SocketAddr> {
+        vec![
+            "127.0.0.1:40000".parse().unwrap(),
+            "[2001:db8::1]:40001".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = generate_key();
+        let lifetime = time::Duration::from_secs(30);
+        let token = ConnectToken::generate(&key, 42, lifetime, &servers(), b"blue team").unwrap();
+        let bytes = token.to_bytes();
+        let read = ConnectToken::from_bytes(&bytes).unwrap();
+        assert_eq!(read, token);
+        assert_eq!(read.server_addresses, servers());
+
+        let private = read.sealed.open(&key).unwrap();
+        assert_eq!(private.client_id, 42);
+        assert_eq!(private.server_addresses, servers());
+        assert_eq!(private.client_to_server_key, token.client_to_server_key);
+        assert_eq!(private.server_to_client_key, token.server_to_client_key);
+        assert_eq!(&private.user_data[..9], b"blue team");
+        assert!(!read.sealed.is_expired(unix_time()));
+        assert!(read.sealed.is_expired(unix_time() + lifetime.as_secs()));
+
+        assert!(ConnectToken::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn tampering() {
+        let key = generate_key();
+        let lifetime = time::Duration::from_secs(30);
+        let token = ConnectToken::generate(&key, 7, lifetime, &servers(), &[]).unwrap();
+        assert!(token.sealed.open(&generate_key()).is_none());
+
+        // the expiry is authenticated.
+        let mut extended = token.sealed.clone();
+        extended.expire_timestamp += 3600;
+        assert!(extended.open(&key).is_none());
+
+        let mut writer = BitWriter::growable(None);
+        token.sealed.clone().serialize(&mut writer).unwrap();
+        let mut bytes = writer.into_bytes();
+        bytes[100] ^= 1;
+        let mut flipped = SealedToken::default();
+        flipped.serialize(&mut BitReader::new(&bytes)).unwrap();
+        assert!(flipped.open(&key).is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let key = generate_key();
+        let lifetime = time::Duration::from_secs(30);
+        let many = vec![servers()[0]; MAX_SERVER_ADDRESSES + 1];
+        assert_eq!(
+            ConnectToken::generate(&key, 1, lifetime, &many, &[]),
+            Err(Error::ValueOutOfBounds)
+        );
+        assert_eq!(
+            ConnectToken::generate(&key, 1, lifetime, &[], &[]),
+            Err(Error::ValueOutOfBounds)
+        );
+        assert_eq!(
+            ConnectToken::generate(&key, 1, lifetime, &servers(), &[0; 257]),
+            Err(Error::ValueOutOfBounds)
+        );
+        let forever = time::Duration::from_secs(u64::MAX);
+        assert_eq!(
+            ConnectToken::generate(&key, 1, forever, &servers(), &[]),
+            Err(Error::ValueOutOfBounds)
+        );
+        let most = vec![servers()[1]; MAX_SERVER_ADDRESSES];
+        let token = ConnectToken::generate(&key, 1, lifetime, &most, &[0xff; 256]).unwrap();
+        assert_eq!(token.sealed.open(&key).unwrap().server_addresses, most);
+    }
+}
@@ -0,0 +1,260 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time;
+
+use super::socketio::{self, Packet};
+
+/// Datagram transport used by `NetcodeServer` and `NetcodeClient`.
+pub trait Transport {
+    fn local_addr(&self) -> SocketAddr;
+
+    /// Sends `buf` to `dest`. Returns the number of payload bytes sent.
+    fn send(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize>;
+
+    fn try_recv(&mut self) -> Result<Packet, TryRecvError>;
+
+    /// Sends every payload to its address. Returns the number of packets sent,
+    /// which is less than `packets.len()` only if a later one failed.
+    fn send_batch(&mut self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        for (sent, (buf, dest)) in packets.iter().enumerate() {
+            if let Err(e) = self.send(buf, *dest) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+        Ok(packets.len())
+    }
+
+    /// Takes the error that made `try_recv` report `Disconnected`, if any.
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+
+    /// Number of received packets dropped before reaching the game.
+    fn rejected_packets(&self) -> u64 {
+        0
+    }
+
+    /// Hands a processed packet back so its buffer can be reused.
+    fn recycle(&mut self, _packet: Packet) {}
+
+    /// A buffer for a packet made up on this side of the transport, handed
+    /// back with `recycle` like received ones.
+    fn acquire(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Number of times a packet buffer had to be allocated because the buffer
+    /// pool was empty.
+    fn pool_exhausted(&self) -> u64 {
+        0
+    }
+}
+
+impl Transport for socketio::Context {
+    fn local_addr(&self) -> SocketAddr {
+        socketio::Context::local_addr(self)
+    }
+
+    fn send(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        socketio::Context::send(self, buf, dest)
+    }
+
+    fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
+        socketio::Context::try_recv(self)
+    }
+
+    fn send_batch(&mut self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        socketio::Context::send_batch(self, packets)
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        socketio::Context::take_error(self)
+    }
+
+    fn rejected_packets(&self) -> u64 {
+        socketio::Context::rejected_packets(self)
+    }
+
+    fn recycle(&mut self, packet: Packet) {
+        socketio::Context::recycle(self, packet)
+    }
+
+    fn acquire(&mut self) -> Vec<u8> {
+        self.pool().acquire()
+    }
+
+    fn pool_exhausted(&self) -> u64 {
+        self.pool().exhausted()
+    }
+}
+
+// in-memory endpoints get made up, unique loopback addresses.
+fn next_memory_addr() -> SocketAddr {
+    static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+    SocketAddr::from(([127, 0, 0, 1], NEXT_PORT.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// One end of an in-process channel pair. Packets sent to the address of the
+/// other end are delivered to it, anything else is dropped like UDP would.
+pub struct MemoryTransport {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    tx: Sender<Packet>,
+    rx: Receiver<Packet>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (first_addr, second_addr) = (next_memory_addr(), next_memory_addr());
+        let (first_tx, second_rx) = mpsc::channel();
+        let (second_tx, first_rx) = mpsc::channel();
+        (
+            MemoryTransport {
+                local_addr: first_addr,
+                peer_addr: second_addr,
+                tx: first_tx,
+                rx: first_rx,
+            },
+            MemoryTransport {
+                local_addr: second_addr,
+                peer_addr: first_addr,
+                tx: second_tx,
+                rx: second_rx,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn send(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if dest == self.peer_addr {
+            // the other end being gone looks like a lost packet.
+            let _ = self.tx.send(Packet {
+                addr: self.local_addr,
+                recv_time: time::Instant::now(),
+                nbytes: buf.len(),
+                buffer: buf.to_vec(),
+            });
+        }
+        Ok(buf.len())
+    }
+
+    fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
+        self.rx.try_recv()
+    }
+}
+
+/// Transport that only talks to itself: packets sent to its own address come back.
+pub struct LoopbackTransport {
+    local_addr: SocketAddr,
+    tx: Sender<Packet>,
+    rx: Receiver<Packet>,
+}
+
+impl LoopbackTransport {
+    pub fn new() -> LoopbackTransport {
+        let (tx, rx) = mpsc::channel();
+        LoopbackTransport {
+            local_addr: next_memory_addr(),
+            tx,
+            rx,
+        }
+    }
+}
+
+impl Default for LoopbackTransport {
+    fn default() -> Self {
+        LoopbackTransport::new()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn send(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if dest == self.local_addr {
+            self.tx
+                .send(Packet {
+                    addr: self.local_addr,
+                    recv_time: time::Instant::now(),
+                    nbytes: buf.len(),
+                    buffer: buf.to_vec(),
+                })
+                .unwrap();
+        }
+        Ok(buf.len())
+    }
+
+    fn try_recv(&mut self) -> Result<Packet, TryRecvError> {
+        self.rx.try_recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoopbackTransport, MemoryTransport, Transport};
+    use crate::shared::{checksum::PROTOCOL, socketio};
+    use std::{net::SocketAddr, sync::mpsc::TryRecvError};
+
+    #[test]
+    fn memory_pair() {
+        let (mut first, mut second) = MemoryTransport::pair();
+        assert_ne!(first.local_addr(), second.local_addr());
+
+        assert_eq!(first.send(b"ping", second.local_addr()).unwrap(), 4);
+        let packet = second.try_recv().unwrap();
+        assert_eq!(packet.buffer, b"ping");
+        assert_eq!(packet.addr, first.local_addr());
+        assert!(matches!(second.try_recv(), Err(TryRecvError::Empty)));
+
+        // unknown destinations are dropped.
+        let nowhere = SocketAddr::from(([10, 0, 0, 1], 1));
+        assert!(second.send(b"lost", nowhere).is_ok());
+        assert!(second.send(b"pong", first.local_addr()).is_ok());
+        assert_eq!(first.try_recv().unwrap().buffer, b"pong");
+        assert!(matches!(first.try_recv(), Err(TryRecvError::Empty)));
+
+        drop(first);
+        assert!(second.send(b"gone", nowhere).is_ok());
+        assert!(matches!(second.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn loopback() {
+        let mut transport = LoopbackTransport::new();
+        let addr = transport.local_addr();
+        assert!(transport.send(b"self", addr).is_ok());
+        let packet = transport.try_recv().unwrap();
+        assert_eq!(packet.buffer, b"self");
+        assert_eq!(packet.addr, addr);
+        assert!(matches!(transport.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn udp() {
+        let (context, _port) =
+            socketio::Context::new(SocketAddr::from(([127, 0, 0, 1], 0)), PROTOCOL).unwrap();
+        let mut transport: Box<dyn Transport> = Box::new(context);
+        let addr = transport.local_addr();
+        assert_eq!(transport.send(b"udp", addr).unwrap(), 3);
+        loop {
+            match transport.try_recv() {
+                Ok(packet) => {
+                    assert_eq!(packet.buffer, b"udp");
+                    break;
+                }
+                Err(TryRecvError::Empty) => continue,
+                Err(TryRecvError::Disconnected) => panic!("socket failure?"),
+            }
+        }
+        assert_eq!(transport.rejected_packets(), 0);
+    }
+}
@@ -1,16 +1,11 @@
 pub type FrameId = i32;
 pub const INVALID_FRAMEID: FrameId = 0;
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
 pub struct SimCommand {
     pub buttons: i32,
 }
 
-impl SimCommand {
-    pub fn default() -> SimCommand {
-        SimCommand { buttons: 0 }
-    }
-}
 #[derive(PartialEq, Debug)]
 pub struct SimInput {
     pub previous: SimCommand,
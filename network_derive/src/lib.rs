@@ -0,0 +1,424 @@
+//! `#[derive(NetSerialize)]` for `network::shared::bits::NetSerialize`.
+//!
+//! Every field is serialized in declaration order. The encoding is picked from
+//! the field type unless one of the attributes below overrides it:
+//!
+//! * `#[bits(n)]` - unsigned integer written with `n` bits,
+//! * `#[range(min, max)]` - integer bounded to `[min, max]`,
+//! * `#[quantize(min, max, resolution)]` - `f32` or `[f32; 3]` quantized to `resolution`,
+//! * `#[max_length(n)]` - `String` of at most `n` bytes.
+//!
+//! Without an attribute `i8` and `i16` use their full range. `usize`, `isize`
+//! and `f64` have no encoding, other types have to implement `NetSerialize`.
+//!
+//! Enums write the variant index bounded by the number of variants, followed by
+//! the fields of that variant. Fields of variants have to implement `Default`.
+
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, punctuated::Punctuated, spanned::Spanned, token::Comma, Attribute, Data,
+    DeriveInput, Expr, Fields, Type,
+};
+
+#[proc_macro_derive(NetSerialize, attributes(bits, range, quantize, max_length))]
+pub fn derive_net_serialize(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let mut statements = Vec::new();
+            for (index, field) in data.fields.iter().enumerate() {
+                let place = match &field.ident {
+                    Some(ident) => quote! { self.#ident },
+                    None => {
+                        let index = syn::Index::from(index);
+                        quote! { self.#index }
+                    }
+                };
+                statements.push(field_code(place, &field.ty, &field.attrs)?);
+            }
+            quote! { #(#statements)* }
+        }
+        Data::Enum(data) => enum_code(data)?,
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "NetSerialize can not be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::network::shared::bits::NetSerialize for #name #type_generics #where_clause {
+            fn serialize<S: ::network::shared::bits::Stream>(
+                &mut self,
+                stream: &mut S,
+            ) -> ::std::result::Result<(), ::network::shared::bits::Error> {
+                #body
+                Ok(())
+            }
+        }
+    })
+}
+
+fn enum_code(data: &syn::DataEnum) -> syn::Result<TokenStream> {
+    if data.variants.is_empty() {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "NetSerialize needs at least one enum variant",
+        ));
+    }
+    let max_index = data.variants.len() as i32 - 1;
+
+    let mut index_arms = Vec::new();
+    let mut default_arms = Vec::new();
+    let mut field_arms = Vec::new();
+    for (index, variant) in data.variants.iter().enumerate() {
+        let index = index as i32;
+        let ident = &variant.ident;
+        let bindings: Vec<_> = variant
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| match &field.ident {
+                Some(ident) => ident.clone(),
+                None => format_ident!("field{}", i),
+            })
+            .collect();
+
+        let mut statements = Vec::new();
+        for (binding, field) in bindings.iter().zip(variant.fields.iter()) {
+            statements.push(field_code(quote! { (*#binding) }, &field.ty, &field.attrs)?);
+        }
+
+        let (pattern, default) = match &variant.fields {
+            Fields::Named(_) => (
+                quote! { Self::#ident { #(#bindings),* } },
+                quote! { Self::#ident { #(#bindings: ::std::default::Default::default()),* } },
+            ),
+            Fields::Unnamed(_) => {
+                let defaults = bindings
+                    .iter()
+                    .map(|_| quote! { ::std::default::Default::default() });
+                (
+                    quote! { Self::#ident ( #(#bindings),* ) },
+                    quote! { Self::#ident ( #(#defaults),* ) },
+                )
+            }
+            Fields::Unit => (quote! { Self::#ident }, quote! { Self::#ident }),
+        };
+        let wildcard = match &variant.fields {
+            Fields::Named(_) => quote! { Self::#ident { .. } },
+            Fields::Unnamed(_) => quote! { Self::#ident ( .. ) },
+            Fields::Unit => quote! { Self::#ident },
+        };
+
+        index_arms.push(quote! { #wildcard => #index, });
+        default_arms.push(quote! { #index => #default, });
+        field_arms.push(quote! { #pattern => { #(#statements)* } });
+    }
+
+    Ok(quote! {
+        let mut index: i32 = match self {
+            #(#index_arms)*
+        };
+        stream.serialize_int_range(&mut index, 0, #max_index)?;
+        if stream.is_reading() {
+            *self = match index {
+                #(#default_arms)*
+                _ => return Err(::network::shared::bits::Error::ValueOutOfBounds),
+            };
+        }
+        #[allow(unused_variables)]
+        match self {
+            #(#field_arms)*
+        }
+    })
+}
+
+fn attribute_args(attr: &Attribute, count: usize) -> syn::Result<Vec<Expr>> {
+    let args = attr.parse_args_with(Punctuated::<Expr, Comma>::parse_terminated)?;
+    if args.len() != count {
+        return Err(syn::Error::new(
+            attr.span(),
+            format!("expected {} arguments", count),
+        ));
+    }
+    Ok(args.into_iter().collect())
+}
+
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => {
+            path.path.get_ident().map(|ident| ident.to_string())
+        }
+        Type::Array(array) => {
+            let element = type_name(&array.elem)?;
+            match &array.len {
+                Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Int(len),
+                    ..
+                }) => Some(format!("[{}; {}]", element, len.base10_digits())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// the value of an integer literal, `None` for any other expression.
+fn int_literal(expr: &Expr) -> Option<i64> {
+    match expr {
+        Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(int),
+            ..
+        }) => int.base10_parse().ok(),
+        Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => int_literal(expr).map(|value| -value),
+        Expr::Paren(paren) => int_literal(&paren.expr),
+        _ => None,
+    }
+}
+
+// smallest and largest value of the integer types fields can be bounded to.
+fn integer_bounds(name: &str) -> (i64, i64) {
+    match name {
+        "i8" => (i8::MIN as i64, i8::MAX as i64),
+        "i16" => (i16::MIN as i64, i16::MAX as i64),
+        "u8" => (0, u8::MAX as i64),
+        "u16" => (0, u16::MAX as i64),
+        "u32" => (0, u32::MAX as i64),
+        _ => (i32::MIN as i64, i32::MAX as i64),
+    }
+}
+
+fn field_code(place: TokenStream, ty: &Type, attrs: &[Attribute]) -> syn::Result<TokenStream> {
+    let name = type_name(ty).unwrap_or_default();
+
+    for attr in attrs {
+        if attr.path().is_ident("bits") {
+            let args = attribute_args(attr, 1)?;
+            let bits = &args[0];
+            return match name.as_str() {
+                "u8" | "u16" | "u32" => {
+                    let width = integer_bounds(&name).1.count_ones() as i64;
+                    let check = match int_literal(bits) {
+                        Some(n) if n > width => {
+                            return Err(syn::Error::new(
+                                bits.span(),
+                                format!("#[bits({})] is wider than an {} field", n, name),
+                            ))
+                        }
+                        Some(_) => quote! {},
+                        None => quote! {
+                            const _: () = assert!((#bits) as i64 <= #width, "#[bits] is wider than the field");
+                        },
+                    };
+                    Ok(quote! {
+                        #check
+                        let mut value = #place as u32;
+                        stream.serialize_bits(&mut value, #bits)?;
+                        #place = value as #ty;
+                    })
+                }
+                "bool" => Ok(quote! {
+                    let mut value = #place as u32;
+                    stream.serialize_bits(&mut value, #bits)?;
+                    #place = value != 0;
+                }),
+                _ => Err(syn::Error::new(
+                    ty.span(),
+                    "#[bits] needs an u8, u16, u32 or bool field",
+                )),
+            };
+        }
+        if attr.path().is_ident("range") {
+            let args = attribute_args(attr, 2)?;
+            let (min, max) = (&args[0], &args[1]);
+            return match name.as_str() {
+                "i8" | "i16" | "i32" | "u8" | "u16" => {
+                    let (lowest, highest) = integer_bounds(&name);
+                    let check = match (int_literal(min), int_literal(max)) {
+                        (Some(min), Some(max)) if min < lowest || max > highest => {
+                            return Err(syn::Error::new(
+                                attr.span(),
+                                format!(
+                                    "#[range({}, {})] does not fit an {} field",
+                                    min, max, name
+                                ),
+                            ))
+                        }
+                        (Some(_), Some(_)) => quote! {},
+                        _ => quote! {
+                            const _: () = assert!(
+                                (#min) as i64 >= #lowest && (#max) as i64 <= #highest,
+                                "#[range] does not fit the field"
+                            );
+                        },
+                    };
+                    Ok(quote! {
+                        #check
+                        let mut value = #place as i32;
+                        stream.serialize_int_range(&mut value, #min, #max)?;
+                        #place = value as #ty;
+                    })
+                }
+                _ => Err(syn::Error::new(
+                    ty.span(),
+                    "#[range] needs an i8, i16, i32, u8 or u16 field",
+                )),
+            };
+        }
+        if attr.path().is_ident("quantize") {
+            let args = attribute_args(attr, 3)?;
+            let (min, max, resolution) = (&args[0], &args[1], &args[2]);
+            return match name.as_str() {
+                "f32" => Ok(quote! {
+                    stream.serialize_float(&mut #place, #min, #max, #resolution)?;
+                }),
+                "[f32; 3]" => Ok(quote! {
+                    stream.serialize_vector(&mut #place, #min, #max, #resolution)?;
+                }),
+                _ => Err(syn::Error::new(
+                    ty.span(),
+                    "#[quantize] needs an f32 or [f32; 3] field",
+                )),
+            };
+        }
+        if attr.path().is_ident("max_length") {
+            let args = attribute_args(attr, 1)?;
+            let max_length = &args[0];
+            return match name.as_str() {
+                "String" => Ok(quote! {
+                    stream.serialize_string(&mut #place, #max_length)?;
+                }),
+                _ => Err(syn::Error::new(
+                    ty.span(),
+                    "#[max_length] needs a String field",
+                )),
+            };
+        }
+    }
+
+    Ok(match name.as_str() {
+        "bool" => quote! { stream.serialize_bool(&mut #place)?; },
+        "u8" => quote! { stream.serialize_byte(&mut #place)?; },
+        "u16" | "u32" => {
+            let bits = if name == "u16" { 16 } else { 32 };
+            quote! {
+                let mut value = #place as u32;
+                stream.serialize_bits(&mut value, #bits)?;
+                #place = value as #ty;
+            }
+        }
+        "u64" => quote! { stream.serialize_u64(&mut #place)?; },
+        "i8" | "i16" => {
+            let (lowest, highest) = integer_bounds(&name);
+            let (lowest, highest) = (lowest as i32, highest as i32);
+            quote! {
+                let mut value = #place as i32;
+                stream.serialize_int_range(&mut value, #lowest, #highest)?;
+                #place = value as #ty;
+            }
+        }
+        "i32" => quote! { stream.serialize_i32(&mut #place)?; },
+        "i64" => quote! { stream.serialize_i64(&mut #place)?; },
+        "f32" => quote! {
+            let mut value = #place.to_bits();
+            stream.serialize_bits(&mut value, 32)?;
+            #place = f32::from_bits(value);
+        },
+        "String" => {
+            return Err(syn::Error::new(
+                ty.span(),
+                "String fields need #[max_length(n)]",
+            ))
+        }
+        "usize" | "isize" => {
+            return Err(syn::Error::new(
+                ty.span(),
+                format!(
+                    "{} fields have no fixed size, use u32, u64 or #[range(min, max)] on an i32",
+                    name
+                ),
+            ))
+        }
+        "f64" => {
+            return Err(syn::Error::new(
+                ty.span(),
+                "f64 fields are not supported, use f32 or #[quantize(min, max, resolution)]",
+            ))
+        }
+        _ => quote! {
+            ::network::shared::bits::NetSerialize::serialize(&mut #place, stream)?;
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::field_code;
+    use quote::quote;
+    use syn::{parse_quote, Field};
+
+    fn expand(field: Field) -> syn::Result<proc_macro2::TokenStream> {
+        field_code(quote! { self.value }, &field.ty, &field.attrs)
+    }
+
+    #[test]
+    fn bits_fit_the_field() {
+        assert!(expand(parse_quote! { #[bits(8)] value: u8 }).is_ok());
+        assert!(expand(parse_quote! { #[bits(16)] value: u16 }).is_ok());
+        assert!(expand(parse_quote! { #[bits(32)] value: u32 }).is_ok());
+        assert!(expand(parse_quote! { #[bits(9)] value: u8 }).is_err());
+        assert!(expand(parse_quote! { #[bits(17)] value: u16 }).is_err());
+        assert!(expand(parse_quote! { #[bits(33)] value: u32 }).is_err());
+        // constants are checked when the expansion is compiled.
+        let code = expand(parse_quote! { #[bits(WIDTH)] value: u8 }).unwrap();
+        assert!(code.to_string().contains("assert !"));
+    }
+
+    #[test]
+    fn range_fits_the_field() {
+        assert!(expand(parse_quote! { #[range(0, 255)] value: u8 }).is_ok());
+        assert!(expand(parse_quote! { #[range(-128, 127)] value: i8 }).is_ok());
+        assert!(expand(parse_quote! { #[range(0, 256)] value: u8 }).is_err());
+        assert!(expand(parse_quote! { #[range(-1, 10)] value: u16 }).is_err());
+        assert!(expand(parse_quote! { #[range(-200, 0)] value: i8 }).is_err());
+    }
+
+    #[test]
+    fn default_encodings() {
+        let code = expand(parse_quote! { value: i8 }).unwrap().to_string();
+        assert!(code.contains("serialize_int_range (& mut value , - 128i32 , 127i32)"));
+        let code = expand(parse_quote! { value: i16 }).unwrap().to_string();
+        assert!(code.contains("serialize_int_range"));
+        for field in [
+            parse_quote! { value: usize },
+            parse_quote! { value: isize },
+            parse_quote! { value: f64 },
+        ] {
+            let error = expand(field).unwrap_err().to_string();
+            assert!(error.contains("use "), "{}", error);
+        }
+        let code = expand(parse_quote! { value: Rotation })
+            .unwrap()
+            .to_string();
+        assert!(code.contains("NetSerialize :: serialize"));
+    }
+}
@@ -74,14 +74,20 @@ static void cli_get_input(struct simcmd_t* input) {
 
     // init
     server = server_create();
+    if (!server)
+        NSLog(@"Error: Cannot create server: %s", netcode_last_error());
     client = client_create();
+    if (!client)
+        NSLog(@"Error: Cannot create client: %s", netcode_last_error());
 }
 
 -(void)updateAndDrawDemoView
 {
     // update
-    server_update(server);
-    client_update(client);
+    if (server && server_update(server) != NETCODE_OK)
+        NSLog(@"Error: server update: %s", netcode_last_error());
+    if (client && client_update(client) != NETCODE_OK)
+        NSLog(@"Error: client update: %s", netcode_last_error());
 
     // Start the Dear ImGui frame
     ImGui_ImplOpenGL2_NewFrame();
@@ -131,6 +137,31 @@ static void cli_get_input(struct simcmd_t* input) {
         ImGui::End();
     }
 
+    // 4. Show the link statistics of the most recent client.
+    {
+        static float rtt_history[128] = {};
+        static int rtt_offset = 0;
+        netcode_address address;
+        netcode_connection_stats stats;
+
+        ImGui::Begin("Connection");
+        if (server && server_last_sender(server, &address) && server_connection_stats(server, &address, &stats))
+        {
+            rtt_history[rtt_offset] = stats.rtt_ms;
+            rtt_offset = (rtt_offset + 1) % IM_ARRAYSIZE(rtt_history);
+            ImGui::PlotLines("rtt", rtt_history, IM_ARRAYSIZE(rtt_history), rtt_offset, NULL, 0.0f, 200.0f, ImVec2(0, 80));
+            ImGui::Text("rtt %.1fms, jitter %.1fms, loss %.1f%%", stats.rtt_ms, stats.jitter_ms, stats.packet_loss_percent);
+            ImGui::Text("sent %.1fkbps, received %.1fkbps, acked %.1fkbps", stats.sent_kbps, stats.received_kbps, stats.acked_kbps);
+            ImGui::Text("max packet %u bytes", stats.max_packet_bytes);
+            ImGui::SameLine();
+            if (ImGui::Button("Probe MTU"))
+                server_probe_mtu(server, &address);
+        }
+        else
+            ImGui::Text("No connection.");
+        ImGui::End();
+    }
+
     // Rendering
     ImGui::Render();
     [[self openGLContext] makeCurrentContext];